@@ -0,0 +1,194 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::request::Cmd;
+
+const DEFAULT_SIZE: usize = 1000;
+const DEFAULT_TTL: u64 = 60;
+const DEFAULT_NEGATIVE_TTL: u64 = 10;
+const DEFAULT_STALE_TTL: u64 = 3600;
+
+type Key = (Cmd, String);
+
+struct Entry {
+    line:       String,
+    expires:    Instant,
+    seqno:      u64,
+}
+
+/// In-memory cache of map lookup replies, keyed on (command, argument).
+///
+/// Positive (2xx) and negative (404) replies are cached with separate TTLs.
+/// Expired entries are kept around for another `stale_ttl`, so that they
+/// can still be served when none of the servers can be reached.
+/// When the cache is full, the least recently used entry is evicted.
+pub struct Cache {
+    map:            HashMap<Key, Entry>,
+    lru:            BTreeMap<u64, Key>,
+    seqno:          u64,
+    max_size:       usize,
+    positive_ttl:   Duration,
+    negative_ttl:   Duration,
+    stale_ttl:      Duration,
+}
+
+// reply code of a reply line ("200 mikevs:x:..")
+pub(crate) fn reply_code(line: &str) -> u16 {
+    line.split(' ').next().and_then(|c| c.parse::<u16>().ok()).unwrap_or(0)
+}
+
+impl Cache {
+    /// Returns None if caching is disabled (`cache_size = 0`).
+    pub fn new(config: &Config) -> Option<Cache> {
+        let max_size = config.cache_size.unwrap_or(DEFAULT_SIZE);
+        if max_size == 0 {
+            return None;
+        }
+        Some(Cache {
+            map:            HashMap::new(),
+            lru:            BTreeMap::new(),
+            seqno:          0,
+            max_size:       max_size,
+            positive_ttl:   Duration::from_secs(config.cache_ttl.unwrap_or(DEFAULT_TTL)),
+            negative_ttl:   Duration::from_secs(config.cache_negative_ttl.unwrap_or(DEFAULT_NEGATIVE_TTL)),
+            stale_ttl:      Duration::from_secs(config.cache_stale_ttl.unwrap_or(DEFAULT_STALE_TTL)),
+        })
+    }
+
+    /// Get a fresh entry.
+    pub fn get(&mut self, cmd: Cmd, arg: &str) -> Option<String> {
+        self.lookup(cmd, arg, Duration::from_secs(0))
+    }
+
+    /// Get an entry that might have expired, but not more than `stale_ttl` ago.
+    pub fn get_stale(&mut self, cmd: Cmd, arg: &str) -> Option<String> {
+        let stale_ttl = self.stale_ttl;
+        self.lookup(cmd, arg, stale_ttl)
+    }
+
+    fn lookup(&mut self, cmd: Cmd, arg: &str, grace: Duration) -> Option<String> {
+        let key = (cmd, arg.to_string());
+        let now = Instant::now();
+        let (line, seqno, gone) = {
+            let entry = self.map.get(&key)?;
+            (entry.line.clone(), entry.seqno, now >= entry.expires + self.stale_ttl)
+        };
+        if gone {
+            self.remove(&key);
+            return None;
+        }
+        if now >= self.map[&key].expires + grace {
+            return None;
+        }
+
+        // move to the back of the LRU list.
+        self.seqno += 1;
+        self.lru.remove(&seqno);
+        self.lru.insert(self.seqno, key.clone());
+        self.map.get_mut(&key).unwrap().seqno = self.seqno;
+
+        Some(line)
+    }
+
+    /// Store a reply. Only 2xx and 404 replies are cached.
+    pub fn insert(&mut self, cmd: Cmd, arg: &str, line: &str) {
+        let ttl = match reply_code(line) {
+            200..=299 => self.positive_ttl,
+            404 => self.negative_ttl,
+            _ => return,
+        };
+        let key = (cmd, arg.to_string());
+        self.remove(&key);
+
+        // evict least recently used entries.
+        while self.map.len() >= self.max_size {
+            let seqno = match self.lru.keys().next() {
+                Some(s) => *s,
+                None => break,
+            };
+            let key = self.lru.remove(&seqno).unwrap();
+            self.map.remove(&key);
+        }
+
+        self.seqno += 1;
+        self.lru.insert(self.seqno, key.clone());
+        self.map.insert(key, Entry {
+            line:       line.to_string(),
+            expires:    Instant::now() + ttl,
+            seqno:      self.seqno,
+        });
+    }
+
+    /// Expire all entries for a command. They are not removed, so
+    /// they can still be served stale if no server can be reached.
+    pub fn expire(&mut self, cmd: Cmd) {
+        let now = Instant::now();
+        for (_, entry) in self.map.iter_mut().filter(|(k, _)| k.0 == cmd) {
+            if entry.expires > now {
+                entry.expires = now;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.map.remove(key) {
+            self.lru.remove(&entry.seqno);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(size: usize) -> Cache {
+        Cache {
+            map:            HashMap::new(),
+            lru:            BTreeMap::new(),
+            seqno:          0,
+            max_size:       size,
+            positive_ttl:   Duration::from_secs(60),
+            negative_ttl:   Duration::from_secs(0),
+            stale_ttl:      Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn t_lru() {
+        let mut c = cache(2);
+        c.insert(Cmd::GetPwNam, "a", "200 a:x:1:1:::");
+        c.insert(Cmd::GetPwNam, "b", "200 b:x:2:2:::");
+        // touch "a", so that "b" gets evicted.
+        assert!(c.get(Cmd::GetPwNam, "a").is_some());
+        c.insert(Cmd::GetPwUid, "3", "200 c:x:3:3:::");
+        assert!(c.get(Cmd::GetPwNam, "b").is_none());
+        assert!(c.get(Cmd::GetPwNam, "a").is_some());
+        assert!(c.get(Cmd::GetPwUid, "3").is_some());
+        assert_eq!(c.map.len(), 2);
+        assert_eq!(c.lru.len(), 2);
+    }
+
+    #[test]
+    fn t_negative_and_stale() {
+        let mut c = cache(10);
+        c.insert(Cmd::GetGrNam, "x", "404 Not Found");
+        c.insert(Cmd::GetGrNam, "y", "500 HTTP error");
+        // negative ttl is 0, so it's expired, but still available as stale entry.
+        assert!(c.get(Cmd::GetGrNam, "x").is_none());
+        assert_eq!(c.get_stale(Cmd::GetGrNam, "x").unwrap(), "404 Not Found");
+        // errors are not cached.
+        assert!(c.get_stale(Cmd::GetGrNam, "y").is_none());
+    }
+
+    #[test]
+    fn t_expire() {
+        let mut c = cache(10);
+        c.insert(Cmd::GetGrNam, "wheel", "200 wheel:x:10:root");
+        c.insert(Cmd::GetPwNam, "root", "200 root:x:0:0:::");
+        c.expire(Cmd::GetGrNam);
+        assert!(c.get(Cmd::GetGrNam, "wheel").is_none());
+        assert!(c.get_stale(Cmd::GetGrNam, "wheel").is_some());
+        assert!(c.get(Cmd::GetPwNam, "root").is_some());
+    }
+}
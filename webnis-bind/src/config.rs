@@ -5,22 +5,88 @@ use std::fs::File;
 
 use toml;
 
+use webnis_common::secret::secret;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    #[serde(default)]
-    pub domain:             String,
-    pub http_authschema:    String,
-    pub http_authtoken:     String,
+    // either the name of the one domain, or a list of [[domain]] sections.
+    #[serde(default, rename = "domain")]
+    domain_:                NameOrDomains,
+    // the settings below are defaults for all domains.
+    pub http_authschema:    Option<String>,
+    pub http_authtoken:     Option<String>,
     pub http_authencoding:  Option<String>,
     pub server:             Option<String>,
     #[serde(default)]
     pub servers:            Vec<String>,
+    pub servers_srv:        Option<String>,
+    pub srv_nameserver:     Option<String>,
     pub http2_only:         Option<bool>,
     pub concurrency:        Option<usize>,
     #[serde(default)]
     pub restrict_getpwuid:  bool,
     #[serde(default)]
     pub restrict_getgrgid:  bool,
+    pub cache_size:         Option<usize>,
+    pub cache_ttl:          Option<u64>,
+    pub cache_negative_ttl: Option<u64>,
+    pub cache_stale_ttl:    Option<u64>,
+    pub watch_changes:      Option<bool>,
+    pub offline_db:         Option<String>,
+    pub offline_max_age:    Option<u64>,
+    pub health_check_interval:  Option<u64>,
+    pub health_check_fall:      Option<u32>,
+    pub health_check_rise:      Option<u32>,
+    // run as this user / group after creating the socket.
+    pub user:               Option<String>,
+    pub group:              Option<String>,
+    // the domains, in lookup order. filled in by read().
+    #[serde(skip)]
+    pub domains:            Vec<Domain>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum NameOrDomains {
+    Name(String),
+    Domains(Vec<Domain>),
+}
+
+impl Default for NameOrDomains {
+    fn default() -> NameOrDomains {
+        NameOrDomains::Name("".to_string())
+    }
+}
+
+/// A webnis domain, with its own servers and credentials.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Domain {
+    #[serde(default)]
+    pub name:               String,
+    pub http_authschema:    Option<String>,
+    pub http_authtoken:     Option<String>,
+    pub http_authencoding:  Option<String>,
+    pub server:             Option<String>,
+    #[serde(default)]
+    pub servers:            Vec<String>,
+    pub servers_srv:        Option<String>,
+    pub srv_nameserver:     Option<String>,
+}
+
+impl Domain {
+    /// Value of the Authorization: header.
+    pub fn authorization(&self) -> String {
+        let schema = self.http_authschema.as_ref().map(|s| s.as_str()).unwrap_or("");
+        let token = self.http_authtoken.as_ref().map(|s| s.as_str()).unwrap_or("");
+        match self.http_authencoding.as_ref().map(|s| s.as_str()) {
+            Some("base64") => format!("{} {}", schema, base64::encode(token)),
+            _ => format!("{} {}", schema, token),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
 }
 
 pub fn read(name: &str) -> io::Result<Config> {
@@ -36,12 +102,64 @@ pub fn read(name: &str) -> io::Result<Config> {
     if let Some(s) = config.server.take() {
         config.servers.push(s);
     }
-    if config.servers.len() == 0 {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "no servers defined"));
+
+    // old style config, one domain, everything at the top level.
+    let (mut domains, single) = match std::mem::replace(&mut config.domain_, NameOrDomains::default()) {
+        NameOrDomains::Name(name) => (vec![Domain{ name, ..Domain::default() }], true),
+        NameOrDomains::Domains(d) => (d, false),
+    };
+    if domains.len() == 0 {
+        return Err(invalid(format!("{}: no domains defined", name)));
+    }
+
+    // fill in the defaults from the top level, then check.
+    for d in domains.iter_mut() {
+        if d.name.as_str() == "" {
+            if !single {
+                return Err(invalid(format!("{}: [[domain]]: name not set", name)));
+            }
+            d.name = "default".to_string();
+        }
+        let dname = d.name.clone();
+        let err = |msg: &str| invalid(format!("{}: domain {}: {}", name, dname, msg));
+        if d.http_authschema.is_none() {
+            d.http_authschema = config.http_authschema.clone();
+        }
+        if d.http_authtoken.is_none() {
+            d.http_authtoken = config.http_authtoken.clone();
+        }
+        if d.http_authencoding.is_none() {
+            d.http_authencoding = config.http_authencoding.clone();
+        }
+        if let Some(s) = d.server.take() {
+            d.servers.push(s);
+        }
+        if d.servers.len() == 0 && d.servers_srv.is_none() {
+            d.servers = config.servers.clone();
+            d.servers_srv = config.servers_srv.clone();
+        }
+        if d.srv_nameserver.is_none() {
+            d.srv_nameserver = config.srv_nameserver.clone();
+        }
+        if d.http_authschema.is_none() {
+            return Err(err("http_authschema not set"));
+        }
+        if d.http_authtoken.is_none() {
+            return Err(err("http_authtoken not set"));
+        }
+        if let Some(token) = d.http_authtoken.as_mut() {
+            *token = secret(token).map_err(|e| err(&format!("http_authtoken: {}", e)))?;
+        }
+        if d.servers.len() == 0 && d.servers_srv.is_none() {
+            return Err(err("no servers or servers_srv defined"));
+        }
     }
-    if config.domain.as_str() == "" {
-        config.domain = "default".to_string();
+    for i in 1 .. domains.len() {
+        if domains[..i].iter().any(|d| d.name == domains[i].name) {
+            return Err(invalid(format!("{}: domain {}: defined more than once", name, domains[i].name)));
+        }
     }
+    config.domains = domains;
 
     Ok(config)
 }
@@ -0,0 +1,24 @@
+use std::sync::Mutex;
+
+use crate::config;
+use crate::pool::Pool;
+
+/// A webnis domain, and the state of its servers.
+pub struct Domain {
+    pub name:           String,
+    // value of the Authorization: header.
+    pub authorization:  String,
+    pub pool:           Mutex<Pool>,
+    pub config:         config::Domain,
+}
+
+impl Domain {
+    pub fn new(config: &config::Config, domain: &config::Domain) -> Domain {
+        Domain {
+            name:           domain.name.clone(),
+            authorization:  domain.authorization(),
+            pool:           Mutex::new(Pool::new(config, &domain.servers)),
+            config:         domain.clone(),
+        }
+    }
+}
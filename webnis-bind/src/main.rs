@@ -3,34 +3,36 @@
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate serde_json;
 
+mod cache;
 mod config;
+mod domain;
+mod offline;
+mod pool;
 mod request;
 mod response;
+mod srv;
+mod systemd;
 
 use std::fs;
 use std::io;
+use std::os::unix::net::UnixListener as StdUnixListener;
 use std::process::exit;
 use std::time::Duration;
 use std::sync::{Arc,Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use env_logger;
-use futures::prelude::*;
-use futures::stream;
-use futures::sync::mpsc;
-use tokio;
-use tokio::prelude::*;
-use tokio_uds::UnixListener;
-use tokio_codec::Decoder;
-use tk_listen::ListenExt;
-use hyper::client::HttpConnector;
-use hyper_tls::HttpsConnector;
-
-use tokio_codec::LinesCodec;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use webnis_common::privs;
 
 // contains the currently active http client, and a sequence number.
 pub struct HttpClient {
-    client: Option<hyper::Client<HttpsConnector<HttpConnector>>>,
+    client: Option<request::Client>,
     seqno:  usize,
 }
 
@@ -40,6 +42,12 @@ pub struct Context {
     config:         Arc<config::Config>,
     // a client that we can replace.
     http_client:    Arc<Mutex<HttpClient>>,
+    // the webnis domains, in lookup order.
+    domains:        Arc<Vec<Arc<domain::Domain>>>,
+    // reply cache.
+    cache:          Option<Arc<Mutex<cache::Cache>>>,
+    // on-disk store for offline mode.
+    offline:        Option<Arc<offline::OfflineDb>>,
     // has client gone away?
     eof:            Arc<AtomicBool>,
     // uid/gid of process talking to us on unix socket
@@ -48,10 +56,24 @@ pub struct Context {
 }
 
 const PROGNAME : &'static str = "webnis-bind";
+// close a session if the client has not sent anything for this many seconds.
+const IDLE_TIMEOUT: u64 = 10;
+// max number of requests with a request id that are processed at the same time.
+const MAX_PIPELINE: usize = 16;
 
 fn main() {
     env_logger::init();
 
+    // a socket from systemd (socket activation). the environment is
+    // changed, so do this before the runtime starts any threads.
+    let activated = match systemd::listen_fd() {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}: socket activation: {}", PROGNAME, e);
+            exit(1);
+        },
+    };
+
     let matches = clap_app!(webnis_bind =>
         (version: "0.1")
         (@arg LISTEN: -l --listen +takes_value "unix domain socket to listen on (/var/run/webnis-bind.sock)")
@@ -68,105 +90,286 @@ fn main() {
             exit(1);
         }
     };
+
+    // the user to run as, looked up while there are no threads yet.
+    let ids = match privs::lookup(config.user.as_ref(), config.group.as_ref()) {
+        Ok(ids) => ids,
+        Err(e) => {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
+        },
+    };
+
+    let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    rt.block_on(async_main(listen, config, activated, ids));
+}
+
+async fn async_main(listen: &str, config: config::Config, activated: Option<StdUnixListener>, ids: privs::Ids) {
     let http2_only = config.http2_only.unwrap_or(false);
     let mut concurrency = config.concurrency.unwrap_or(32);
     if http2_only && concurrency < 100 {
         concurrency = 100;
     }
 
-    let seqno = std::process::id() as usize % (config.servers.len());
+    let domains = config.domains.iter().map(|d| Arc::new(domain::Domain::new(&config, d))).collect::<Vec<_>>();
+    let cache = cache::Cache::new(&config).map(|c| Arc::new(Mutex::new(c)));
+    let offline = match offline::OfflineDb::open(&config) {
+        Ok(o) => o.map(Arc::new),
+        Err(e) => {
+            eprintln!("{}: offline_db: {}", PROGNAME, e);
+            exit(1);
+        }
+    };
     let ctx = Context{
         config:         Arc::new(config),
-		http_client:    Arc::new(Mutex::new(HttpClient{ client: None, seqno: seqno })),
+        http_client:    Arc::new(Mutex::new(HttpClient{ client: None, seqno: 0 })),
+        domains:        Arc::new(domains),
+        cache:          cache,
+        offline:        offline,
         eof:            Arc::new(AtomicBool::new(false)),
         uid:            0xfffffffe,
         gid:            0xfffffffe,
     };
 
+    // the socket from systemd, or our own.
+    let listener = match activated {
+        Some(l) => {
+            println!("{}: listening on: socket from systemd", PROGNAME);
+            match UnixListener::from_std(l) {
+                Ok(l) => l,
+                Err(e) => {
+                    eprintln!("{}: socket activation: {}", PROGNAME, e);
+                    exit(1);
+                },
+            }
+        },
+        None => bind_socket(listen),
+    };
+
+    // the socket exists and the offline store is open, root is not needed anymore.
+    if let Err(e) = privs::switch(&ids) {
+        eprintln!("{}: {}", PROGNAME, e);
+        exit(1);
+    }
+
+    // write the offline store to disk every minute.
+    if let Some(offline) = ctx.offline.clone() {
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = offline.flush() {
+                    warn!("offline: {}", e);
+                }
+            }
+        });
+    }
+
+    for domain in ctx.domains.iter() {
+        // find the servers using DNS SRV records, and keep them up to date.
+        if let Some(name) = domain.config.servers_srv.clone() {
+            let resolver = match srv::DnsResolver::new(&domain.config) {
+                Ok(r) => r,
+                Err(e) => {
+                    eprintln!("{}: domain {}: srv_nameserver: {}", PROGNAME, domain.name, e);
+                    exit(1);
+                },
+            };
+            let (servers, refresh) = srv::lookup(domain, &resolver, &name).await;
+            domain.pool.lock().unwrap().set_servers(servers);
+            tokio::spawn(srv::refresher(domain.clone(), Box::new(resolver), name, refresh));
+        }
+
+        // expire cache entries when maps change on the server.
+        if ctx.cache.is_some() && ctx.config.watch_changes.unwrap_or(true) {
+            tokio::spawn(request::change_watcher(ctx.clone(), domain.clone()));
+        }
+
+        // check the health of the servers in the background.
+        let interval = domain.pool.lock().unwrap().interval();
+        if let Some(interval) = interval {
+            tokio::spawn(request::health_checker(ctx.clone(), domain.clone(), interval));
+        }
+    }
+
+    // on SIGTERM / SIGINT, tell systemd, save the offline store, and exit.
+    let offline = ctx.offline.clone();
+    tokio::spawn(async move {
+        let mut sig_term = signal(SignalKind::terminate()).unwrap();
+        let mut sig_int = signal(SignalKind::interrupt()).unwrap();
+        tokio::select! {
+            _ = sig_term.recv() => {},
+            _ = sig_int.recv() => {},
+        }
+        systemd::notify("STOPPING=1");
+        if let Some(offline) = offline {
+            if let Err(e) = offline.flush() {
+                warn!("offline: {}", e);
+            }
+        }
+        exit(0);
+    });
+
+    // at most `concurrency` sessions are active at the same time.
+    let sessions = Arc::new(Semaphore::new(concurrency));
+
+    systemd::start_watchdog();
+    systemd::notify("READY=1");
+
+    loop {
+        let permit = sessions.clone().acquire_owned().await.unwrap();
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                eprintln!("{}: accept error = {:?}", PROGNAME, e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            },
+        };
+
+        // set up context for this session.
+        let (uid, gid) = match socket.peer_cred() {
+            Ok(creds) => (creds.uid() as u32, creds.gid() as u32),
+            Err(_) => (0xfffffffe, 0xfffffffe),
+        };
+        let ctx = Context{
+            config:         ctx.config.clone(),
+            http_client:    ctx.http_client.clone(),
+            domains:        ctx.domains.clone(),
+            cache:          ctx.cache.clone(),
+            offline:        ctx.offline.clone(),
+            eof:            Arc::new(AtomicBool::new(false)),
+            uid:            uid,
+            gid:            gid,
+        };
+
+        tokio::spawn(async move {
+            session(ctx, socket).await;
+            drop(permit);
+        });
+    }
+}
+
+// Create the socket, and listen on it.
+fn bind_socket(listen: &str) -> UnixListener {
     // first set umask so that anyone can connect to the socket we're about to create.
     let saved_umask = unsafe { libc::umask(0o111) };
 
     // Get a UNIX stream listener.
-	let listener = match UnixListener::bind(&listen) {
+    let listener = match UnixListener::bind(&listen) {
         Ok(m) => Ok(m),
         Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
             // old socket laying around, get rid of it. then try again.
-            fs::remove_file(&listen).map_err(|e| {
+            if let Err(e) = fs::remove_file(&listen) {
                 eprintln!("{}: {}: {}", PROGNAME, listen, e);
                 exit(1);
-            }).unwrap();
+            }
             UnixListener::bind(&listen)
         },
         Err(e) => Err(e),
-    }.map_err(|e| {
-        eprintln!("{}: {}: {}", PROGNAME, listen, e);
-        exit(1);
-    }).unwrap();
+    };
+    let listener = match listener {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}: {}: {}", PROGNAME, listen, e);
+            exit(1);
+        },
+    };
 
     // restore umask to whatever wildly insane insecure value it was before.
     unsafe { libc::umask(saved_umask) };
 
     println!("{}: listening on: {}", PROGNAME, listen);
+    listener
+}
 
-    let server = listener.incoming()
-        .map_err(|e| { eprintln!("{}: accept error = {:?}", PROGNAME, e); e })
-        .sleep_on_error(Duration::from_millis(100))
-        .map(move |socket| {
+// Handle one client connection.
+//
+// A request can be prefixed with a request id ("#<id> GETPWNAM mikevs").
+// The reply is then prefixed with the same id. Requests with an id are
+// processed concurrently, so their replies can be sent out of order.
+// Requests without an id are processed one at a time, as before.
+async fn session(ctx: Context, socket: UnixStream) {
 
-            // set up context for this session.
-            let (uid, gid) = match socket.peer_cred() {
-                Ok(creds) => (creds.uid as u32, creds.gid as u32),
-                Err(_) => (0xfffffffe, 0xfffffffe),
-            };
-            let ctx = Context{
-                config:         ctx.config.clone(),
-                http_client:    ctx.http_client.clone(),
-                eof:            Arc::new(AtomicBool::new(false)),
-                uid:            uid,
-                gid:            gid,
+    // set up codec for reader and writer.
+    let (reader, writer) = socket.into_split();
+    let mut reader = FramedRead::new(reader, LinesCodec::new());
+    let mut writer = FramedWrite::new(writer, LinesCodec::new());
+
+    // We read the incoming stream continously, and forward it onto a channel.
+    // That way we can detect immediately if the client has gone away (EOF).
+    let (tx, mut rx) = mpsc::channel(1);
+    let eof = ctx.eof.clone();
+    let read_lines = async move {
+        loop {
+            // close the session if the client is idle for too long.
+            let next = match time::timeout(Duration::from_secs(IDLE_TIMEOUT), reader.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    debug!("command reader: idle timeout");
+                    break;
+                },
             };
+            match next {
+                Some(Ok(line)) => {
+                    if tx.send(line).await.is_err() {
+                        break;
+                    }
+                },
+                _ => {
+                    //debug!("reader saw EOF or error, setting EOF flag in Context");
+                    eof.store(true, Ordering::SeqCst);
+                    break;
+                },
+            }
+        }
+    };
+    tokio::spawn(read_lines);
 
-            // set up codec for reader and writer.
-            let (writer, reader) = LinesCodec::new().framed(socket).split();
-
-            // produce a final "EOF" error on the stream when the client has gone away.
-            let final_eof = stream::once::<String, _>(Err(io::Error::new(io::ErrorKind::Other, "EOF")));
-            let reader = reader.chain(final_eof);
-            let eof_clone = ctx.eof.clone();
-
-            // We read the incoming stream continously, and forward it onto a channel.
-            // That way we can detect immediately if the client has gone away (EOF).
-            let (tx, rx) = mpsc::channel(0);
-            let fut = reader.then(move |res| {
-                if let Err(_) = res {
-                    //debug!("reader saw error, setting EOF flag in Context");
-                    eof_clone.store(true, Ordering::SeqCst);
+    // replies are written by a separate task. the lines of one reply
+    // (more than one for MULTI) are written together.
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<String>>(MAX_PIPELINE);
+    let write_replies = tokio::spawn(async move {
+        while let Some(lines) = reply_rx.recv().await {
+            for line in lines {
+                if writer.feed(line).await.is_err() {
+                    return;
                 }
-                tx.clone().send(res)
-            })
-            .for_each(|_| Ok(()));
-
-            // add a timeout. FIXME? this is a hard session timeout, not per-request.
-            let timeout = Duration::new(10, 0);
-            let fut = fut.timeout(timeout).map_err(|e| {
-                debug!("command reader timeout wrapper: error on {:?}", e);
-                ()
-            });
-            tokio::spawn(fut);
-
-            // The reader side of the channel reads Result<Result<Item, Error>, ()>.
-            // Unwrap one level of Result.
-            let reader = rx.then(|x| x.unwrap());
-
-            // process commands and write result.
-            let ctx = ctx.clone();
-            let responses = reader.and_then(move |line| request::process(ctx.clone(), line));
-            let session = writer.send_all(responses).map_err(|_| ()).map(|_| ());
-            session
-        })
-        .listen(concurrency);
-
-    tokio::run(server);
-    exit(1);
-}
+            }
+            if SinkExt::<String>::flush(&mut writer).await.is_err() {
+                return;
+            }
+        }
+    });
 
+    // process commands and send the result to the writer.
+    let pipeline = Arc::new(Semaphore::new(MAX_PIPELINE));
+    while let Some(line) = rx.recv().await {
+        let (tag, line) = request::split_tag(&line);
+        let tag = tag.map(|t| t.to_string());
+        let line = line.to_string();
+        let ctx = ctx.clone();
+        match tag {
+            Some(tag) => {
+                let permit = pipeline.clone().acquire_owned().await.unwrap();
+                let reply_tx = reply_tx.clone();
+                tokio::spawn(async move {
+                    let lines = request::process_line(ctx, line).await;
+                    let lines = lines.into_iter().map(|l| format!("{} {}", tag, l)).collect();
+                    let _ = reply_tx.send(lines).await;
+                    drop(permit);
+                });
+            },
+            None => {
+                let lines = request::process_line(ctx, line).await;
+                if reply_tx.send(lines).await.is_err() {
+                    break;
+                }
+            },
+        }
+    }
+
+    // wait until all replies have been written.
+    drop(reply_tx);
+    let _ = write_replies.await;
+}
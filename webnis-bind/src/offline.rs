@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pwhash::{sha512_crypt, unix};
+
+use crate::config::Config;
+use crate::request::Cmd;
+
+const DEFAULT_MAX_AGE: u64 = 7 * 86400;
+
+#[derive(Serialize, Deserialize, Default)]
+struct Store {
+    // map lookups. key is "Cmd:arg", e.g. "GetPwNam:mikevs".
+    #[serde(default)]
+    entries:    HashMap<String, Entry>,
+    // username -> salted password hash.
+    #[serde(default)]
+    auth:       HashMap<String, Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    data:   String,
+    time:   u64,
+}
+
+struct Inner {
+    store:  Store,
+    dirty:  bool,
+}
+
+/// On-disk store of recent answers and credentials, used when
+/// none of the servers can be reached (offline mode).
+///
+/// Successful passwd/group/gidlist answers are stored as-is. For
+/// successful AUTH requests a salted hash of the password is stored.
+/// Nothing older than `offline_max_age` seconds is ever used.
+pub struct OfflineDb {
+    path:       String,
+    max_age:    u64,
+    inner:      Mutex<Inner>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn key(cmd: Cmd, arg: &str) -> String {
+    format!("{:?}:{}", cmd, arg)
+}
+
+impl OfflineDb {
+    /// Returns Ok(None) if `offline_db` is not set.
+    pub fn open(config: &Config) -> io::Result<Option<OfflineDb>> {
+        let path = match config.offline_db {
+            Some(ref p) => p.to_string(),
+            None => return Ok(None),
+        };
+        let store = match fs::read(&path) {
+            Ok(data) => {
+                serde_json::from_slice(&data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Store::default(),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path, e))),
+        };
+        Ok(Some(OfflineDb {
+            path:       path,
+            max_age:    config.offline_max_age.unwrap_or(DEFAULT_MAX_AGE),
+            inner:      Mutex::new(Inner { store, dirty: false }),
+        }))
+    }
+
+    /// Remember the reply to a map lookup. Only 2xx replies are stored,
+    /// a 404 reply removes the entry.
+    pub fn store(&self, cmd: Cmd, arg: &str, line: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = key(cmd, arg);
+        match crate::cache::reply_code(line) {
+            200..=299 => {
+                inner.store.entries.insert(key, Entry { data: line.to_string(), time: now() });
+                inner.dirty = true;
+            },
+            404 => {
+                if inner.store.entries.remove(&key).is_some() {
+                    inner.dirty = true;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Find the reply to a map lookup.
+    pub fn lookup(&self, cmd: Cmd, arg: &str) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        let entry = inner.store.entries.get(&key(cmd, arg))?;
+        if entry.time + self.max_age < now() {
+            return None;
+        }
+        Some(entry.data.clone())
+    }
+
+    /// Remember a successful authentication.
+    pub fn auth_ok(&self, username: &str, password: &str) {
+        // if the password did not change, just update the timestamp.
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(entry) = inner.store.auth.get_mut(username) {
+                if unix::verify(password, &entry.data) {
+                    entry.time = now();
+                    inner.dirty = true;
+                    return;
+                }
+            }
+        }
+        let hash = match sha512_crypt::hash(password) {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("offline: cannot hash password: {}", e);
+                return;
+            },
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.store.auth.insert(username.to_string(), Entry { data: hash, time: now() });
+        inner.dirty = true;
+    }
+
+    /// The server rejected this username/password. If it is the one we
+    /// have stored, it must have been changed, so forget about it.
+    pub fn auth_rejected(&self, username: &str, password: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let matches = match inner.store.auth.get(username) {
+            Some(entry) => unix::verify(password, &entry.data),
+            None => false,
+        };
+        if matches {
+            inner.store.auth.remove(username);
+            inner.dirty = true;
+        }
+    }
+
+    /// Check username/password against the stored credentials.
+    pub fn auth_verify(&self, username: &str, password: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.store.auth.get(username) {
+            Some(entry) => entry.time + self.max_age >= now() && unix::verify(password, &entry.data),
+            None => false,
+        }
+    }
+
+    /// Write the store to disk if it was changed. Entries that
+    /// are too old to be used are dropped.
+    pub fn flush(&self) -> io::Result<()> {
+        let data = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return Ok(());
+            }
+            let oldest = now().saturating_sub(self.max_age);
+            inner.store.entries.retain(|_, e| e.time >= oldest);
+            inner.store.auth.retain(|_, e| e.time >= oldest);
+            inner.dirty = false;
+            serde_json::to_vec(&inner.store).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        };
+
+        let res = self.write(&data);
+        if res.is_err() {
+            // try again next time.
+            self.inner.lock().unwrap().dirty = true;
+        }
+        res
+    }
+
+    // write to a temporary file, then rename, so that the
+    // store is never left half-written.
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let tmp = format!("{}.tmp", self.path);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+impl OfflineDb {
+    /// A store that is never written to disk, for tests.
+    pub fn in_memory() -> OfflineDb {
+        OfflineDb {
+            path:       "/nonexistent".to_string(),
+            max_age:    60,
+            inner:      Mutex::new(Inner { store: Store::default(), dirty: false }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> OfflineDb {
+        OfflineDb::in_memory()
+    }
+
+    #[test]
+    fn t_lookup() {
+        let db = db();
+        db.store(Cmd::GetPwNam, "a", "200 a:x:1:1:::");
+        db.store(Cmd::GetPwNam, "b", "500 HTTP error");
+        assert_eq!(db.lookup(Cmd::GetPwNam, "a").unwrap(), "200 a:x:1:1:::");
+        assert!(db.lookup(Cmd::GetPwNam, "b").is_none());
+        db.store(Cmd::GetPwNam, "a", "404 Not Found");
+        assert!(db.lookup(Cmd::GetPwNam, "a").is_none());
+    }
+
+    #[test]
+    fn t_auth() {
+        let db = db();
+        db.auth_ok("truus", "secret");
+        assert!(db.auth_verify("truus", "secret"));
+        assert!(!db.auth_verify("truus", "wrong"));
+        db.auth_rejected("truus", "wrong");
+        assert!(db.auth_verify("truus", "secret"));
+        db.auth_rejected("truus", "secret");
+        assert!(!db.auth_verify("truus", "secret"));
+    }
+}
@@ -0,0 +1,354 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::config::Config;
+
+const DEFAULT_INTERVAL: u64 = 5;
+const DEFAULT_FALL: u32 = 2;
+const DEFAULT_RISE: u32 = 3;
+// without health checks, retry a down server after this many seconds.
+const DOWN_RETRY: u64 = 30;
+// servers with a latency within this factor (plus a few ms) of the
+// fastest one are treated as equal, and are picked by SRV weight.
+const SIMILAR_LATENCY: f64 = 1.2;
+const SIMILAR_LATENCY_MS: f64 = 2.0;
+
+/// State of a server in the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    /// Healthy, takes requests.
+    Up,
+    /// Failed, only gets health checks.
+    Down,
+    /// Was down, came back. Used only if no server is up, until it
+    /// has answered `rise` requests or health checks in a row.
+    Probation,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            State::Up => "up",
+            State::Down => "down",
+            State::Probation => "probation",
+        }
+    }
+}
+
+struct Server {
+    name:       String,
+    // lower is preferred (SRV priority).
+    priority:   u16,
+    // share of the requests among equal servers (SRV weight).
+    weight:     u16,
+    state:      State,
+    since:      Instant,
+    // moving average of the response time, in milliseconds.
+    latency:    Option<f64>,
+    // consecutive failures or successes.
+    failures:   u32,
+    successes:  u32,
+}
+
+/// The set of webnis servers, with their health state.
+///
+/// Requests go to the healthy server with the lowest latency, within
+/// the group of servers with the lowest priority value. If several
+/// servers have about the same latency, one is picked at random by
+/// weight; if they all have weight 0, the fastest one. A server
+/// that fails `fall` times in a row is marked down. When a health check
+/// or request succeeds again it goes into probation, and after `rise`
+/// successes in a row it is up again.
+pub struct Pool {
+    servers:    Vec<Server>,
+    // offset for tie-breaking, so that not all processes pick the same server.
+    offset:     usize,
+    fall:       u32,
+    rise:       u32,
+    interval:   Option<Duration>,
+}
+
+impl Pool {
+    pub fn new(config: &Config, servers: &[String]) -> Pool {
+        let interval = config.health_check_interval.unwrap_or(DEFAULT_INTERVAL);
+        let mut pool = Pool {
+            servers:    Vec::new(),
+            offset:     0,
+            fall:       std::cmp::max(1, config.health_check_fall.unwrap_or(DEFAULT_FALL)),
+            rise:       std::cmp::max(1, config.health_check_rise.unwrap_or(DEFAULT_RISE)),
+            interval:   if interval > 0 { Some(Duration::from_secs(interval)) } else { None },
+        };
+        pool.set_servers(servers.iter().map(|s| (s.to_string(), 0, 0)).collect());
+        if pool.servers.len() > 0 {
+            pool.offset = std::process::id() as usize % pool.servers.len();
+        }
+        pool
+    }
+
+    /// Replace the list of servers (name, priority, weight). The state of
+    /// servers that were already in the pool is kept, new servers start out as up.
+    pub fn set_servers(&mut self, servers: Vec<(String, u16, u16)>) {
+        let now = Instant::now();
+        let mut old = std::mem::replace(&mut self.servers, Vec::new());
+        for (name, priority, weight) in servers {
+            let server = match old.iter().position(|s| s.name == name) {
+                Some(idx) => {
+                    let mut s = old.remove(idx);
+                    s.priority = priority;
+                    s.weight = weight;
+                    s
+                },
+                None => Server {
+                    name:       name,
+                    priority:   priority,
+                    weight:     weight,
+                    state:      State::Up,
+                    since:      now,
+                    latency:    None,
+                    failures:   0,
+                    successes:  0,
+                },
+            };
+            self.servers.push(server);
+        }
+        // the list is in order of preference, don't rotate it.
+        self.offset = 0;
+    }
+
+    /// Interval between health checks, None if disabled.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Name (hostname) of a server.
+    pub fn name(&self, idx: usize) -> &str {
+        &self.servers[idx].name
+    }
+
+    // state of a server as far as selecting it goes.
+    fn select_state(&self, server: &Server) -> State {
+        // without active health checks, nothing will ever
+        // bring a server back up, so retry it after a while.
+        if server.state == State::Down &&
+           self.interval.is_none() &&
+           server.since.elapsed() >= Duration::from_secs(DOWN_RETRY) {
+            return State::Probation;
+        }
+        server.state
+    }
+
+    /// Select a server to send a request to. Returns None if the pool is empty.
+    pub fn select(&self) -> Option<usize> {
+        self.select_with(&mut rand::thread_rng())
+    }
+
+    fn select_with<R: Rng>(&self, rng: &mut R) -> Option<usize> {
+        let n = self.servers.len();
+        // no latency known yet, try it.
+        let latency = |idx: usize| self.servers[idx].latency.unwrap_or(0.0);
+        for &state in &[State::Up, State::Probation] {
+            let mut group = (0 .. n)
+                .map(|i| (i + self.offset) % n)
+                .filter(|&idx| self.select_state(&self.servers[idx]) == state)
+                .collect::<Vec<_>>();
+            let prio = match group.iter().map(|&idx| self.servers[idx].priority).min() {
+                Some(prio) => prio,
+                None => continue,
+            };
+            group.retain(|&idx| self.servers[idx].priority == prio);
+
+            // the fastest server, and the ones that are about as fast.
+            group.sort_by(|&a, &b| latency(a).partial_cmp(&latency(b)).unwrap());
+            let limit = latency(group[0]) * SIMILAR_LATENCY + SIMILAR_LATENCY_MS;
+            group.retain(|&idx| latency(idx) <= limit);
+
+            let total: u32 = group.iter().map(|&idx| self.servers[idx].weight as u32).sum();
+            if total == 0 {
+                return Some(group[0]);
+            }
+            let mut pick = rng.gen_range(0 .. total);
+            for &idx in &group {
+                let weight = self.servers[idx].weight as u32;
+                if pick < weight {
+                    return Some(idx);
+                }
+                pick -= weight;
+            }
+        }
+        // everything is down. pick the one that went down first.
+        (0 .. n).min_by_key(|&idx| self.servers[idx].since)
+    }
+
+    /// Names of all servers.
+    pub fn names(&self) -> Vec<String> {
+        self.servers.iter().map(|s| s.name.clone()).collect()
+    }
+
+    // find a server by name. the server list might have
+    // been changed while a request was in flight.
+    fn find(&self, name: &str) -> Option<usize> {
+        self.servers.iter().position(|s| s.name == name)
+    }
+
+    /// A request or health check to this server succeeded.
+    pub fn report_ok(&mut self, name: &str, elapsed: Duration) {
+        let idx = match self.find(name) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let rise = self.rise;
+        let state = self.select_state(&self.servers[idx]);
+        let server = &mut self.servers[idx];
+        let ms = elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_micros() as f64 / 1000.0;
+        server.latency = Some(match server.latency {
+            Some(l) => 0.7 * l + 0.3 * ms,
+            None => ms,
+        });
+        server.failures = 0;
+        server.successes += 1;
+        let new_state = match state {
+            State::Up => State::Up,
+            State::Down => State::Probation,
+            State::Probation if server.successes >= rise => State::Up,
+            State::Probation => State::Probation,
+        };
+        if new_state != server.state {
+            info!("server {}: {} -> {}", server.name, server.state.as_str(), new_state.as_str());
+            server.state = new_state;
+            server.since = Instant::now();
+        }
+    }
+
+    /// A request or health check to this server failed.
+    pub fn report_error(&mut self, name: &str) {
+        let idx = match self.find(name) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let fall = self.fall;
+        let server = &mut self.servers[idx];
+        server.successes = 0;
+        server.failures += 1;
+        let down = match server.state {
+            State::Up => server.failures >= fall,
+            State::Probation => true,
+            State::Down => false,
+        };
+        if down || (server.state == State::Down && self.interval.is_none()) {
+            if server.state != State::Down {
+                info!("server {}: {} -> down", server.name, server.state.as_str());
+            }
+            server.state = State::Down;
+            server.since = Instant::now();
+        }
+    }
+
+    /// Status of the pool, for the `servers` command.
+    pub fn status(&self) -> serde_json::Value {
+        let servers = self.servers.iter().map(|s| {
+            json!({
+                "server":       s.name,
+                "priority":     s.priority,
+                "weight":       s.weight,
+                "state":        s.state.as_str(),
+                "since":        s.since.elapsed().as_secs(),
+                "latency_ms":   s.latency.map(|l| (l * 10.0).round() / 10.0),
+                "failures":     s.failures,
+            })
+        }).collect::<Vec<_>>();
+        json!({
+            "active":   self.select().map(|idx| &self.servers[idx].name),
+            "servers":  servers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: usize) -> Pool {
+        let now = Instant::now();
+        Pool {
+            servers:    (0 .. n).map(|i| Server {
+                name:       format!("s{}", i),
+                priority:   0,
+                weight:     0,
+                state:      State::Up,
+                since:      now,
+                latency:    None,
+                failures:   0,
+                successes:  0,
+            }).collect(),
+            offset:     0,
+            fall:       2,
+            rise:       2,
+            interval:   Some(Duration::from_secs(5)),
+        }
+    }
+
+    #[test]
+    fn t_latency() {
+        let mut p = pool(3);
+        p.report_ok("s0", Duration::from_millis(50));
+        p.report_ok("s1", Duration::from_millis(10));
+        p.report_ok("s2", Duration::from_millis(30));
+        assert_eq!(p.select().unwrap(), 1);
+        p.report_ok("s1", Duration::from_millis(200));
+        assert_eq!(p.select().unwrap(), 2);
+
+        // a lower priority value always wins.
+        p.set_servers(vec![("s0".to_string(), 10, 0), ("s1".to_string(), 10, 0), ("s2".to_string(), 20, 0)]);
+        assert_eq!(p.select().unwrap(), 0);
+        assert_eq!(p.name(0), "s0");
+    }
+
+    #[test]
+    fn t_state_machine() {
+        let mut p = pool(2);
+        p.report_ok("s0", Duration::from_millis(1));
+        p.report_ok("s1", Duration::from_millis(5));
+        p.report_error("s0");
+        assert_eq!(p.servers[0].state, State::Up);
+        p.report_error("s0");
+        assert_eq!(p.servers[0].state, State::Down);
+        assert_eq!(p.select().unwrap(), 1);
+        p.report_ok("s0", Duration::from_millis(1));
+        assert_eq!(p.servers[0].state, State::Probation);
+        assert_eq!(p.select().unwrap(), 1);
+        p.report_ok("s0", Duration::from_millis(1));
+        assert_eq!(p.servers[0].state, State::Up);
+        assert_eq!(p.select().unwrap(), 0);
+        // failure in probation means down again.
+        p.report_error("s0");
+        p.report_error("s0");
+        p.report_ok("s0", Duration::from_millis(1));
+        p.report_error("s0");
+        assert_eq!(p.servers[0].state, State::Down);
+    }
+
+    #[test]
+    fn t_weight() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut p = pool(0);
+        p.set_servers(vec![("s0".to_string(), 10, 90), ("s1".to_string(), 10, 10), ("s2".to_string(), 20, 100)]);
+        p.report_ok("s0", Duration::from_millis(10));
+        p.report_ok("s1", Duration::from_millis(11));
+        p.report_ok("s2", Duration::from_millis(1));
+
+        // s0 and s1 are about as fast, and are picked by weight.
+        let mut count = [0; 3];
+        for _ in 0 .. 1000 {
+            count[p.select_with(&mut rng).unwrap()] += 1;
+        }
+        assert!(count[0] > 800 && count[1] > 50 && count[2] == 0);
+
+        // a much slower server is not picked, whatever its weight.
+        p.report_ok("s0", Duration::from_millis(100));
+        for _ in 0 .. 100 {
+            assert_eq!(p.select_with(&mut rng).unwrap(), 1);
+        }
+    }
+}
@@ -1,72 +1,241 @@
 
 use std::io;
-use std::time::{Instant,Duration};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
 use std::sync::atomic::Ordering;
 
-use url::percent_encoding::{
-    utf8_percent_encode,
-    DEFAULT_ENCODE_SET,
-    QUERY_ENCODE_SET
-};
-use hyper;
-use hyper::client::HttpConnector;
-use hyper::{header,Method};
-use hyper_tls::HttpsConnector;
-use tokio::prelude::*;
-use tokio::timer::Delay;
+use bytes::Bytes;
 use futures::future;
-use base64;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use http_body_util::{BodyExt, Full};
+use hyper::{header, Method};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use tokio::time;
 
 use crate::Context;
-use crate::response::Response;
+use crate::config::Config;
+use crate::domain::Domain;
+use crate::offline::OfflineDb;
+use crate::cache::reply_code;
+use crate::response::{ChangesResponse, Response};
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("either the \"native-tls\" or the \"rustls\" feature must be enabled");
 
 const MAX_TRIES: u32 = 8;
 const RETRY_DELAY_MS: u64 = 250;
 const REQUEST_TIMEOUT_MS: u64 = 1000;
+// how long the server may wait before answering a changes request,
+// and how long to wait before trying again after an error.
+const CHANGES_TIMEOUT: u64 = 30;
+const CHANGES_RETRY_DELAY: u64 = 5;
+// max number of keys in a MULTI request.
+const MAX_MULTI: usize = 256;
+
+const X_WWW_FORM: &str = "application/x-www-form-urlencoded";
+const APPL_JSON: &str = "application/json";
+
+// The same sets as the QUERY_ENCODE_SET and DEFAULT_ENCODE_SET
+// from the old url::percent_encoding module.
+const QUERY_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'<').add(b'>');
+const DEFAULT_ENCODE_SET: &AsciiSet = &QUERY_ENCODE_SET.add(b'`').add(b'?').add(b'{').add(b'}');
+
+#[cfg(feature = "rustls")]
+type HttpsConnector = hyper_rustls::HttpsConnector<HttpConnector>;
+#[cfg(not(feature = "rustls"))]
+type HttpsConnector = hyper_tls::HttpsConnector<HttpConnector>;
+
+/// The HTTP client type.
+pub(crate) type Client = hyper_util::client::legacy::Client<HttpsConnector, Full<Bytes>>;
 
 /// Possible requests our clients can send us
 pub(crate) struct Request<'a> {
     cmd:    Cmd,
     args:   Vec<&'a str>,
     arg0:   u32,
+    // "getpwnam@domain" form.
+    domain: Option<&'a str>,
 }
 
-pub(crate) fn process(ctx: Context, line: String) -> Box<Future<Item=String, Error=io::Error> + Send> {
-    let request = match Request::parse(&line) {
-        Ok(req) => req,
-        Err(e) => return Box::new(future::ok(Response::error(400, &e))),
+// How bad a reply is, for the lookup chain.
+fn reply_rank(line: &str) -> u8 {
+    match reply_code(line) {
+        200..=299 => 0,
+        404 => 1,
+        408 | 500..=599 => 3,
+        _ => 2,
+    }
+}
+
+// Remember the reply to return if no domain in the lookup chain has an
+// answer: "could not reach the servers" first, then other errors, then 404.
+fn chain_reply(worst: Option<String>, line: String) -> Option<String> {
+    match worst {
+        Some(w) if reply_rank(&w) >= reply_rank(&line) => Some(w),
+        _ => Some(line),
+    }
+}
+
+// Build the reply to an auth request from the replies of the domains
+// that were tried, and keep the offline store up to date.
+//
+// When the servers cannot be reached, the offline store may accept the
+// password instead. But not if any domain rejected it: that password
+// might have been changed, and must not keep working just because some
+// other domain is down.
+fn auth_done(offline: Option<&OfflineDb>, key: &str, password: &str, replies: Vec<String>) -> String {
+    let mut reply = None;
+    let mut rejected = None;
+    for line in replies {
+        match reply_code(&line) {
+            200 => {
+                reply = Some(line);
+                break;
+            },
+            401 | 403 if rejected.is_none() => rejected = Some(line.clone()),
+            _ => {},
+        }
+        reply = chain_reply(reply, line);
+    }
+    let mut line = reply.unwrap();
+    if reply_code(&line) != 200 {
+        if let Some(r) = rejected {
+            line = r;
+        }
+    }
+
+    let offline = match offline {
+        Some(o) => o,
+        None => return line,
     };
 
+    // remember successful logins, so that we can still authenticate
+    // users when none of the servers can be reached.
+    match reply_code(&line) {
+        200 => offline.auth_ok(key, password),
+        401 | 403 => offline.auth_rejected(key, password),
+        408 | 500..=599 if offline.auth_verify(key, password) => {
+            debug!("offline: authenticated {} because of {}", key, line);
+            return "200 OK".to_string();
+        },
+        _ => {},
+    }
+    line
+}
+
+// Check if the client is allowed to do this request.
+fn check_restrict(ctx: &Context, request: &Request) -> Result<(), String> {
+
     // getpwuid() might be restricted to only looking up your own uid.
     if ctx.config.restrict_getpwuid && request.cmd == Cmd::GetPwUid {
         if ctx.uid > 0 && request.arg0 != ctx.uid {
-            return Box::new(future::ok(Response::error(403, "Forbidden")));
+            return Err(Response::error(403, "Forbidden"));
         }
     }
 
+    // raw map lookups and listings can return anything, so they are for root only.
+    if (request.cmd == Cmd::Map || request.cmd == Cmd::List) && ctx.uid != 0 {
+        return Err(Response::error(403, "Forbidden"));
+    }
+
     // getgrgid() might be restricted to only looking up gids < 1000 and your own gid.
     if ctx.config.restrict_getgrgid && request.cmd == Cmd::GetGrGid {
         if ctx.uid > 0 && request.arg0 >= 1000 && request.arg0 != ctx.gid {
-            return Box::new(future::ok(Response::error(403, "Forbidden")));
+            return Err(Response::error(403, "Forbidden"));
         }
     }
+    Ok(())
+}
 
-    let anchor;
-    let token = match ctx.config.http_authencoding.as_ref().map(|s| s.as_str()) {
-        Some("base64") => {
-            anchor = base64::encode(&ctx.config.http_authtoken);
-            &anchor
+// the domains to try, in order.
+fn select_domains(ctx: &Context, domain: Option<&str>) -> Result<Vec<Arc<Domain>>, String> {
+    match domain {
+        Some(name) => {
+            match ctx.domains.iter().find(|d| d.name == name) {
+                Some(d) => Ok(vec![d.clone()]),
+                None => Err(Response::error(400, "unknown domain")),
+            }
         },
-        _ => &ctx.config.http_authtoken,
+        None => Ok(ctx.domains.to_vec()),
+    }
+}
+
+// key for the cache and the offline store.
+fn store_key(request: &Request) -> String {
+    match request.domain {
+        Some(name) => format!("{} {}", name, request.args[0]),
+        None => request.args[0].to_string(),
+    }
+}
+
+// map and query parameter for a lookup command.
+fn map_param(cmd: Cmd) -> (&'static str, &'static str) {
+    match cmd {
+        Cmd::GetPwNam => ("passwd", "username"),
+        Cmd::GetPwUid => ("passwd", "uid"),
+        Cmd::GetGrNam => ("group", "group"),
+        Cmd::GetGrGid => ("group", "gid"),
+        Cmd::GetGidList => ("gidlist", "username"),
+        _ => unreachable!(),
+    }
+}
+
+/// Split the optional request id ("#<id> ") off a line.
+pub(crate) fn split_tag(line: &str) -> (Option<&str>, &str) {
+    if line.starts_with('#') {
+        let mut parts = line.splitn(2, ' ');
+        let tag = parts.next().unwrap();
+        return (Some(tag), parts.next().unwrap_or(""));
+    }
+    (None, line)
+}
+
+/// Process one line, which can be a MULTI command. Returns the reply lines.
+pub(crate) async fn process_line(ctx: Context, line: String) -> Vec<String> {
+    let mut buf = [0u8; 16];
+    let is_multi = tolower(line.split(' ').next().unwrap(), &mut buf) == "multi";
+    if is_multi {
+        process_multi(ctx, &line).await
+    } else {
+        vec![process(ctx, line).await]
+    }
+}
+
+pub(crate) async fn process(ctx: Context, line: String) -> String {
+    let request = match Request::parse(&line) {
+        Ok(req) => req,
+        Err(e) => return Response::error(400, &e),
+    };
+    if let Err(e) = check_restrict(&ctx, &request) {
+        return e;
+    }
+    let domains = match select_domains(&ctx, request.domain) {
+        Ok(d) => d,
+        Err(e) => return e,
     };
-    let authorization = format!("{} {}", ctx.config.http_authschema, token);
+
+    if request.cmd == Cmd::Servers {
+        // output the state of the server pools.
+        let domains = domains.iter().map(|d| {
+            let mut status = d.pool.lock().unwrap().status();
+            status["domain"] = json!(d.name);
+            status
+        }).collect::<Vec<_>>();
+        let reply = json!({ "domains": domains });
+        return format!("200 {}", reply.to_string());
+    }
+
+    if request.cmd == Cmd::Map || request.cmd == Cmd::List {
+        return lookup_raw(&ctx, &domains, &request).await;
+    }
+
+    let key = store_key(&request);
 
     if request.cmd == Cmd::Auth {
         // authentication
         // note that the password has already been percent encoded by
         // the client (webnis-pam), we do not have to encode again.
-        let path = format!("/{}/auth",
-                        utf8_percent_encode(&ctx.config.domain, DEFAULT_ENCODE_SET));
         let mut body = format!("username={}&password={}",
                         utf8_percent_encode(&request.args[0], QUERY_ENCODE_SET),
                         request.args[1]);
@@ -76,45 +245,232 @@ pub(crate) fn process(ctx: Context, line: String) -> Box<Future<Item=String, Err
         if request.args.len() > 3 {
             body.push_str(&format!("&remote={}", utf8_percent_encode(&request.args[3], QUERY_ENCODE_SET)));
         }
-        return req_with_retries(&ctx, path, authorization, Some(body), 1)
+
+        // try the domains in order, until one accepts.
+        let mut replies = Vec::new();
+        for domain in &domains {
+            let path = format!("/{}/auth", utf8_percent_encode(&domain.name, DEFAULT_ENCODE_SET));
+            let res = req_with_retries(&ctx, domain, &path, Some((X_WWW_FORM, body.clone())), 1).await;
+            let line = reply_line(res);
+            let ok = reply_code(&line) == 200;
+            replies.push(line);
+            if ok {
+                break;
+            }
+        }
+        return auth_done(ctx.offline.as_deref(), &key, request.args[1], replies);
     }
 
-    if request.cmd == Cmd::Servers {
-        // output the configured servers and the currently active server.
-        let (active, seqno) = {
-            let mut guard = ctx.http_client.lock().unwrap();
-            let http_client = &mut *guard;
-            let active = if http_client.client.is_none() {
-                None
-            } else {
-                Some(&ctx.config.servers[http_client.seqno % ctx.config.servers.len()])
-            };
-            (active, http_client.seqno)
-        };
-        let reply = json!({
-            "seqno":    seqno,
-            "active":   active,
-            "servers":  ctx.config.servers,
+    // map lookup
+    let (map, param) = map_param(request.cmd);
+
+    // see if we have a fresh answer in the cache.
+    let cmd = request.cmd;
+    if let Some(ref cache) = ctx.cache {
+        if let Some(line) = cache.lock().unwrap().get(cmd, &key) {
+            return line;
+        }
+    }
+
+    // no, ask the servers of each domain in turn, until one has an answer.
+    let mut reply = None;
+    for domain in &domains {
+        let line = lookup_map(&ctx, domain, map, param, request.args[0]).await;
+        if reply_rank(&line) == 0 {
+            reply = Some(line);
+            break;
+        }
+        reply = chain_reply(reply, line);
+    }
+    lookup_done(&ctx, cmd, &key, reply.unwrap())
+}
+
+// MULTI <cmd> <key1> <key2> ...
+//
+// Looks up several keys in the same map. The reply is one line per key,
+// in the same order as the keys. The keys that are not in the cache are
+// sent to the server in one batch request.
+async fn process_multi(ctx: Context, line: &str) -> Vec<String> {
+    let mut parts = line.split(' ').skip(1);
+    let c = parts.next().unwrap_or("");
+    let keys = parts.collect::<Vec<_>>();
+    if keys.len() == 0 || keys.len() > MAX_MULTI {
+        return vec![Response::error(400, &format!("multi needs a command and 1-{} keys", MAX_MULTI))];
+    }
+
+    // parse every key as a separate "<cmd> <key>" request.
+    let lines = keys.iter().map(|k| format!("{} {}", c, k)).collect::<Vec<_>>();
+    let mut replies: Vec<Option<String>> = vec![None; keys.len()];
+    let mut requests = Vec::new();
+    for (idx, line) in lines.iter().enumerate() {
+        let res = Request::parse(line).and_then(|request| {
+            match request.cmd {
+                Cmd::Auth | Cmd::Servers | Cmd::Map | Cmd::List => Err(format!("cannot use {} in multi", c)),
+                _ => Ok(request),
+            }
         });
-        return Box::new(future::ok(format!("200 {}", reply.to_string())));
+        match res {
+            Ok(request) => match check_restrict(&ctx, &request) {
+                Ok(()) => requests.push((idx, request)),
+                Err(e) => replies[idx] = Some(e),
+            },
+            Err(e) => replies[idx] = Some(Response::error(400, &e)),
+        }
     }
 
-    // map lookup
-    let (map, param) = match request.cmd {
-        Cmd::GetPwNam => ("passwd", "username"),
-        Cmd::GetPwUid => ("passwd", "uid"),
-        Cmd::GetGrNam => ("group", "group"),
-        Cmd::GetGrGid => ("group", "gid"),
-        Cmd::GetGidList => ("gidlist", "username"),
-        _ => unreachable!(),
-    };
+    if requests.len() > 0 {
+        let cmd = requests[0].1.cmd;
+        let (map, param) = map_param(cmd);
+        let domains = match select_domains(&ctx, requests[0].1.domain) {
+            Ok(d) => d,
+            Err(e) => return vec![e; keys.len()],
+        };
+
+        // answer what we can from the cache.
+        let mut pending = Vec::new();
+        for &(idx, ref request) in &requests {
+            let key = store_key(request);
+            let cached = ctx.cache.as_ref().and_then(|c| c.lock().unwrap().get(cmd, &key));
+            match cached {
+                Some(line) => replies[idx] = Some(line),
+                None => pending.push((idx, key, request.args[0])),
+            }
+        }
+
+        // ask the servers of each domain in turn, for the
+        // keys that do not have an answer yet.
+        let mut answers: Vec<Option<String>> = vec![None; pending.len()];
+        for domain in &domains {
+            let todo = (0 .. pending.len())
+                .filter(|&i| answers[i].as_ref().map(|l| reply_rank(l) > 0).unwrap_or(true))
+                .collect::<Vec<_>>();
+            if todo.len() == 0 {
+                break;
+            }
+            let args = todo.iter().map(|&i| pending[i].2).collect::<Vec<_>>();
+            let lines = lookup_map_batch(&ctx, domain, map, param, &args).await;
+            for (i, line) in todo.into_iter().zip(lines.into_iter()) {
+                answers[i] = if reply_rank(&line) == 0 {
+                    Some(line)
+                } else {
+                    chain_reply(answers[i].take(), line)
+                };
+            }
+        }
+        for ((idx, key, _), line) in pending.into_iter().zip(answers.into_iter()) {
+            replies[idx] = Some(lookup_done(&ctx, cmd, &key, line.unwrap()));
+        }
+    }
+
+    replies.into_iter().map(|r| r.unwrap()).collect()
+}
+
+// Store the answer of a map lookup, or if the servers could not be
+// reached, serve a stale or offline answer if we have one.
+fn lookup_done(ctx: &Context, cmd: Cmd, key: &str, line: String) -> String {
+    match reply_code(&line) {
+        408 | 500..=599 => {
+            let stale = ctx.cache.as_ref().and_then(|c| c.lock().unwrap().get_stale(cmd, key));
+            if let Some(stale) = stale {
+                debug!("serving stale cache entry for {:?} {} because of {}", cmd, key, line);
+                return stale;
+            }
+            if let Some(stored) = ctx.offline.as_ref().and_then(|o| o.lookup(cmd, key)) {
+                debug!("serving offline entry for {:?} {} because of {}", cmd, key, line);
+                return stored;
+            }
+        },
+        _ => {
+            if let Some(ref cache) = ctx.cache {
+                cache.lock().unwrap().insert(cmd, key, &line);
+            }
+            if let Some(ref offline) = ctx.offline {
+                offline.store(cmd, key, &line);
+            }
+        },
+    }
+    line
+}
+
+// Look up one key in a map.
+async fn lookup_map(ctx: &Context, domain: &Domain, map: &str, param: &str, arg: &str) -> String {
     let path = format!("/{}/map/{}?{}={}&cred_uid={}",
-                utf8_percent_encode(&ctx.config.domain, DEFAULT_ENCODE_SET),
+                utf8_percent_encode(&domain.name, DEFAULT_ENCODE_SET),
                 utf8_percent_encode(map, DEFAULT_ENCODE_SET),
                 utf8_percent_encode(param, QUERY_ENCODE_SET),
-                utf8_percent_encode(&request.args[0], QUERY_ENCODE_SET),
+                utf8_percent_encode(arg, QUERY_ENCODE_SET),
                 ctx.uid);
-    req_with_retries(&ctx, path, authorization, None, 0)
+    reply_line(req_with_retries(ctx, domain, &path, None, 0).await)
+}
+
+// MAP <map> <keyname> <keyvalue>, LIST <map>
+//
+// Look up a key in any map, or list all entries of a map. The reply is
+// the JSON result as-is, with the name of the server that answered:
+// 200 {"server":"<server>","result":<result>}. These are not cached.
+async fn lookup_raw(ctx: &Context, domains: &[Arc<Domain>], request: &Request<'_>) -> String {
+    let mut reply = None;
+    for domain in domains {
+        let path = match request.cmd {
+            Cmd::Map => format!("/{}/map/{}?{}={}&cred_uid={}",
+                utf8_percent_encode(&domain.name, DEFAULT_ENCODE_SET),
+                utf8_percent_encode(request.args[0], DEFAULT_ENCODE_SET),
+                utf8_percent_encode(request.args[1], QUERY_ENCODE_SET),
+                utf8_percent_encode(request.args[2], QUERY_ENCODE_SET),
+                ctx.uid),
+            _ => format!("/{}/map/{}/list?cred_uid={}",
+                utf8_percent_encode(&domain.name, DEFAULT_ENCODE_SET),
+                utf8_percent_encode(request.args[0], DEFAULT_ENCODE_SET),
+                ctx.uid),
+        };
+        let line = match req_with_server(ctx, domain, &path, None, 0).await {
+            Ok((server, body)) => Response::raw(&body, &server),
+            Err(e) => e,
+        };
+        if reply_rank(&line) == 0 {
+            return line;
+        }
+        reply = chain_reply(reply, line);
+    }
+    reply.unwrap()
+}
+
+// Look up several keys in a map, using the batch endpoint of the server.
+// Returns one reply line per key.
+//
+// Servers that do not have the batch endpoint return a 404 or 405,
+// in that case we fall back to looking up every key separately.
+async fn lookup_map_batch(ctx: &Context, domain: &Domain, map: &str, param: &str, args: &[&str]) -> Vec<String> {
+    if args.len() == 1 {
+        return vec![lookup_map(ctx, domain, map, param, args[0]).await];
+    }
+    let path = format!("/{}/map/{}/batch?cred_uid={}",
+                utf8_percent_encode(&domain.name, DEFAULT_ENCODE_SET),
+                utf8_percent_encode(map, DEFAULT_ENCODE_SET),
+                ctx.uid);
+    let body = args.iter().map(|arg| json!({ "keyname": param, "keyvalue": arg })).collect::<Vec<_>>();
+    let body = serde_json::Value::Array(body).to_string();
+    let res = req_with_retries(ctx, domain, &path, Some((APPL_JSON, body)), 0).await;
+    let line = match res.and_then(|body| Response::transform_batch(&body, args.len())) {
+        Ok(lines) => return lines,
+        Err(line) => line,
+    };
+    match reply_code(&line) {
+        404 | 405 => {
+            debug!("domain {}: no batch lookups ({}), looking up keys one by one", domain.name, line);
+            let lookups = args.iter().map(|arg| lookup_map(ctx, domain, map, param, arg));
+            future::join_all(lookups).await
+        },
+        _ => vec![line; args.len()],
+    }
+}
+
+// Turn the result of a request into a reply line.
+fn reply_line(res: Result<Bytes, String>) -> String {
+    match res {
+        Ok(body) => Response::transform(&body),
+        Err(e) => e,
+    }
 }
 
 // build a hyper::Uri from a host and a path.
@@ -134,158 +490,286 @@ fn build_uri(host: &str, path: &str) -> hyper::Uri {
     url.parse::<hyper::Uri>().unwrap()
 }
 
-// build a new hyper::Client.
-fn new_client(config: &crate::config::Config) -> hyper::Client<HttpsConnector<HttpConnector>> {
+// build the https connector, using the system TLS library.
+#[cfg(not(feature = "rustls"))]
+fn https_connector() -> io::Result<HttpsConnector> {
+    Ok(hyper_tls::HttpsConnector::new())
+}
+
+// build the https connector, using rustls and the system root certificates.
+#[cfg(feature = "rustls")]
+fn https_connector() -> io::Result<HttpsConnector> {
+    Ok(hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()?
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build())
+}
+
+// build a new hyper client.
+fn new_client(config: &Config) -> io::Result<Client> {
     let http2_only = config.http2_only.unwrap_or(false);
-    let https = HttpsConnector::new(4).unwrap();
-    hyper::Client::builder()
-                .http2_only(http2_only)
-                .keep_alive(true)
-                .keep_alive_timeout(Duration::new(30, 0))
-                .build::<_, hyper::Body>(https)
+    let https = https_connector()?;
+    let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+        .http2_only(http2_only)
+        .pool_idle_timeout(Duration::new(30, 0))
+        .build(https);
+    Ok(client)
 }
 
-// This function can call itself recursively to keep on
-// generating futures so as to retry.
+// Send one request to one server, and read the response body.
 //
-// On errors (except 404) we cycle to the next server.
+// Errors are returned as an error line ("550 GET error").
+async fn request_once(client: &Client, request: hyper::Request<Full<Bytes>>) -> Result<Bytes, String> {
+    let res = client.request(request).await.map_err(|e| {
+        // something went very wrong. mark it with code 550 so that
+        // the caller can detect it and retry.
+        //
+        // FIXME differ between real problems where we need to throw away the
+        // hyper client and problems where we just need to switch to the next server.
+        debug!("client: got error, need retry: {}", e);
+        Response::error(550, &format!("GET error: {}", e))
+    })?;
+
+    // see if response is what we expected
+    let is_json = res.headers().get(header::CONTENT_TYPE).map(|h| h == "application/json").unwrap_or(false);
+    if !is_json {
+        if res.status().is_success() {
+            return Err(Response::error(416, "expected application/json"));
+        } else {
+            let code = res.status().as_u16() as i64;
+            return Err(Response::error(code, "HTTP error"));
+        }
+    }
+
+    let body = res.into_body().collect().await.map_err(|_| Response::error(400, "GET body error"))?;
+    Ok(body.to_bytes())
+}
+
+// Get the current http client and its sequence number,
+// creating a new one if needed.
+fn get_client(ctx: &Context) -> Result<(Client, usize), String> {
+    let mut guard = ctx.http_client.lock().unwrap();
+    let http_client = &mut *guard;
+    if http_client.client.is_none() {
+        // create a new http client.
+        match new_client(&ctx.config) {
+            Ok(c) => http_client.client = Some(c),
+            Err(e) => return Err(Response::error(500, &format!("cannot create http client: {}", e))),
+        }
+        http_client.seqno += 1;
+    }
+    let cc = http_client.client.as_ref().unwrap().clone();
+    Ok((cc, http_client.seqno))
+}
+
+// Build a request.
 //
-// If there is a serious error from hyper::Client that we do not reckognize,
-// we throw away the current hyper::Client instance and create a new one.
+// If there is a body, it is a POST request. The body is a
+// tuple of (content-type, data).
+fn build_request(server: &str, path: &str, authorization: &str, body: Option<&(&str, String)>) -> hyper::Request<Full<Bytes>> {
+    let uri = build_uri(server, path);
+    let method = if body.is_some() { Method::POST } else { Method::GET };
+    let mut builder = hyper::Request::builder()
+        .uri(uri)
+        .method(method)
+        .header(header::AUTHORIZATION, authorization);
+    if let Some((content_type, _)) = body {
+        builder = builder.header(header::CONTENT_TYPE, *content_type);
+    }
+    builder
+        .body(Full::new(Bytes::from(body.map(|b| b.1.clone()).unwrap_or_default())))
+        .unwrap()
+}
+
+// Send a request to the best server in the pool, and retry on errors.
 //
-// This guards against bugs in hyper::Client or its dependencies
-// that can get a hyper::Client stuck, see:
+// On errors (except 401, 403 and 404) the server is reported as failing
+// to the pool, and we retry, probably on another server.
 //
-// https://github.com/hyperium/hyper/issues/1422
-// https://github.com/rust-lang/rust/issues/47955
+// If there is a serious error from the hyper client that we do not reckognize,
+// we throw away the current hyper client instance and create a new one.
+// This guards against a client getting stuck.
 //
-fn req_with_retries(ctx: &Context, path: String, authorization: String, body: Option<String>, try_no: u32) -> Box<Future<Item=String, Error=io::Error> + Send> {
-
-    let ctx_clone = ctx.clone();
+// Returns the body of the response, or an error line.
+async fn req_with_retries(ctx: &Context, domain: &Domain, path: &str, body: Option<(&str, String)>, try_no: u32) -> Result<Bytes, String> {
+    req_with_server(ctx, domain, path, body, try_no).await.map(|(_, body)| body)
+}
 
-    let (client, seqno) = {
-        let mut guard = ctx.http_client.lock().unwrap();
-        let http_client = &mut *guard;
-        if http_client.client.is_none() {
-            // create a new http client.
-            http_client.client.get_or_insert_with(|| new_client(&ctx.config));
-            http_client.seqno += 1;
+// Like req_with_retries, but also returns the name of the server that answered.
+async fn req_with_server(ctx: &Context, domain: &Domain, path: &str, body: Option<(&str, String)>, mut try_no: u32) -> Result<(String, Bytes), String> {
+    loop {
+        if try_no > 1 {
+            time::sleep(Duration::from_millis(RETRY_DELAY_MS)).await;
         }
-        let cc = http_client.client.as_ref().unwrap().clone();
-        (cc, http_client.seqno)
-    };
 
-    // build the uri based on the currently active webnis server.
-    let server = &ctx.config.servers[seqno % ctx.config.servers.len()];
-    let uri = build_uri(server, &path);
-    let method = if body.is_some() { Method::POST } else { Method::GET };
+        let (client, seqno) = match get_client(ctx) {
+            Ok(c) => c,
+            Err(e) => return Err(e),
+        };
 
-    let mut builder = hyper::Request::builder();
-    builder
-        .uri(uri)
-        .method(method)
-        .header(header::AUTHORIZATION, authorization.as_str());
-    if body.is_some() {
-        builder.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
-    }
-    let request = builder
-        .body(body.clone().map(|x| x.into()).unwrap_or(hyper::Body::empty()))
-        .unwrap();
-
-    let resp_body = client.request(request)
-    .map_err(|e| {
-        // something went very wrong. mark it with code 550 so that at the
-        // end of the future chain we can detect it and retry.
-        //
-        // FIXME differ between real problems where we need to throw away the
-        // hyper::Client and problems where we just need to switch to the next server.
-        debug!("client: got error, need retry: {}", e);
-        Response::error(550, &format!("GET error: {}", e))
-    })
-    .and_then(|res| {
-        // see if response is what we expected
-        let is_json = res.headers().get(header::CONTENT_TYPE).map(|h| h == "application/json").unwrap_or(false);
-        if !is_json {
-            if res.status().is_success() {
-                future::err(Response::error(416, "expected application/json"))
-            } else {
-                let code = res.status().as_u16() as i64;
-                future::err(Response::error(code, "HTTP error"))
+        // build the request for the currently preferred webnis server.
+        let server = {
+            let pool = domain.pool.lock().unwrap();
+            match pool.select() {
+                Some(idx) => pool.name(idx).to_string(),
+                None => return Err(Response::error(503, "no servers available")),
             }
-        } else {
-            future::ok(res)
+        };
+        let request = build_request(&server, path, &domain.authorization, body.as_ref());
+
+        // add a timeout. need to have an answer in 1 second.
+        let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
+        let now = Instant::now();
+        let res = match time::timeout(timeout, request_once(&client, request)).await {
+            Ok(res) => res,
+            Err(_) => Err(Response::error(408, "request timeout")),
+        };
+
+        let e = match res {
+            Ok(body) => {
+                domain.pool.lock().unwrap().report_ok(&server, now.elapsed());
+                return Ok((server, body));
+            },
+            Err(e) => e,
+        };
+        if e.starts_with("401 ") ||
+           e.starts_with("403 ") ||
+           e.starts_with("404 ") {
+            domain.pool.lock().unwrap().report_ok(&server, now.elapsed());
+            return Err(e);
         }
-    })
-    .and_then(|res| {
-        res
-        .into_body()
-        .concat2()
-        .map_err(|_| Response::error(400, "GET body error"))
-    });
-
-    // add a timeout. need to have an answer in 1 second.
-    let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
-    let body_tmout_wrapper = resp_body.timeout(timeout).map_err(|e| {
-        debug!("got error {}", e);
-        match e.into_inner() {
-            Some(e) => e,
-            None => Response::error(408, "request timeout"),
+        domain.pool.lock().unwrap().report_error(&server);
+        if ctx.eof.load(Ordering::SeqCst) || try_no >= MAX_TRIES {
+            return Err(e);
         }
-    });
 
-    let resp =
-    body_tmout_wrapper.then(move |res| {
-        let resp_body = match res {
-            Ok(body) => body,
+        if e.starts_with("550 ") {
+            let mut guard = ctx.http_client.lock().unwrap();
+            if (*guard).seqno == seqno {
+                // only do something if noone else took action.
+                debug!("throwing away http client because of {}", e);
+                (*guard).client.take();
+            }
+        }
+
+        // and retry.
+        debug!("server {}: scheduling retry {} because of {}", server, try_no + 1, e);
+        try_no += 1;
+    }
+}
+
+/// Check the health of all servers of a domain every `interval`.
+///
+/// Every server gets a `GET /<domain>/info` request. The
+/// results are reported to the server pool.
+pub(crate) async fn health_checker(ctx: Context, domain: Arc<Domain>, interval: Duration) {
+    let path = format!("/{}/info", utf8_percent_encode(&domain.name, DEFAULT_ENCODE_SET));
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let client = match get_client(&ctx) {
+            Ok((c, _)) => c,
             Err(e) => {
-                if !e.starts_with("401 ") &&
-                   !e.starts_with("403 ") &&
-                   !e.starts_with("404 ") &&
-                   !ctx_clone.eof.load(Ordering::SeqCst) &&
-                   try_no < MAX_TRIES {
-                    {
-    				    let mut guard = ctx_clone.http_client.lock().unwrap();
-                        if (*guard).seqno == seqno {
-                            // only do something if noone else took action.
-                            debug!("invalidating server {} and scheduling retry {} because of {}",
-                                   ctx_clone.config.servers[seqno % ctx_clone.config.servers.len()], try_no + 1, e);
-                            if e.starts_with("550 ") {
-                                // throw away hyper::Client
-    				            (*guard).client.take();
-                                // reset DNS resolver state just in case.
-                                // see https://github.com/rust-lang/rust/issues/47955
-                                #[cfg(target_env = "gnu")]
-                                {
-                                    extern { fn __h_errno_location() -> *mut i32; }
-                                    unsafe { *__h_errno_location() = 0 }
-                                }
-                            } else {
-                                // just switch to next server.
-                                (*guard).seqno += 1;
-                            }
-                        } else {
-                            debug!("scheduling try {} because of {}", try_no + 1, e);
+                warn!("health check: {}", e);
+                continue;
+            },
+        };
+        let servers = domain.pool.lock().unwrap().names();
+        let checks = servers.into_iter().map(|server| {
+            let request = build_request(&server, &path, &domain.authorization, None);
+            let client = client.clone();
+            async move {
+                let now = Instant::now();
+                let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
+                let res = match time::timeout(timeout, request_once(&client, request)).await {
+                    Ok(res) => res,
+                    Err(_) => Err(Response::error(408, "request timeout")),
+                };
+                (server, now.elapsed(), res)
+            }
+        }).collect::<Vec<_>>();
+        for (server, elapsed, res) in future::join_all(checks).await {
+            let mut pool = domain.pool.lock().unwrap();
+            match res {
+                Ok(_) => pool.report_ok(&server, elapsed),
+                Err(e) => {
+                    debug!("health check: server {}: {}", server, e);
+                    pool.report_error(&server);
+                },
+            }
+        }
+    }
+}
+
+/// Follow the map changes of a domain, and expire the cache entries
+/// of the maps that changed.
+///
+/// This is a long-poll loop: `GET /<domain>/changes` returns as soon as
+/// a map changes after the generation we saw last, or after a timeout.
+/// If the server does not know about changes (404), we give up.
+pub(crate) async fn change_watcher(ctx: Context, domain: Arc<Domain>) {
+    let mut seen: Option<(u64, u64)> = None;
+    loop {
+        let mut path = format!("/{}/changes?timeout={}", utf8_percent_encode(&domain.name, DEFAULT_ENCODE_SET), CHANGES_TIMEOUT);
+        if let Some((epoch, generation)) = seen {
+            path.push_str(&format!("&epoch={}&since={}", epoch, generation));
+        }
+        let res = match get_client(&ctx) {
+            Ok((client, _)) => {
+                let server = {
+                    let pool = domain.pool.lock().unwrap();
+                    pool.select().map(|idx| pool.name(idx).to_string())
+                };
+                match server {
+                    Some(server) => {
+                        let request = build_request(&server, &path, &domain.authorization, None);
+                        let timeout = Duration::from_secs(CHANGES_TIMEOUT) + Duration::from_millis(REQUEST_TIMEOUT_MS);
+                        match time::timeout(timeout, request_once(&client, request)).await {
+                            Ok(res) => res,
+                            Err(_) => Err(Response::error(408, "request timeout")),
                         }
-                    }
-					// and retry.
-                    return req_with_retries(&ctx_clone, path, authorization, body, try_no + 1);
-                } else {
-                    return Box::new(future::ok(e));
+                    },
+                    None => Err(Response::error(503, "no servers available")),
                 }
             },
+            Err(e) => Err(e),
         };
-        Box::new(future::ok(Response::transform(resp_body)))
-    });
+        let res = res.and_then(|body| {
+            serde_json::from_slice::<ChangesResponse>(&body)
+                .map(|r| r.result)
+                .map_err(|_| Response::error(500, "changes: unexpected reply"))
+        });
 
-    if try_no > 1 {
-        let when = Instant::now() + Duration::from_millis(RETRY_DELAY_MS);
-        Box::new(Delay::new(when).then(move |_| resp))
-    } else {
-        Box::new(resp)
+        let changes = match res {
+            Ok(changes) => changes,
+            Err(e) => {
+                if reply_code(&e) == 404 {
+                    info!("domain {}: server does not support change notification, not watching", domain.name);
+                    return;
+                }
+                debug!("domain {}: changes: {}", domain.name, e);
+                time::sleep(Duration::from_secs(CHANGES_RETRY_DELAY)).await;
+                continue;
+            },
+        };
+        if seen.is_some() && changes.maps.len() > 0 {
+            debug!("domain {}: maps changed: {:?}", domain.name, changes.maps);
+            if let Some(ref cache) = ctx.cache {
+                let mut cache = cache.lock().unwrap();
+                for cmd in [Cmd::GetPwNam, Cmd::GetPwUid, Cmd::GetGrNam, Cmd::GetGrGid, Cmd::GetGidList].iter() {
+                    if changes.maps.iter().any(|m| m == map_param(*cmd).0) {
+                        cache.expire(*cmd);
+                    }
+                }
+            }
+        }
+        seen = Some((changes.epoch, changes.generation));
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) enum Cmd {
     Auth,
     GetPwNam,
@@ -294,6 +778,8 @@ pub(crate) enum Cmd {
     GetGrGid,
     GetGidList,
     Servers,
+    Map,
+    List,
 }
 
 // over-engineered way to lowercase a string without allocating.
@@ -318,8 +804,14 @@ impl<'a> Request<'a> {
         let mut buf = [0u8; 16];
 	    let c = match parts.next() {
 		    None => return Err("NO".to_owned()),
-            Some(c) => tolower(c, &mut buf),
+            Some(c) => c,
+        };
+        // command can be in the form "cmd@domain".
+        let (c, domain) = match c.find('@') {
+            Some(idx) => (&c[..idx], Some(&c[idx+1..])),
+            None => (c, None),
         };
+        let c = tolower(c, &mut buf);
         let args = parts.collect::<Vec<_>>();
         let (cmd, argsmin, argsmax) = match c {
             "auth" => (Cmd::Auth, 2, 4),
@@ -328,7 +820,9 @@ impl<'a> Request<'a> {
             "getgrnam" => (Cmd::GetGrNam, 1, 1),
             "getgrgid" => (Cmd::GetGrGid, 1, 1),
             "getgidlist" => (Cmd::GetGidList, 1, 1),
-            "servers" => (Cmd::GetGidList, 0, 0),
+            "servers" => (Cmd::Servers, 0, 0),
+            "map" => (Cmd::Map, 3, 3),
+            "list" => (Cmd::List, 1, 1),
             _ => return Err(format!("unknown command {}", c)),
         };
         if args.len() < argsmin || args.len() > argsmax {
@@ -346,7 +840,39 @@ impl<'a> Request<'a> {
         } else {
             0
         };
-        Ok(Request{ cmd: cmd, args: args, arg0: arg0 })
+        Ok(Request{ cmd: cmd, args: args, arg0: arg0, domain: domain })
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replies(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn t_auth_done() {
+        let db = OfflineDb::in_memory();
+        db.auth_ok("truus", "old");
+
+        // all servers down: the stored password is accepted.
+        let line = auth_done(Some(&db), "truus", "old", replies(&["503 Service Unavailable", "408 Timeout"]));
+        assert_eq!(line, "200 OK");
+
+        // one domain rejects the password while the other is down:
+        // that is a rejection, and the stored password is forgotten.
+        let line = auth_done(Some(&db), "truus", "old", replies(&["401 Unauthorized", "503 Service Unavailable"]));
+        assert_eq!(line, "401 Unauthorized");
+        assert!(!db.auth_verify("truus", "old"));
+        let line = auth_done(Some(&db), "truus", "old", replies(&["503 Service Unavailable"]));
+        assert_eq!(line, "503 Service Unavailable");
+
+        // the next domain accepts.
+        let line = auth_done(Some(&db), "truus", "new", replies(&["401 Unauthorized", "200 OK"]));
+        assert_eq!(line, "200 OK");
+        assert!(db.auth_verify("truus", "new"));
+    }
+}
@@ -1,7 +1,4 @@
-use std::error::Error;
-
 use serde_json;
-use hyper;
 use libc::{uid_t,gid_t};
 
 #[derive(Serialize,Deserialize)]
@@ -11,6 +8,25 @@ pub enum Response<'a> {
     Error { error: ResponseError },
 }
 
+// reply to a batch request: an array of results and errors.
+#[derive(Deserialize)]
+struct BatchResponse {
+    result:     Vec<serde_json::Value>,
+}
+
+// reply to a changes request.
+#[derive(Deserialize)]
+pub struct ChangesResponse {
+    pub result: Changes,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Changes {
+    pub epoch:      u64,
+    pub generation: u64,
+    pub maps:       Vec<String>,
+}
+
 #[derive(Serialize,Deserialize)]
 pub struct ResponseError {
     code:       i64,
@@ -58,10 +74,13 @@ pub struct Auth {}
 
 impl<'a> Response<'a> {
 
-    pub fn transform(s: hyper::Chunk) -> String {
-        let data = match serde_json::from_slice::<Response>(&s) {
+    pub fn transform(s: &[u8]) -> String {
+        let data = match serde_json::from_slice::<Response>(s) {
             Ok(resp) => resp,
-            Err(e) => return Response::error(400, e.description()),
+            Err(e) => {
+                debug!("transform: {}", e);
+                return Response::error(400, "JSON error");
+            },
         };
         let result = match data {
             Response::Success{ result } => result,
@@ -76,6 +95,27 @@ impl<'a> Response<'a> {
         line
     }
 
+    /// Transform the reply to a batch request into `n` reply lines.
+    /// If the reply is an error, returns the error line.
+    pub fn transform_batch(s: &[u8], n: usize) -> Result<Vec<String>, String> {
+        let batch = match serde_json::from_slice::<BatchResponse>(s) {
+            Ok(b) => b,
+            Err(_) => {
+                let line = Response::transform(s);
+                if line.starts_with("2") {
+                    return Err(Response::error(400, "JSON error"));
+                }
+                return Err(line);
+            },
+        };
+        if batch.result.len() != n {
+            debug!("transform_batch: expected {} results, got {}", n, batch.result.len());
+            return Err(Response::error(400, "JSON error"));
+        }
+        let lines = batch.result.iter().map(|item| Response::transform(item.to_string().as_bytes())).collect();
+        Ok(lines)
+    }
+
     #[allow(unused)]
     pub fn serialize(&self) -> String {
         match serde_json::to_string(&*self) {
@@ -97,6 +137,26 @@ impl<'a> Response<'a> {
         }).unwrap()
     }
 
+    /// The result of a request as-is, with the server that answered.
+    pub fn raw(s: &[u8], server: &str) -> String {
+        let data = match serde_json::from_slice::<serde_json::Value>(s) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("raw: {}", e);
+                return Response::error(400, "JSON error");
+            },
+        };
+        if let Some(error) = data.get("error") {
+            let code = error.get("code").and_then(|c| c.as_i64()).unwrap_or(500);
+            let message = error.get("message").and_then(|m| m.as_str()).unwrap_or("error");
+            return Response::error(code, message);
+        }
+        match data.get("result") {
+            Some(result) => format!("200 {}", json!({ "server": server, "result": result })),
+            None => Response::error(400, "JSON error"),
+        }
+    }
+
     pub fn error(code: i64, message: &str) -> String {
         format!("{} {}", code, message)
     }
@@ -129,3 +189,22 @@ impl Auth {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_transform_batch() {
+        let body = br#"{"result":[
+            {"result":{"group":"wheel","passwd":"x","gid":10,"members":["mikevs","root"]}},
+            {"error":{"code":404,"message":"No such key in map"}}
+        ]}"#;
+        let lines = Response::transform_batch(body, 2).unwrap();
+        assert_eq!(lines, vec!["200 wheel:x:10:mikevs,root", "404 No such key in map"]);
+        assert_eq!(Response::transform_batch(body, 3).unwrap_err(), "400 JSON error");
+
+        let body = br#"{"error":{"code":404,"message":"Not found"}}"#;
+        assert_eq!(Response::transform_batch(body, 2).unwrap_err(), "404 Not found");
+    }
+}
@@ -0,0 +1,237 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use rand::Rng;
+use tokio::time;
+
+use crate::config;
+use crate::domain::Domain;
+
+// bounds for the refresh interval, whatever the TTL says.
+const MIN_REFRESH: u64 = 30;
+const MAX_REFRESH: u64 = 3600;
+
+/// One SRV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority:   u16,
+    pub weight:     u16,
+    pub port:       u16,
+    pub target:     String,
+}
+
+type LookupFuture<'a> = Pin<Box<dyn Future<Output=io::Result<(Vec<SrvRecord>, Instant)>> + Send + 'a>>;
+
+/// Something that can look up SRV records. Returns the records
+/// and the time until which they are valid.
+pub trait SrvResolver: Send + Sync {
+    fn lookup_srv<'a>(&'a self, name: &'a str) -> LookupFuture<'a>;
+}
+
+/// SRV resolver that uses DNS.
+pub struct DnsResolver {
+    resolver:   TokioAsyncResolver,
+}
+
+impl DnsResolver {
+    /// Uses the system resolver configuration, unless `srv_nameserver` is set.
+    pub fn new(config: &config::Domain) -> io::Result<DnsResolver> {
+        let resolver = match config.srv_nameserver {
+            Some(ref ns) => {
+                let sa = ns.parse::<SocketAddr>()
+                    .or_else(|_| format!("{}:53", ns).parse::<SocketAddr>())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: bad address", ns)))?;
+                let group = NameServerConfigGroup::from_ips_clear(&[sa.ip()], sa.port(), true);
+                let rc = ResolverConfig::from_parts(None, Vec::new(), group);
+                TokioAsyncResolver::tokio(rc, ResolverOpts::default())
+            },
+            None => {
+                TokioAsyncResolver::tokio_from_system_conf()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            },
+        };
+        Ok(DnsResolver{ resolver })
+    }
+}
+
+impl SrvResolver for DnsResolver {
+    fn lookup_srv<'a>(&'a self, name: &'a str) -> LookupFuture<'a> {
+        Box::pin(async move {
+            let lookup = self.resolver.srv_lookup(name).await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let records = lookup.iter().map(|srv| SrvRecord {
+                priority:   srv.priority(),
+                weight:     srv.weight(),
+                port:       srv.port(),
+                target:     srv.target().to_utf8(),
+            }).collect();
+            Ok((records, lookup.as_lookup().valid_until()))
+        })
+    }
+}
+
+/// Order SRV records as described in RFC 2782: by priority, and within
+/// the same priority a weighted random order. Returns a list of
+/// ("target:port", priority, weight). A target of "." means "no service".
+pub fn order_records<R: Rng>(mut records: Vec<SrvRecord>, rng: &mut R) -> Vec<(String, u16, u16)> {
+    records.retain(|r| r.target != "." && r.target != "");
+    records.sort_by_key(|r| r.priority);
+
+    let mut result = Vec::new();
+    while !records.is_empty() {
+        let prio = records[0].priority;
+        let end = records.iter().position(|r| r.priority != prio).unwrap_or(records.len());
+        let mut group = records.drain(..end).collect::<Vec<_>>();
+
+        // zero-weight records first, so that they have a very small chance of being picked.
+        group.sort_by_key(|r| r.weight);
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|r| r.weight as u32).sum();
+            let pick = rng.gen_range(0 ..= total);
+            let mut sum = 0;
+            let mut idx = group.len() - 1;
+            for (i, r) in group.iter().enumerate() {
+                sum += r.weight as u32;
+                if sum >= pick {
+                    idx = i;
+                    break;
+                }
+            }
+            let r = group.remove(idx);
+            let target = r.target.trim_end_matches('.');
+            result.push((format!("{}:{}", target, r.port), prio, r.weight));
+        }
+    }
+    result
+}
+
+/// Look up the servers once. Returns the ordered server list and
+/// when to refresh it. On error, or if the answer is empty, the
+/// static `servers` list of the domain is used.
+pub async fn lookup(domain: &Domain, resolver: &dyn SrvResolver, name: &str) -> (Vec<(String, u16, u16)>, Duration) {
+    let (servers, refresh): (Vec<_>, _) = match resolver.lookup_srv(name).await {
+        Ok((records, valid_until)) => {
+            let servers = order_records(records, &mut rand::thread_rng());
+            let ttl = valid_until.saturating_duration_since(Instant::now()).as_secs();
+            (servers, ttl)
+        },
+        Err(e) => {
+            warn!("srv: {}: {}", name, e);
+            (Vec::new(), MIN_REFRESH)
+        },
+    };
+    let refresh = Duration::from_secs(std::cmp::min(std::cmp::max(refresh, MIN_REFRESH), MAX_REFRESH));
+    if servers.is_empty() {
+        debug!("srv: {}: no servers found, using static list", name);
+        let servers = domain.config.servers.iter().map(|s| (s.to_string(), 0, 0)).collect();
+        return (servers, refresh);
+    }
+    (servers, refresh)
+}
+
+/// Refresh the server pool from the SRV records every time the TTL expires.
+pub async fn refresher(domain: Arc<Domain>, resolver: Box<dyn SrvResolver>, name: String, mut refresh: Duration) {
+    loop {
+        time::sleep(refresh).await;
+        let (servers, r) = lookup(&domain, &*resolver, &name).await;
+        debug!("srv: {}: {:?}, refresh in {:?}", name, servers, r);
+        domain.pool.lock().unwrap().set_servers(servers);
+        refresh = r;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn rec(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord { priority, weight, port: 2884, target: target.to_string() }
+    }
+
+    // stub resolver that always returns the same records.
+    struct StubResolver(Vec<SrvRecord>);
+
+    impl SrvResolver for StubResolver {
+        fn lookup_srv<'a>(&'a self, _name: &'a str) -> LookupFuture<'a> {
+            let records = self.0.clone();
+            Box::pin(async move { Ok((records, Instant::now() + Duration::from_secs(300))) })
+        }
+    }
+
+    #[test]
+    fn t_order() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let records = vec![
+            rec(20, 0, "c.example.com."),
+            rec(10, 0, "a.example.com."),
+            rec(10, 100, "b.example.com."),
+            rec(30, 0, "."),
+        ];
+        let r = order_records(records, &mut rng);
+        assert_eq!(r.len(), 3);
+        assert_eq!(r[2], ("c.example.com:2884".to_string(), 20, 0));
+        assert_eq!(r[0].1, 10);
+
+        // weight 100 vs weight 0 - "b" should nearly always come first.
+        let mut first_b = 0;
+        for _ in 0 .. 100 {
+            let records = vec![rec(10, 0, "a."), rec(10, 100, "b.")];
+            if order_records(records, &mut rng)[0].0 == "b:2884" {
+                first_b += 1;
+            }
+        }
+        assert!(first_b > 90);
+    }
+
+    #[test]
+    fn t_stub_resolver() {
+        let resolver = StubResolver(vec![rec(10, 1, "x.example.com.")]);
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (records, _) = rt.block_on(resolver.lookup_srv("_webnis._tcp.example.com")).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(order_records(records, &mut rng), vec![("x.example.com:2884".to_string(), 10, 1)]);
+    }
+
+    // resolver that fails.
+    struct FailResolver;
+
+    impl SrvResolver for FailResolver {
+        fn lookup_srv<'a>(&'a self, _name: &'a str) -> LookupFuture<'a> {
+            Box::pin(async move { Err(io::Error::new(io::ErrorKind::Other, "SERVFAIL")) })
+        }
+    }
+
+    #[test]
+    fn t_lookup() {
+        let config: config::Config = toml::from_str("").unwrap();
+        let dconfig = config::Domain {
+            name:       "d".to_string(),
+            servers:    vec!["static.example.com:2884".to_string()],
+            ..config::Domain::default()
+        };
+        let domain = Domain::new(&config, &dconfig);
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let name = "_webnis._tcp.example.com";
+        let fallback = vec![("static.example.com:2884".to_string(), 0, 0)];
+
+        let (servers, refresh) = rt.block_on(lookup(&domain, &StubResolver(vec![rec(10, 5, "x.example.com.")]), name));
+        assert_eq!(servers, vec![("x.example.com:2884".to_string(), 10, 5)]);
+        // the TTL of the records.
+        assert!(refresh > Duration::from_secs(290) && refresh <= Duration::from_secs(300));
+
+        // no records, or an error: the static list, and retry soon.
+        let (servers, _) = rt.block_on(lookup(&domain, &StubResolver(vec![rec(10, 0, ".")]), name));
+        assert_eq!(servers, fallback);
+        let (servers, refresh) = rt.block_on(lookup(&domain, &FailResolver, name));
+        assert_eq!(servers, fallback);
+        assert_eq!(refresh, Duration::from_secs(MIN_REFRESH));
+    }
+}
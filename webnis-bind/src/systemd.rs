@@ -0,0 +1,43 @@
+//
+// systemd integration: socket activation, readiness notification and
+// the watchdog. See sd_listen_fds(3), sd_notify(3) and sd_watchdog_enabled(3).
+// Notification and the watchdog are in webnis-common.
+//
+// Everything here is a no-op if webnis-bind was not started by systemd.
+//
+use std::env;
+use std::io;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+pub use webnis_common::systemd::{notify, start_watchdog};
+
+// first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listening socket passed by systemd (socket activation), if any.
+///
+/// The environment variables are removed, so that they are not passed on.
+pub fn listen_fd() -> io::Result<Option<UnixListener>> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let num = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if pid != Some(std::process::id()) || num == 0 {
+        return Ok(None);
+    }
+    if num > 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("expected 1 socket, got {}", num)));
+    }
+
+    let fd = LISTEN_FDS_START;
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    // fails if this is not an AF_UNIX socket.
+    if listener.local_addr().is_err() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {}: not a unix socket", fd)));
+    }
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
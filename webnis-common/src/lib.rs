@@ -0,0 +1,6 @@
+//
+// webnis-common: code shared by webnis-server, webnis-bind and webnis-utils.
+//
+pub mod privs;
+pub mod secret;
+pub mod systemd;
@@ -0,0 +1,63 @@
+//
+// Switching to another user and group after startup.
+//
+use std::ffi::CString;
+use std::io;
+
+/// The uid and gid to switch to.
+pub struct Ids {
+    pub uid: Option<libc::uid_t>,
+    pub gid: Option<libc::gid_t>,
+}
+
+/// A string as a CString, for libc.
+pub fn cstring(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}: invalid name", s)))
+}
+
+/// The last OS error, prefixed with `what`.
+pub fn os_error(what: impl std::fmt::Display) -> io::Error {
+    let e = io::Error::last_os_error();
+    io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+/// Look up the uid and gid of `user` and `group`. If only `user` is set,
+/// the primary group of that user is used. getpwnam and getgrnam are
+/// not thread-safe, so call this before any threads are started.
+pub fn lookup(user: Option<&String>, group: Option<&String>) -> io::Result<Ids> {
+    let mut ids = Ids { uid: None, gid: None };
+    if let Some(user) = user {
+        let pw = unsafe { libc::getpwnam(cstring(user)?.as_ptr()) };
+        if pw.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("user {}: not found", user)));
+        }
+        ids.uid = Some(unsafe { (*pw).pw_uid });
+        ids.gid = Some(unsafe { (*pw).pw_gid });
+    }
+    if let Some(group) = group {
+        let gr = unsafe { libc::getgrnam(cstring(group)?.as_ptr()) };
+        if gr.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("group {}: not found", group)));
+        }
+        ids.gid = Some(unsafe { (*gr).gr_gid });
+    }
+    Ok(ids)
+}
+
+/// Switch to the uid and gid. The glibc wrappers apply this to all threads.
+pub fn switch(ids: &Ids) -> io::Result<()> {
+    if let Some(gid) = ids.gid {
+        if unsafe { libc::setgroups(1, &gid) } < 0 {
+            return Err(os_error("setgroups"));
+        }
+        if unsafe { libc::setgid(gid) } < 0 {
+            return Err(os_error(format!("setgid {}", gid)));
+        }
+    }
+    if let Some(uid) = ids.uid {
+        if unsafe { libc::setuid(uid) } < 0 {
+            return Err(os_error(format!("setuid {}", uid)));
+        }
+    }
+    Ok(())
+}
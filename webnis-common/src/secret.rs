@@ -0,0 +1,61 @@
+//
+// Secrets in configuration files: ${NAME} expansion, and reading the
+// value from a file.
+//
+use std::fs;
+
+/// Expand ${NAME} environment variables in a value.
+pub fn expand_env(value: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(idx) = rest.find("${") {
+        out.push_str(&rest[..idx]);
+        let end = match rest[idx..].find('}') {
+            Some(end) => idx + end,
+            None => return Err(format!("{:?}: missing '}}'", value)),
+        };
+        let name = &rest[idx + 2..end];
+        let val = std::env::var(name).map_err(|_| format!("environment variable {} not set", name))?;
+        out.push_str(&val);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// A secret can be read from a file: "file:/path" or "@/path". The
+/// trailing newline is removed. ${NAME} is expanded first, so that
+/// "@${CREDENTIALS_DIRECTORY}/token" works.
+pub fn secret(value: &str) -> Result<String, String> {
+    let value = expand_env(value)?;
+    match value.strip_prefix("file:").or_else(|| value.strip_prefix('@')) {
+        Some(path) => {
+            let data = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(data.trim_end_matches(['\n', '\r']).to_string())
+        },
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_secret() {
+        let dir = std::env::temp_dir();
+        let file = dir.join(format!("webnis-secret-{}", std::process::id()));
+        fs::write(&file, "s3cret\n").unwrap();
+        std::env::set_var("WEBNIS_T_SECRET_DIR", &dir);
+
+        assert_eq!(expand_env("a${WEBNIS_T_SECRET_DIR}b").unwrap(), format!("a{}b", dir.display()));
+        assert!(expand_env("${WEBNIS_T_SECRET_UNSET}").is_err());
+        assert!(expand_env("${WEBNIS_T_SECRET_DIR").is_err());
+        assert_eq!(secret("plain").unwrap(), "plain");
+        let name = file.file_name().unwrap().to_str().unwrap();
+        assert_eq!(secret(&format!("@${{WEBNIS_T_SECRET_DIR}}/{}", name)).unwrap(), "s3cret");
+        assert_eq!(secret(&format!("file:{}", file.display())).unwrap(), "s3cret");
+
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+//
+// systemd integration: readiness notification and the watchdog.
+// See sd_notify(3) and sd_watchdog_enabled(3). Socket activation is
+// done by the daemons themselves, they need different socket types.
+//
+// Everything here is a no-op if the daemon was not started by systemd.
+//
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
+
+/// Send a state change ("READY=1", "STOPPING=1") to systemd.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = send_notify(path.as_bytes(), state) {
+        log::warn!("sd_notify: {:?}: {}", path, e);
+    }
+}
+
+/// Tell systemd that we are reloading. It wants to know when
+/// that started, in CLOCK_MONOTONIC microseconds.
+pub fn notify_reloading() {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    let usec = ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000;
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+}
+
+fn send_notify(path: &[u8], state: &str) -> io::Result<()> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid socket path"));
+    }
+    for (i, b) in path.iter().enumerate() {
+        addr.sun_path[i] = *b as libc::c_char;
+    }
+    // "@name" is a socket in the abstract namespace.
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let res = unsafe {
+        libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if res < 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+// The watchdog interval, if `WatchdogSec` is set for this process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// If the watchdog is enabled, ping it at half the interval. This runs
+/// as a task on the runtime, so a stuck runtime is noticed.
+pub fn start_watchdog() {
+    if let Some(interval) = watchdog_interval() {
+        log::info!("systemd watchdog enabled, interval {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval / 2);
+            loop {
+                ticker.tick().await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
@@ -0,0 +1,187 @@
+//
+// LDIF exports, with posixAccount and posixGroup entries (RFC 2307).
+//
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use crate::source::{Db, Group, Passwd, Shadow};
+
+// One entry: attribute names (lowercased) and their values.
+type Entry = HashMap<String, Vec<String>>;
+
+fn invalid(file: &str, lineno: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", file, lineno, msg))
+}
+
+// Parse the LDIF data into entries.
+fn parse(file: &str, data: &str) -> io::Result<Vec<Entry>> {
+    // unfold continuation lines first, remembering the line numbers.
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        if let Some(rest) = line.strip_prefix(' ') {
+            match lines.last_mut() {
+                Some((_, l)) => l.push_str(rest),
+                None => return Err(invalid(file, n + 1, "continuation line at start of file")),
+            }
+        } else {
+            lines.push((n + 1, line.to_string()));
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut entry = Entry::new();
+    for (n, line) in lines.into_iter() {
+        if line.trim() == "" {
+            if entry.len() > 0 {
+                entries.push(std::mem::take(&mut entry));
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut kv = line.splitn(2, ':');
+        let attr = kv.next().unwrap();
+        let value = match kv.next() {
+            Some(v) => v,
+            None => return Err(invalid(file, n, "missing ':'")),
+        };
+        // strip attribute options, like ";lang-en" or ";binary".
+        let attr = attr.split(';').next().unwrap().to_lowercase();
+        let value = if let Some(b64) = value.strip_prefix(':') {
+            base64::decode(b64.trim())
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+                .ok_or_else(|| invalid(file, n, "invalid base64 value"))?
+        } else if value.starts_with('<') {
+            return Err(invalid(file, n, "URL values are not supported"));
+        } else {
+            value.trim_start().to_string()
+        };
+        if attr == "version" && entry.len() == 0 {
+            continue;
+        }
+        entry.entry(attr).or_default().push(value);
+    }
+    if entry.len() > 0 {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn has_class(entry: &Entry, class: &str) -> bool {
+    match entry.get("objectclass") {
+        Some(v) => v.iter().any(|c| c.eq_ignore_ascii_case(class)),
+        None => false,
+    }
+}
+
+fn first<'a>(entry: &'a Entry, attr: &str) -> Option<&'a str> {
+    entry.get(attr).and_then(|v| v.first()).map(|s| s.as_str())
+}
+
+// A required attribute.
+fn attr<'a>(file: &str, entry: &'a Entry, attr: &str) -> io::Result<&'a str> {
+    first(entry, attr).ok_or_else(|| {
+        let dn = first(entry, "dn").unwrap_or("?");
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}: missing {}", file, dn, attr))
+    })
+}
+
+fn number(file: &str, entry: &Entry, name: &str) -> io::Result<u32> {
+    attr(file, entry, name)?.parse::<u32>().map_err(|_| {
+        let dn = first(entry, "dn").unwrap_or("?");
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}: {} is not a number", file, dn, name))
+    })
+}
+
+// userPassword: only {CRYPT} hashes can be used.
+fn crypt_password(entry: &Entry) -> Option<String> {
+    let pw = entry.get("userpassword")?;
+    pw.iter().find_map(|p| {
+        if p.len() > 7 && p[..7].eq_ignore_ascii_case("{crypt}") {
+            Some(p[7..].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+impl Db {
+    /// Read the posixAccount and posixGroup entries from an LDIF file.
+    /// Password hashes (`userPassword: {CRYPT}...`) go into the shadow list.
+    pub fn read_ldif(&mut self, file: &str) -> io::Result<()> {
+        let data = fs::read_to_string(file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+        for entry in parse(file, &data)? {
+            if has_class(&entry, "posixAccount") {
+                let username = attr(file, &entry, "uid")?.to_string();
+                let gecos = first(&entry, "gecos").or_else(|| first(&entry, "cn")).unwrap_or("");
+                self.passwd.push(Passwd {
+                    username: username.clone(),
+                    passwd:   "x".to_string(),
+                    uid:      number(file, &entry, "uidnumber")?,
+                    gid:      number(file, &entry, "gidnumber")?,
+                    gecos:    gecos.to_string(),
+                    home:     attr(file, &entry, "homedirectory")?.to_string(),
+                    shell:    first(&entry, "loginshell").unwrap_or("").to_string(),
+                });
+                if let Some(passwd) = crypt_password(&entry) {
+                    self.shadow.push(Shadow { username, passwd });
+                }
+            }
+            if has_class(&entry, "posixGroup") {
+                self.group.push(Group {
+                    group:   attr(file, &entry, "cn")?.to_string(),
+                    passwd:  "x".to_string(),
+                    gid:     number(file, &entry, "gidnumber")?,
+                    members: entry.get("memberuid").cloned().unwrap_or(Vec::new()),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_ldif() {
+        let data = "version: 1\n\
+            \n\
+            # a user\n\
+            dn: uid=mikevs,ou=People,dc=example,dc=com\n\
+            objectClass: top\n\
+            objectClass: posixAccount\n\
+            uid: mikevs\n\
+            cn: Mike\n\
+            uidNumber: 1000\n\
+            gidNumber: 100\n\
+            homeDirectory: /home/\n\x20mikevs\n\
+            userPassword: {CRYPT}$1$salt$hash\n\
+            \n\
+            dn: cn=wheel,ou=Group,dc=example,dc=com\n\
+            objectclass: posixGroup\n\
+            cn:: d2hlZWw=\n\
+            gidNumber: 10\n\
+            memberUid: root\n\
+            memberUid: mikevs\n";
+        let dir = std::env::temp_dir().join(format!("webnis-makedb-ldif-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("export.ldif");
+        fs::write(&file, data).unwrap();
+
+        let mut db = Db::default();
+        db.read_ldif(file.to_str().unwrap()).unwrap();
+        assert_eq!(db.passwd.len(), 1);
+        assert_eq!(db.passwd[0].home, "/home/mikevs");
+        assert_eq!(db.passwd[0].gecos, "Mike");
+        assert_eq!(db.shadow[0].passwd, "$1$salt$hash");
+        assert_eq!(db.group[0].group, "wheel");
+        assert_eq!(db.group[0].members, vec!["root".to_string(), "mikevs".to_string()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+//
+// webnis-makedb: build webnis-server map files from passwd, group
+// and shadow files, or from LDIF exports.
+//
+use std::path::Path;
+
+use structopt::StructOpt;
+
+mod ldif;
+mod maps;
+mod source;
+
+use crate::maps::MapType;
+use crate::source::Db;
+
+static PROGNAME: &'static str = "webnis-makedb";
+
+macro_rules! die {
+    ($($tt:tt)*) => ({
+        eprintln!("{}: {}", PROGNAME, format!($($tt)*));
+        std::process::exit(2);
+    });
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "webnis-makedb")]
+struct Opts {
+    /// passwd file (/etc/passwd format)
+    #[structopt(short = "p", long = "passwd")]
+    passwd: Option<String>,
+
+    /// group file (/etc/group format)
+    #[structopt(short = "g", long = "group")]
+    group: Option<String>,
+
+    /// shadow file (/etc/shadow format), the hashes go into an adjunct map
+    #[structopt(short = "s", long = "shadow")]
+    shadow: Option<String>,
+
+    /// LDIF file with posixAccount and posixGroup entries
+    #[structopt(short = "l", long = "ldif", number_of_values = 1)]
+    ldif: Vec<String>,
+
+    /// output directory (the db_dir of the domain)
+    #[structopt(short = "d", long = "dir")]
+    dir: String,
+
+    /// type of the map files: gdbm or json
+    #[structopt(short = "t", long = "type", default_value = "gdbm")]
+    map_type: String,
+
+    /// write the map definitions, for include_maps, to this file
+    #[structopt(short = "m", long = "maps")]
+    maps: Option<String>,
+
+    /// do not generate a gidlist map
+    #[structopt(long = "no-gidlist")]
+    no_gidlist: bool,
+
+    /// show what would be written, but do not write anything
+    #[structopt(short = "n", long = "dry-run")]
+    dry_run: bool,
+
+    /// show the differences with the existing map files, but do not write anything.
+    /// exits with status 1 if there are differences.
+    #[structopt(long = "diff")]
+    diff: bool,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+
+    let map_type = match opts.map_type.as_str() {
+        "gdbm" => MapType::Gdbm,
+        "json" => MapType::Json,
+        t => die!("unknown map type {}", t),
+    };
+    if opts.passwd.is_none() && opts.group.is_none() && opts.shadow.is_none() && opts.ldif.len() == 0 {
+        die!("nothing to do (use --passwd, --group, --shadow or --ldif)");
+    }
+
+    // read all the sources.
+    let mut db = Db::default();
+    let res = opts
+        .passwd
+        .iter()
+        .try_for_each(|f| db.read_passwd(f))
+        .and_then(|_| opts.group.iter().try_for_each(|f| db.read_group(f)))
+        .and_then(|_| opts.shadow.iter().try_for_each(|f| db.read_shadow(f)))
+        .and_then(|_| opts.ldif.iter().try_for_each(|f| db.read_ldif(f)));
+    if let Err(e) = res {
+        die!("{}", e);
+    }
+    for w in db.check_duplicates() {
+        eprintln!("{}: warning: {}", PROGNAME, w);
+    }
+
+    let dir = Path::new(&opts.dir);
+    let gidlist = !opts.no_gidlist;
+    let files = maps::build(&db, map_type, gidlist);
+    let toml = maps::toml(&db, map_type, gidlist);
+
+    if opts.dry_run {
+        for file in &files {
+            println!("{}: {} entries", dir.join(&file.name).display(), file.entries.len());
+        }
+        print!("\n{}", toml);
+        return;
+    }
+
+    if opts.diff {
+        let mut changed = false;
+        for file in &files {
+            let diff = file.diff(dir).unwrap_or_else(|e| die!("{}", e));
+            for d in &diff {
+                println!("{}: {}", file.name, d);
+            }
+            changed |= diff.len() > 0;
+        }
+        std::process::exit(if changed { 1 } else { 0 });
+    }
+
+    for file in &files {
+        if let Err(e) = file.write(dir) {
+            die!("{}", e);
+        }
+    }
+    if let Some(ref m) = opts.maps {
+        if let Err(e) = maps::write_text(Path::new(m), &toml) {
+            die!("{}", e);
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//
+// Build the map files from the source data, write them, and compare
+// them with the map files that are already there.
+//
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::source::Db;
+
+/// Type of the map files to generate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapType {
+    Gdbm,
+    Json,
+}
+
+// A map: a name for the config, the format of the gdbm data, and the keys.
+struct MapDef {
+    name:    &'static str,
+    file:    &'static str,
+    format:  &'static str,
+    // key name, and the suffix of the gdbm file ("byname").
+    keys:    &'static [(&'static str, &'static str)],
+    // mode of new map files.
+    mode:    u32,
+    entries: Vec<(Vec<String>, String, Value)>,
+}
+
+/// One map file, with its entries as (key, data) in the file order.
+pub struct MapFile {
+    /// name relative to the output directory.
+    pub name:    String,
+    pub entries: Vec<(String, String)>,
+    map_type:    MapType,
+    // json maps: the entries, and the name of the first key.
+    values:      Vec<Value>,
+    keyname:     &'static str,
+    mode:        u32,
+}
+
+fn passwd_map(db: &Db) -> MapDef {
+    let entries = db
+        .passwd
+        .iter()
+        .map(|p| {
+            let line = format!(
+                "{}:{}:{}:{}:{}:{}:{}",
+                p.username, p.passwd, p.uid, p.gid, p.gecos, p.home, p.shell
+            );
+            let value = json!({
+                "username": p.username, "passwd": p.passwd, "uid": p.uid, "gid": p.gid,
+                "gecos": p.gecos, "home": p.home, "shell": p.shell,
+            });
+            (vec![p.username.clone(), p.uid.to_string()], line, value)
+        })
+        .collect();
+    MapDef {
+        name: "passwd",
+        file: "passwd",
+        format: "passwd",
+        keys: &[("username", "byname"), ("uid", "byuid")],
+        mode: 0o644,
+        entries,
+    }
+}
+
+fn group_map(db: &Db) -> MapDef {
+    let entries = db
+        .group
+        .iter()
+        .map(|g| {
+            let line = format!("{}:{}:{}:{}", g.group, g.passwd, g.gid, g.members.join(","));
+            let value = json!({ "group": g.group, "passwd": g.passwd, "gid": g.gid, "members": g.members });
+            (vec![g.group.clone(), g.gid.to_string()], line, value)
+        })
+        .collect();
+    MapDef {
+        name: "group",
+        file: "group",
+        format: "group",
+        keys: &[("group", "byname"), ("gid", "bygid")],
+        mode: 0o644,
+        entries,
+    }
+}
+
+fn adjunct_map(db: &Db) -> MapDef {
+    let entries = db
+        .shadow
+        .iter()
+        .map(|s| {
+            let line = format!("{}:{}", s.username, s.passwd);
+            let value = json!({ "username": s.username, "passwd": s.passwd });
+            (vec![s.username.clone()], line, value)
+        })
+        .collect();
+    MapDef {
+        name: "adjunct",
+        file: "passwd.adjunct",
+        format: "adjunct",
+        keys: &[("username", "byname")],
+        // password hashes.
+        mode: 0o640,
+        entries,
+    }
+}
+
+fn gidlist_map(db: &Db) -> MapDef {
+    let entries = db
+        .gidlist()
+        .into_iter()
+        .map(|(username, gids)| {
+            let value = json!({ "username": username, "gidlist": gids });
+            (vec![username], value.to_string(), value)
+        })
+        .collect();
+    MapDef {
+        name: "gidlist",
+        file: "gidlist",
+        format: "json",
+        keys: &[("username", "byname")],
+        mode: 0o644,
+        entries,
+    }
+}
+
+fn map_defs(db: &Db, gidlist: bool) -> Vec<MapDef> {
+    let mut defs = Vec::new();
+    if db.passwd.len() > 0 {
+        defs.push(passwd_map(db));
+    }
+    if db.group.len() > 0 {
+        defs.push(group_map(db));
+    }
+    if db.shadow.len() > 0 {
+        defs.push(adjunct_map(db));
+    }
+    if gidlist && (db.passwd.len() > 0 || db.group.len() > 0) {
+        defs.push(gidlist_map(db));
+    }
+    defs
+}
+
+/// Build the map files. Entries with a key that was already
+/// seen are left out, the first one wins.
+pub fn build(db: &Db, map_type: MapType, gidlist: bool) -> Vec<MapFile> {
+    let mut files = Vec::new();
+    for def in map_defs(db, gidlist) {
+        match map_type {
+            MapType::Gdbm => {
+                for (idx, (_, suffix)) in def.keys.iter().enumerate() {
+                    let mut seen = HashSet::new();
+                    let entries = def
+                        .entries
+                        .iter()
+                        .filter(|(keys, _, _)| seen.insert(keys[idx].clone()))
+                        .map(|(keys, line, _)| (keys[idx].clone(), line.clone()))
+                        .collect();
+                    files.push(MapFile {
+                        name: format!("{}.{}", def.file, suffix),
+                        entries,
+                        map_type,
+                        values: Vec::new(),
+                        keyname: def.keys[idx].0,
+                        mode: def.mode,
+                    });
+                }
+            },
+            MapType::Json => {
+                let mut seen = HashSet::new();
+                let mut entries = Vec::new();
+                let mut values = Vec::new();
+                for (keys, _, value) in &def.entries {
+                    if seen.insert(keys[0].clone()) {
+                        entries.push((keys[0].clone(), value.to_string()));
+                        values.push(value.clone());
+                    }
+                }
+                files.push(MapFile {
+                    name: format!("{}.json", def.file),
+                    entries,
+                    map_type,
+                    values,
+                    keyname: def.keys[0].0,
+                    mode: def.mode,
+                });
+            },
+        }
+    }
+    files
+}
+
+/// Map definitions for the generated files, to be used with `include_maps`.
+pub fn toml(db: &Db, map_type: MapType, gidlist: bool) -> String {
+    let mut s = String::from("# generated by webnis-makedb.\n");
+    for def in map_defs(db, gidlist) {
+        s.push_str(&format!("\n[{}]\n", def.name));
+        match map_type {
+            MapType::Gdbm => {
+                s.push_str(&format!("  type = \"gdbm\"\n  format = \"{}\"\n", def.format));
+                if def.keys.len() == 1 {
+                    let (key, suffix) = def.keys[0];
+                    s.push_str(&format!("  key = \"{}\"\n  file = \"{}.{}\"\n", key, def.file, suffix));
+                } else {
+                    for (key, suffix) in def.keys {
+                        s.push_str(&format!("\n  [{}.{}]\n    file = \"{}.{}\"\n", def.name, key, def.file, suffix));
+                    }
+                }
+            },
+            MapType::Json => {
+                let keys = def.keys.iter().map(|(k, _)| format!("\"{}\"", k)).collect::<Vec<_>>();
+                s.push_str(&format!("  type = \"json\"\n  keys = [ {} ]\n", keys.join(", ")));
+                s.push_str(&format!("  file = \"{}.json\"\n", def.file));
+            },
+        }
+    }
+    s
+}
+
+// Write to a temporary file next to `path`, then rename it into place.
+// The file gets the mode of the file it replaces, or `mode` if it is new.
+fn replace_file(path: &Path, mode: u32, write: impl FnOnce(&Path, u32) -> io::Result<()>) -> io::Result<()> {
+    let tmp = path.with_file_name(format!(".{}.tmp", path.file_name().unwrap().to_string_lossy()));
+    let mode = fs::metadata(path).map(|m| m.permissions().mode() & 0o7777).unwrap_or(mode);
+    let _ = fs::remove_file(&tmp);
+    let res = write(&tmp, mode)
+        .and_then(|_| fs::set_permissions(&tmp, fs::Permissions::from_mode(mode)))
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res.map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+}
+
+// Create a new file with `mode` (minus the umask) and write `data` to it.
+fn write_new(path: &Path, mode: u32, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(path)?;
+    file.write_all(data)
+}
+
+/// Write a text file (like the map definitions) atomically.
+pub fn write_text(path: &Path, data: &str) -> io::Result<()> {
+    replace_file(path, 0o644, |tmp, mode| write_new(tmp, mode, data.as_bytes()))
+}
+
+impl MapFile {
+    /// Write the map file into `dir`.
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        let path = dir.join(&self.name);
+        match self.map_type {
+            MapType::Gdbm => {
+                replace_file(&path, self.mode, |tmp, mode| {
+                    let db = gdbm::Gdbm::new(tmp, 0, gdbm::Open::NEWDB, mode as i32)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                    for (key, data) in &self.entries {
+                        db.store(key, data, false)
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                    }
+                    Ok(())
+                })
+            },
+            MapType::Json => {
+                let data = serde_json::to_string_pretty(&self.values).unwrap() + "\n";
+                replace_file(&path, self.mode, |tmp, mode| write_new(tmp, mode, data.as_bytes()))
+            },
+        }
+    }
+
+    // Read the existing file in `dir`, if there is one.
+    fn read_existing(&self, dir: &Path) -> io::Result<Option<BTreeMap<String, String>>> {
+        let path = dir.join(&self.name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut entries = BTreeMap::new();
+        match self.map_type {
+            MapType::Gdbm => {
+                let db = gdbm::Gdbm::new(&path, 0, gdbm::Open::READER, 0)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}: {}", path, e)))?;
+                let mut key = db.firstkey().ok();
+                while let Some(k) = key {
+                    if let Ok(data) = db.fetch(&k) {
+                        entries.insert(k.clone(), data);
+                    }
+                    key = db.nextkey(&k).ok();
+                }
+            },
+            MapType::Json => {
+                let data = fs::read(&path).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))?;
+                let values: Vec<Value> = serde_json::from_slice(&data).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+                })?;
+                for v in values {
+                    let key = match v.get(self.keyname) {
+                        Some(Value::String(k)) => k.to_string(),
+                        Some(k) => k.to_string(),
+                        None => continue,
+                    };
+                    entries.entry(key).or_insert_with(|| v.to_string());
+                }
+            },
+        }
+        Ok(Some(entries))
+    }
+
+    /// Compare with the existing file in `dir`. Returns a list of
+    /// "+key", "-key" and "~key" lines for added, removed and changed entries.
+    pub fn diff(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let old = self.read_existing(dir)?.unwrap_or_default();
+        let mut diff = Vec::new();
+        let new = self.entries.iter().cloned().collect::<BTreeMap<_, _>>();
+        for (key, data) in &new {
+            match old.get(key) {
+                None => diff.push(format!("+{}", key)),
+                Some(o) if o != data => diff.push(format!("~{}", key)),
+                _ => {},
+            }
+        }
+        for key in old.keys() {
+            if !new.contains_key(key) {
+                diff.push(format!("-{}", key));
+            }
+        }
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{Passwd, Shadow};
+
+    #[test]
+    fn t_mode() {
+        let dir = std::env::temp_dir().join(format!("webnis-makedb-mode-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut db = Db::default();
+        db.passwd.push(Passwd {
+            username: "mikevs".to_string(),
+            passwd:   "x".to_string(),
+            uid:      1000,
+            gid:      100,
+            gecos:    "".to_string(),
+            home:     "/home/mikevs".to_string(),
+            shell:    "/bin/sh".to_string(),
+        });
+        db.shadow.push(Shadow { username: "mikevs".to_string(), passwd: "$6$salt$hash".to_string() });
+
+        let mode = |name: &str| fs::metadata(dir.join(name)).unwrap().permissions().mode() & 0o777;
+        for file in build(&db, MapType::Json, false) {
+            file.write(&dir).unwrap();
+        }
+        assert_eq!(mode("passwd.adjunct.json"), 0o640);
+
+        // an existing file keeps its mode.
+        fs::set_permissions(dir.join("passwd.adjunct.json"), fs::Permissions::from_mode(0o600)).unwrap();
+        for file in build(&db, MapType::Json, false) {
+            file.write(&dir).unwrap();
+        }
+        assert_eq!(mode("passwd.adjunct.json"), 0o600);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+//
+// Source data: /etc/passwd, /etc/group and /etc/shadow style files.
+//
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passwd {
+    pub username:   String,
+    pub passwd:     String,
+    pub uid:        u32,
+    pub gid:        u32,
+    pub gecos:      String,
+    pub home:       String,
+    pub shell:      String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub group:      String,
+    pub passwd:     String,
+    pub gid:        u32,
+    pub members:    Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shadow {
+    pub username:   String,
+    pub passwd:     String,
+}
+
+/// All the entries read from the sources.
+#[derive(Debug, Default)]
+pub struct Db {
+    pub passwd: Vec<Passwd>,
+    pub group:  Vec<Group>,
+    pub shadow: Vec<Shadow>,
+}
+
+fn invalid(file: &str, lineno: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", file, lineno, msg))
+}
+
+// Read a colon-separated file. Empty lines, comments and NIS
+// "+" / "-" compat entries are skipped.
+fn read_lines(file: &str, min_fields: usize) -> io::Result<Vec<(usize, Vec<String>)>> {
+    let data = fs::read_to_string(file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+    let mut lines = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        if line.trim() == "" || line.starts_with('#') || line.starts_with('+') || line.starts_with('-') {
+            continue;
+        }
+        let fields = line.split(':').map(|s| s.to_string()).collect::<Vec<_>>();
+        if fields.len() < min_fields || fields[0] == "" {
+            return Err(invalid(file, n + 1, "not enough fields"));
+        }
+        lines.push((n + 1, fields));
+    }
+    Ok(lines)
+}
+
+fn number(file: &str, lineno: usize, field: &str) -> io::Result<u32> {
+    field.parse::<u32>().map_err(|_| invalid(file, lineno, &format!("{:?}: not a number", field)))
+}
+
+impl Db {
+    /// Read an /etc/passwd style file.
+    pub fn read_passwd(&mut self, file: &str) -> io::Result<()> {
+        for (n, f) in read_lines(file, 7)? {
+            self.passwd.push(Passwd {
+                username: f[0].clone(),
+                passwd:   f[1].clone(),
+                uid:      number(file, n, &f[2])?,
+                gid:      number(file, n, &f[3])?,
+                gecos:    f[4].clone(),
+                home:     f[5].clone(),
+                shell:    f[6].clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Read an /etc/group style file.
+    pub fn read_group(&mut self, file: &str) -> io::Result<()> {
+        for (n, f) in read_lines(file, 4)? {
+            self.group.push(Group {
+                group:   f[0].clone(),
+                passwd:  f[1].clone(),
+                gid:     number(file, n, &f[2])?,
+                members: f[3].split(',').filter(|m| *m != "").map(|m| m.to_string()).collect(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Read an /etc/shadow style file. Only the password hash is used.
+    pub fn read_shadow(&mut self, file: &str) -> io::Result<()> {
+        for (_, f) in read_lines(file, 2)? {
+            self.shadow.push(Shadow {
+                username: f[0].clone(),
+                passwd:   f[1].clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The list of groups of every user: the primary gid
+    /// first, then the other groups, sorted.
+    pub fn gidlist(&self) -> Vec<(String, Vec<u32>)> {
+        let mut groups: HashMap<&str, BTreeSet<u32>> = HashMap::new();
+        let mut users = Vec::new();
+        for pw in &self.passwd {
+            if !groups.contains_key(pw.username.as_str()) {
+                groups.insert(&pw.username, BTreeSet::new());
+                users.push(pw.username.as_str());
+            }
+        }
+        for gr in &self.group {
+            for m in &gr.members {
+                if !groups.contains_key(m.as_str()) {
+                    users.push(m.as_str());
+                }
+                groups.entry(m).or_default().insert(gr.gid);
+            }
+        }
+        users
+            .into_iter()
+            .map(|u| {
+                let mut gids = Vec::new();
+                if let Some(pw) = self.passwd.iter().find(|pw| pw.username == u) {
+                    gids.push(pw.gid);
+                }
+                for &gid in &groups[u] {
+                    if !gids.contains(&gid) {
+                        gids.push(gid);
+                    }
+                }
+                (u.to_string(), gids)
+            })
+            .collect()
+    }
+
+    /// Warn about entries with the same name or id. The first one wins.
+    pub fn check_duplicates(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut dup = |what: &str, keys: Vec<String>| {
+            let mut seen = BTreeSet::new();
+            for k in keys {
+                if !seen.insert(k.clone()) {
+                    warnings.push(format!("duplicate {} {}", what, k));
+                }
+            }
+        };
+        dup("username", self.passwd.iter().map(|p| p.username.clone()).collect());
+        dup("uid", self.passwd.iter().map(|p| p.uid.to_string()).collect());
+        dup("group", self.group.iter().map(|g| g.group.clone()).collect());
+        dup("gid", self.group.iter().map(|g| g.gid.to_string()).collect());
+        dup("shadow entry", self.shadow.iter().map(|s| s.username.clone()).collect());
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_gidlist() {
+        let dir = std::env::temp_dir().join(format!("webnis-makedb-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let passwd = dir.join("passwd");
+        let group = dir.join("group");
+        fs::write(&passwd, "root:x:0:0:root:/root:/bin/sh\n+::::::\nmikevs:x:1000:100::/home/mikevs:/bin/sh\n").unwrap();
+        fs::write(&group, "# comment\nwheel:x:10:root,mikevs\nusers:x:100:\nstaff:x:50:mikevs,guest\n").unwrap();
+
+        let mut db = Db::default();
+        db.read_passwd(passwd.to_str().unwrap()).unwrap();
+        db.read_group(group.to_str().unwrap()).unwrap();
+        assert_eq!(db.passwd.len(), 2);
+        assert_eq!(db.group[1].members.len(), 0);
+        assert_eq!(db.gidlist(), vec![
+            ("root".to_string(), vec![0, 10]),
+            ("mikevs".to_string(), vec![100, 10, 50]),
+            ("guest".to_string(), vec![50]),
+        ]);
+
+        fs::write(&passwd, "root:x:zero:0:root:/root:/bin/sh\n").unwrap();
+        assert!(Db::default().read_passwd(passwd.to_str().unwrap()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
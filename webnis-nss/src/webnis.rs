@@ -1,10 +1,15 @@
 
-use std::time::{Duration, SystemTime};
+use std::cell::RefCell;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::io::{BufRead,BufReader};
 use std::thread::sleep;
 use std::io::Write;
 
+use libc;
+
 use super::nss::{Passwd,Group,uid_t,gid_t,NssResult,NssError};
 
 static SOCKADDR: &'static str = "/var/run/webnis-bind.sock";
@@ -13,78 +18,251 @@ const MAX_TIMEOUT_MS: u64 = 2000;
 const RETRY_DELAY_MS: u64 = 500;
 const REQUEST_READ_TIMEOUT_MS: u64 = 1500;
 const REQUEST_WRITE_TIMEOUT_MS: u64 = 1000;
+// webnis-bind closes connections that are idle for 10 seconds.
+const IDLE_TIMEOUT_MS: u64 = 8000;
+// how long group entries fetched by getgidlist are valid.
+const PREFETCH_TTL_MS: u64 = 5000;
+// max number of keys in one MULTI request.
+const MAX_MULTI: usize = 64;
+
+// connection to webnis-bind, kept open between lookups.
+struct Conn {
+    rdr:    BufReader<UnixStream>,
+    pid:    libc::pid_t,
+    used:   Instant,
+    // device and inode of the socket, see fd_ident.
+    ident:  Option<(libc::dev_t, libc::ino_t)>,
+}
+
+// group entries fetched in advance.
+struct Prefetch {
+    time:   Instant,
+    groups: Vec<(gid_t, String)>,
+}
 
-pub struct Webnis;
+pub struct Webnis {
+    conn:       RefCell<Option<Conn>>,
+    seqno:      RefCell<u64>,
+    prefetch:   RefCell<Option<Prefetch>>,
+}
 
 impl Webnis {
     pub fn new() -> Webnis {
         if log_enabled!(::log::Level::Debug) {
             ::env_logger::init();
         }
-        Webnis
+        Webnis {
+            conn:       RefCell::new(None),
+            seqno:      RefCell::new(0),
+            prefetch:   RefCell::new(None),
+        }
     }
 
     pub fn getgidlist(&self, name: &str) -> NssResult<(Vec<gid_t>)> {
-        let reply = wnbind_get("getgidlist", name)?;
-        decode_gidlist(reply)
+        let reply = self.wnbind_get("getgidlist", name)?;
+        let gids = decode_gidlist(reply)?;
+
+        // initgroups() is usually followed by a getgrgid() for every gid.
+        // get all of them at once.
+        self.prefetch_groups(&gids);
+
+        Ok(gids)
     }
 
     pub fn getgrnam(&self, grp: &mut Group, name: &str) -> NssResult<()> {
-        let reply = wnbind_get("getgrnam", name)?;
+        let reply = self.wnbind_get("getgrnam", name)?;
         decode_group(grp, reply)
     }
 
     pub fn getgrgid(&self, grp: &mut Group, gid: gid_t) -> NssResult<()> {
-        let reply = wnbind_get("getgrgid", &gid.to_string())?;
+        if let Some(line) = self.prefetched_group(gid) {
+            debug!("getgrgid {}: using prefetched entry", gid);
+            let reply = reply_value(&line)?;
+            return decode_group(grp, reply);
+        }
+        let reply = self.wnbind_get("getgrgid", &gid.to_string())?;
         decode_group(grp, reply)
     }
 
     pub fn getpwnam(&self, pwd: &mut Passwd, name: &str) -> NssResult<()> {
-        let reply = wnbind_get("getpwnam", name)?;
+        let reply = self.wnbind_get("getpwnam", name)?;
         decode_passwd(pwd, reply)
     }
 
     pub fn getpwuid(&self, pwd: &mut Passwd, uid: uid_t) -> NssResult<()> {
-        let reply = wnbind_get("getpwuid", &uid.to_string())?;
+        let reply = self.wnbind_get("getpwuid", &uid.to_string())?;
         decode_passwd(pwd, reply)
     }
+
+    // look up all groups in one MULTI request, and remember the answers.
+    fn prefetch_groups(&self, gids: &[gid_t]) {
+        *self.prefetch.borrow_mut() = None;
+        if gids.len() == 0 || gids.len() > MAX_MULTI {
+            return;
+        }
+        let args = gids.iter().map(|g| g.to_string()).collect::<Vec<_>>().join(" ");
+        let lines = match self.wnbind_try(&format!("multi getgrgid {}", args), gids.len()) {
+            Ok(lines) => lines,
+            Err(_) => return,
+        };
+        let groups = gids.iter().cloned().zip(lines.into_iter()).filter(|&(_, ref line)| {
+            // only remember answers, not errors.
+            line.starts_with("200 ") || line.starts_with("404 ")
+        }).collect();
+        *self.prefetch.borrow_mut() = Some(Prefetch{ time: Instant::now(), groups: groups });
+    }
+
+    fn prefetched_group(&self, gid: gid_t) -> Option<String> {
+        let prefetch = self.prefetch.borrow();
+        let prefetch = prefetch.as_ref()?;
+        if duration_millis(&prefetch.time.elapsed()) > PREFETCH_TTL_MS {
+            return None;
+        }
+        prefetch.groups.iter().find(|&&(g, _)| g == gid).map(|&(_, ref line)| line.clone())
+    }
+
+    // get the open connection to webnis-bind, or connect.
+    fn connection(&self) -> NssResult<(Conn, bool)> {
+        let pid = unsafe { libc::getpid() };
+        if let Some(conn) = self.conn.borrow_mut().take() {
+            // do not use a connection that was inherited over fork(), or that
+            // webnis-bind probably already closed.
+            // The program might have closed all its file descriptors
+            // (closefrom) and opened another file, that got the same number.
+            // Then it is not our socket anymore, and we must not close it.
+            if fd_ident(conn.rdr.get_ref().as_raw_fd()) != conn.ident {
+                debug!("connection to webnis-bind was closed behind our back");
+                mem::forget(conn);
+            } else if conn.pid == pid && duration_millis(&conn.used.elapsed()) < IDLE_TIMEOUT_MS {
+                return Ok((conn, true));
+            }
+        }
+        let socket = match UnixStream::connect(SOCKADDR) {
+            Ok(s) => s,
+            Err(e) => {
+                debug!("connect to {}: {}", SOCKADDR, e);
+                return Err(e)?;
+            },
+        };
+        socket.set_read_timeout(Some(Duration::from_millis(REQUEST_READ_TIMEOUT_MS))).ok();
+        socket.set_write_timeout(Some(Duration::from_millis(REQUEST_WRITE_TIMEOUT_MS))).ok();
+        let ident = fd_ident(socket.as_raw_fd());
+        Ok((Conn{ rdr: BufReader::new(socket), pid: pid, used: Instant::now(), ident: ident }, false))
+    }
+
+    // send one command, read `nlines` reply lines.
+    //
+    // The request is tagged with a request id, so that we can skip
+    // replies to earlier requests that timed out.
+    fn wnbind_try(&self, cmd: &str, nlines: usize) -> NssResult<Vec<String>> {
+        loop {
+            let (mut conn, reused) = self.connection()?;
+            let id = {
+                let mut seqno = self.seqno.borrow_mut();
+                *seqno += 1;
+                *seqno
+            };
+            match conn_request(&mut conn, id, cmd, nlines) {
+                Ok(lines) => {
+                    conn.used = Instant::now();
+                    *self.conn.borrow_mut() = Some(conn);
+                    return Ok(lines);
+                },
+                // webnis-bind might have closed the connection, try a fresh one.
+                Err(NssError::TryAgainNow) if reused => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // call wnbind_try and sleep/retry a few times if we fail.
+    fn wnbind_get(&self, cmd: &str, arg: &str) -> NssResult<String> {
+        let now = SystemTime::now();
+        let cmd = format!("{} {}", cmd, arg);
+        loop {
+            if let Ok(elapsed) = now.elapsed() {
+                if duration_millis(&elapsed) > MAX_TIMEOUT_MS {
+                    return Err(NssError::TryAgainLater);
+                }
+            }
+            match self.wnbind_try(&cmd, 1).and_then(|lines| reply_value(&lines[0])) {
+                Ok(r) => {
+                    if r.contains(0 as char) {
+                        debug!("wnbind answer contains a literal 0");
+                        return Err(NssError::Unavailable);
+                    }
+                    return Ok(r);
+                },
+                res @ Err(NssError::NotFound) => return res,
+                res @ Err(NssError::TryAgainLater) => return res,
+                res @ Err(NssError::InsufficientBuffer) => return res,
+                res @ Err(NssError::Unavailable) => return res,
+                Err(NssError::TimedOut) => {},
+                Err(NssError::TryAgainNow) => {
+                    if let Ok(elapsed) = now.elapsed() {
+                        if duration_millis(&elapsed) + RETRY_DELAY_MS < MAX_TIMEOUT_MS {
+                            sleep(Duration::from_millis(RETRY_DELAY_MS));
+                        }
+                    }
+                },
+            }
+        }
+    }
 }
 
 fn duration_millis(d: &Duration) -> u64 {
-    d.as_secs() + (d.subsec_millis() as u64)
+    d.as_secs() * 1000 + (d.subsec_millis() as u64)
 }
 
-// open socket, send one command, read reply, return.
-fn wnbind_try(cmd: &str, arg: &str) -> NssResult<String> {
+// device and inode of an open file descriptor, to see if it is still
+// the socket we opened (like the sssd nss client does).
+fn fd_ident(fd: RawFd) -> Option<(libc::dev_t, libc::ino_t)> {
+    let mut st: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } < 0 {
+        return None;
+    }
+    Some((st.st_dev, st.st_ino))
+}
 
-    // connect to webnis-bind.
-    let mut socket = match UnixStream::connect(SOCKADDR) {
-        Ok(s) => s,
-        Err(e) => {
-            debug!("connect to {}: {}", SOCKADDR, e);
-            return Err(e)?;
-        },
-    };
-    socket.set_read_timeout(Some(Duration::from_millis(REQUEST_READ_TIMEOUT_MS))).ok();
-    socket.set_write_timeout(Some(Duration::from_millis(REQUEST_WRITE_TIMEOUT_MS))).ok();
+// send a tagged request on a connection, and read the reply lines.
+fn conn_request(conn: &mut Conn, id: u64, cmd: &str, nlines: usize) -> NssResult<Vec<String>> {
 
     // send request.
-    let b = format!("{} {}\n", cmd, arg).into_bytes();
-    if let Err(e) = socket.write_all(&b) {
+    let b = format!("#{} {}\n", id, cmd).into_bytes();
+    if let Err(e) = conn.rdr.get_mut().write_all(&b) {
         debug!("write to {}: {}", SOCKADDR, e);
         return Err(e)?;
     }
 
     // get reply.
-    let mut line = String::new();
-    let mut rdr = BufReader::new(socket);
-    if let Err(e) = rdr.read_line(&mut line) {
-        debug!("reading from {}: {}", SOCKADDR, e);
-        return Err(e)?;
+    let tag = format!("#{} ", id);
+    let mut lines = Vec::new();
+    while lines.len() < nlines {
+        let mut line = String::new();
+        match conn.rdr.read_line(&mut line) {
+            Ok(0) => {
+                debug!("reading from {}: EOF", SOCKADDR);
+                return Err(NssError::TryAgainNow);
+            },
+            Ok(_) => {},
+            Err(e) => {
+                debug!("reading from {}: {}", SOCKADDR, e);
+                return Err(e)?;
+            },
+        }
+        if !line.starts_with(&tag) {
+            debug!("skipping reply to another request: {}", line.trim_right());
+            continue;
+        }
+        lines.push(line[tag.len()..].trim_right().to_string());
     }
+    Ok(lines)
+}
 
-    // split into reply-code and message-text
-    let mut s = line.trim_right().splitn(2, ' ');
+// split reply into reply-code and message-text, and return the message
+// if the reply code means success.
+fn reply_value(line: &str) -> NssResult<String> {
+    let mut s = line.splitn(2, ' ');
     let num = s.next().unwrap();
     let val = s.next().unwrap_or("");
 
@@ -114,39 +292,6 @@ fn wnbind_try(cmd: &str, arg: &str) -> NssResult<String> {
     }
 }
 
-// call cmd_run and sleep/retry a few times if we fail.
-fn wnbind_get(cmd: &str, arg: &str) -> NssResult<String> {
-    let now = SystemTime::now();
-    loop {
-        if let Ok(elapsed) = now.elapsed() {
-            if duration_millis(&elapsed) > MAX_TIMEOUT_MS {
-                return Err(NssError::TryAgainLater);
-            }
-        }
-        match wnbind_try(cmd, arg) {
-            Ok(r) => {
-                if r.contains(0 as char) {
-                    debug!("wnbind answer contains a literal 0");
-                    return Err(NssError::Unavailable);
-                }
-                return Ok(r);
-            },
-            res @ Err(NssError::NotFound) => return res,
-            res @ Err(NssError::TryAgainLater) => return res,
-            res @ Err(NssError::InsufficientBuffer) => return res,
-            res @ Err(NssError::Unavailable) => return res,
-            Err(NssError::TimedOut) => {},
-            Err(NssError::TryAgainNow) => {
-                if let Ok(elapsed) = now.elapsed() {
-                    if duration_millis(&elapsed) + RETRY_DELAY_MS < MAX_TIMEOUT_MS {
-                        sleep(Duration::from_millis(RETRY_DELAY_MS));
-                    }
-                }
-            },
-        }
-    }
-}
-
 // decode passwd line
 fn decode_passwd(pwd: &mut Passwd, line: String) -> NssResult<()> {
 
@@ -237,3 +382,22 @@ fn decode_gidlist(line: String) -> NssResult<Vec<gid_t>> {
     Ok(gids)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn t_fd_ident() {
+        let (sock, _peer) = UnixStream::pair().unwrap();
+        let fd = sock.as_raw_fd();
+        let ident = fd_ident(fd);
+        assert!(ident.is_some());
+        assert_eq!(fd_ident(fd), ident);
+
+        // another file with the same fd number.
+        let file = File::open("/dev/null").unwrap();
+        assert!(unsafe { libc::dup2(file.as_raw_fd(), fd) } >= 0);
+        assert!(fd_ident(fd) != ident);
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::config;
+use crate::datalog::rfc3339;
+
+lazy_static! {
+    static ref AUDIT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// One change to a writable map.
+#[derive(Serialize, Debug)]
+pub(crate) struct Change<'a> {
+    pub time:     String,
+    pub op:       &'a str,
+    pub domain:   &'a str,
+    pub map:      &'a str,
+    pub keyname:  &'a str,
+    pub keyvalue: &'a str,
+    pub src_ip:   String,
+    pub old:      Option<serde_json::Value>,
+    pub new:      Option<serde_json::Value>,
+    /// set if the change failed halfway.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error:    Option<String>,
+    /// files that were changed before the error, and could not be changed back.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changed:  Vec<String>,
+}
+
+impl<'a> Change<'a> {
+    pub fn new(op: &'a str, domain: &'a str, map: &'a str, keyname: &'a str, keyvalue: &'a str, ip: IpAddr) -> Change<'a> {
+        Change {
+            time: rfc3339(SystemTime::now()),
+            op,
+            domain,
+            map,
+            keyname,
+            keyvalue,
+            src_ip: ip.to_string(),
+            old: None,
+            new: None,
+            error: None,
+            changed: Vec::new(),
+        }
+    }
+}
+
+// password hashes do not belong in a log file.
+fn redact(entry: &Option<serde_json::Value>) -> Option<serde_json::Value> {
+    let mut entry = entry.clone();
+    if let Some(serde_json::Value::Object(ref mut obj)) = entry {
+        if let Some(passwd) = obj.get_mut("passwd") {
+            *passwd = serde_json::Value::String("*".to_string());
+        }
+    }
+    entry
+}
+
+/// Write a change to the audit log, one JSON object per line.
+/// If `audit_log` is not set, the change is logged as a normal log message.
+pub(crate) fn log(server: &config::Server, change: &Change) {
+    let change = Change {
+        old: redact(&change.old),
+        new: redact(&change.new),
+        time: change.time.clone(),
+        src_ip: change.src_ip.clone(),
+        error: change.error.clone(),
+        changed: change.changed.clone(),
+        ..*change
+    };
+    let line = serde_json::to_string(&change).unwrap();
+
+    let path = match server.audit_log {
+        Some(ref p) => p,
+        None => {
+            info!("audit: {}", line);
+            return;
+        },
+    };
+    let _guard = AUDIT_LOCK.lock().unwrap();
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(format!("{}\n", line).as_bytes()));
+    if let Err(e) = res {
+        error!("audit log {}: {}", path, e);
+        info!("audit: {}", line);
+    }
+}
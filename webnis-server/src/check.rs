@@ -0,0 +1,238 @@
+//! Deep check of the configuration (`--check`).
+//!
+//! `--syntaxcheck` only parses the configuration. This walks every
+//! domain and every map, opens the map files, and runs the entries
+//! through the same code that is used for lookups.
+use std::fs;
+
+use crate::config::{Config, Domain, Map};
+use crate::db::{self, MapType};
+use crate::errors::WnError;
+use crate::format;
+use crate::lua;
+
+// number of bad entries to show per map.
+const MAX_SHOWN: usize = 3;
+
+#[derive(Default)]
+struct Check {
+    errors:     usize,
+    warnings:   usize,
+}
+
+impl Check {
+    fn error(&mut self, msg: String) {
+        println!("    error: {}", msg);
+        self.errors += 1;
+    }
+
+    fn warning(&mut self, msg: String) {
+        println!("    warning: {}", msg);
+        self.warnings += 1;
+    }
+}
+
+/// Check all domains and maps. If `sample` is set, only that many entries
+/// of each map file are checked. Returns false if there were errors.
+pub fn check(config: &Config, sample: Option<usize>) -> bool {
+    let mut c = Check::default();
+
+    for domain in &config.domain {
+        println!("domain {}: {}", domain.name, domain.db_dir);
+        match fs::metadata(&domain.db_dir) {
+            Ok(m) if m.is_dir() => {},
+            Ok(_) => c.error(format!("db_dir {}: not a directory", domain.db_dir)),
+            Err(e) => c.error(format!("db_dir {}: {}", domain.db_dir, e)),
+        }
+
+        if let Some(auth) = domain.auth.as_ref().and_then(|a| config.auth.get(a)) {
+            if let (Some(map), Some(key)) = (auth.map.as_ref(), auth.key.as_ref()) {
+                if config.find_map(map, key).is_none() {
+                    c.error(format!("auth {}: map {} with key {} not found", domain.auth.as_ref().unwrap(), map, key));
+                }
+            }
+            if let Some(ref func) = auth.lua_function {
+                check_lua_function(&mut c, config, func);
+            }
+        }
+
+        for name in &domain.maps {
+            let maps = match config.map_.get(name) {
+                Some(maps) => maps,
+                None => {
+                    c.error(format!("map {}: not defined", name));
+                    continue;
+                },
+            };
+            for map in maps {
+                check_map(&mut c, config, domain, map, sample);
+            }
+        }
+    }
+
+    println!("{} errors, {} warnings", c.errors, c.warnings);
+    c.errors == 0
+}
+
+fn check_lua_function(c: &mut Check, config: &Config, func: &str) {
+    if config.lua.is_none() {
+        c.error(format!("lua function {}: no lua script configured", func));
+    } else if !lua::lua_function_exists(func) {
+        c.error(format!("lua function {}: not defined in the lua script", func));
+    }
+}
+
+// name of a map definition, with the key if there is one: "passwd.uid".
+fn map_name(map: &Map) -> String {
+    match map.key {
+        Some(ref key) => format!("{}.{}", map.name, key),
+        None => map.name.clone(),
+    }
+}
+
+fn check_map(c: &mut Check, config: &Config, domain: &Domain, map: &Map, sample: Option<usize>) {
+    let name = map_name(map);
+    let path = match map.map_file {
+        Some(ref file) => format!("{}/{}", domain.db_dir, file),
+        None => String::new(),
+    };
+
+    // the number of entries in the file, and the checked entries with the result.
+    let entries = match map.map_type {
+        MapType::Gdbm => {
+            let format = match map.map_format {
+                Some(ref f) => f,
+                None => {
+                    println!("  {}: {}", name, path);
+                    c.error(format!("map {}: gdbm map without format", name));
+                    return;
+                },
+            };
+            db::gdbm_list(&path).map(|lines| {
+                let n = lines.len();
+                let lines = lines.into_iter().take(sample.unwrap_or(n));
+                let values = lines.map(|l| {
+                    let res = format::line_to_json(&l, format, &map.map_output);
+                    (l, res)
+                });
+                (n, values.collect::<Vec<_>>())
+            })
+        },
+        MapType::Json => {
+            db::json_list(&path).map(|values| {
+                let n = values.len();
+                let values = values.into_iter().take(sample.unwrap_or(n));
+                let values = values.map(|v| {
+                    let line = v.to_string();
+                    let res = match map.map_output {
+                        Some(ref output) => Ok(output.apply(&v)),
+                        None => Ok(v),
+                    };
+                    (line, res)
+                });
+                (n, values.collect::<Vec<_>>())
+            })
+        },
+        MapType::Lua => {
+            println!("  {}: lua function {}", name, map.lua_function.as_ref().unwrap());
+            check_lua_function(c, config, map.lua_function.as_ref().unwrap());
+            return;
+        },
+        MapType::Gidlist => {
+            println!("  {}: gidlist of group map {}", name, map.group_map.as_ref().unwrap());
+            for m in map.group_map.iter().chain(map.passwd_map.iter()) {
+                if !domain.maps.contains(m) {
+                    c.warning(format!("map {}: map {} is not in the maps of this domain", name, m));
+                }
+            }
+            return;
+        },
+        MapType::Chain => {
+            println!("  {}: chain of {}", name, map.chain.join(", "));
+            for m in &map.chain {
+                if !domain.maps.contains(m) {
+                    c.warning(format!("map {}: map {} is not in the maps of this domain", name, m));
+                }
+            }
+            return;
+        },
+        MapType::None => return,
+    };
+
+    let (total, entries) = match entries {
+        Ok(e) => e,
+        Err(WnError::MapNotFound) => {
+            println!("  {}: {}", name, path);
+            c.error(format!("map {}: {}: cannot open", name, path));
+            return;
+        },
+        Err(_) => {
+            println!("  {}: {}", name, path);
+            c.error(format!("map {}: {}: cannot read, or not a JSON array", name, path));
+            return;
+        },
+    };
+
+    // json maps are searched for the key members, so every entry should have them.
+    let keys = if map.map_type == MapType::Json {
+        map.key.iter().chain(map.keys.iter()).collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+
+    let mut bad = Vec::new();
+    let mut nokey = 0;
+    for (line, res) in &entries {
+        match res {
+            Ok(v) if !v.is_object() => bad.push(format!("{:?}: not an object", line)),
+            Ok(v) => {
+                if keys.iter().any(|k| v.get(k.as_str()).is_none()) {
+                    nokey += 1;
+                }
+            },
+            Err(e) => bad.push(format!("{:?}: {}", line, e)),
+        }
+    }
+
+    println!("  {}: {}: {} entries, {} checked, {} bad", name, path, total, entries.len(), bad.len());
+    for b in bad.iter().take(MAX_SHOWN) {
+        c.error(format!("map {}: {}", name, b));
+    }
+    if bad.len() > MAX_SHOWN {
+        println!("    ... and {} more", bad.len() - MAX_SHOWN);
+        c.errors += bad.len() - MAX_SHOWN;
+    }
+    if nokey > 0 {
+        c.warning(format!("map {}: {} entries without key {}", name, nokey, keys.iter().map(|k| k.as_str()).collect::<Vec<_>>().join("/")));
+    }
+    if total == 0 {
+        c.warning(format!("map {}: map is empty", name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn t_check() {
+        let dir = std::env::temp_dir().join(format!("webnis-check-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cfg = dir.join("webnis-server.toml");
+        fs::write(&cfg, format!(
+            "[server]\nlisten = \"127.0.0.1:3245\"\n\
+             [[domain]]\nname = \"d\"\ndb_dir = {:?}\nmaps = [ \"passwd\" ]\n\
+             [map.passwd]\ntype = \"json\"\nkey = \"username\"\nfile = \"passwd.json\"\n",
+            dir.to_str().unwrap())).unwrap();
+        let config = config::read(&cfg).unwrap();
+
+        fs::write(dir.join("passwd.json"), r#"[ { "username": "root", "uid": 0 } ]"#).unwrap();
+        assert!(check(&config, None));
+        fs::write(dir.join("passwd.json"), r#"[ { "username": "root", "uid": 0 }, "root" ]"#).unwrap();
+        assert!(!check(&config, None));
+        assert!(check(&config, Some(1)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
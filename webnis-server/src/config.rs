@@ -6,16 +6,25 @@ use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use regex::Regex;
 use serde::{de::Deserializer, Deserialize};
 use toml;
+use webnis_common::secret::{expand_env, secret};
 
+use crate::datalog::{deserialize_datalog_format, DatalogFormat};
 use crate::db::{deserialize_map_type, MapType};
-use crate::format::{option_deserialize_format, Format};
+use crate::errors::WnError;
+use crate::format::{option_deserialize_format, Format, Output};
 use crate::iplist::IpList;
 
+// max nesting of chain maps.
+pub const MAX_CHAIN_DEPTH: u32 = 8;
+
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub server: Server,
+    #[serde(default)]
     pub domain: Vec<Domain>,
     //#[serde(default, rename="mapdef")]
     #[serde(default)]
@@ -26,9 +35,24 @@ pub struct Config {
     pub auth: HashMap<String, Auth>,
     pub lua: Option<LuaConfig>,
     pub include_maps: Option<String>,
+    #[serde(default)]
+    pub include: Vec<String>,
+}
+
+// A file from `include`: domains, maps and auth sections only.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Include {
+    #[serde(default)]
+    domain: Vec<Domain>,
+    #[serde(default)]
+    map:    HashMap<String, MapOrMaps>,
+    #[serde(default)]
+    auth:   HashMap<String, Auth>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Server {
     #[serde(default)]
     pub tls: bool,
@@ -41,10 +65,60 @@ pub struct Server {
     pub securenets: Vec<String>,
     #[serde(skip)]
     pub securenets_: Vec<PathBuf>,
-    pub datalog: Option<String>,
+    pub datalog: Option<FileOrDatalog>,
+    #[serde(skip)]
+    pub datalog_: Option<Datalog>,
+    /// audit log of changes to writable maps.
+    pub audit_log: Option<String>,
+    /// drop privileges to this user and group after startup.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// chroot to this directory after startup.
+    pub chroot: Option<String>,
+    /// restrict filesystem access with landlock.
+    #[serde(default)]
+    pub landlock: bool,
 }
 
+/// `datalog = "/path/to/file"` or a `[server.datalog]` section.
 #[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FileOrDatalog {
+    File(String),
+    Datalog(Datalog),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Datalog {
+    /// output format: datalog, json, syslog
+    #[serde(default, deserialize_with = "deserialize_datalog_format")]
+    pub format: DatalogFormat,
+    /// syslog facility, for format = "syslog".
+    pub facility: Option<String>,
+    /// destination. exactly one of these must be set.
+    pub file: Option<String>,
+    pub unix: Option<String>,
+    pub tcp: Option<String>,
+    pub udp: Option<String>,
+    /// site-specific error codes. replaces the builtin table.
+    #[serde(default)]
+    pub error: Vec<DatalogError>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DatalogError {
+    /// name, as exported to lua in the `error` table.
+    pub name: String,
+    /// message sent to the client.
+    pub reply: Option<String>,
+    /// message that is logged.
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Domain {
     /// domain name
     pub name: String,
@@ -60,15 +134,34 @@ pub struct Domain {
     pub http_authtoken: Option<String>,
     /// Encoding of the authtoken. For schema 'Basic' this is usually 'base64'.
     pub http_authencoding: Option<String>,
+    /// Credential for the admin (write) API. Same format as http_auth*.
+    pub admin_authschema: Option<String>,
+    pub admin_authtoken: Option<String>,
+    pub admin_authencoding: Option<String>,
+    /// Replica: base URL of the primary server to copy the map files from.
+    pub replicate_from: Option<String>,
+    /// Replica: how often to check the primary for changes (seconds).
+    pub replicate_interval: Option<u64>,
+    /// Replica: wait for change notifications from the primary (default true).
+    pub replicate_watch: Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Auth {
     pub map:          Option<String>,
     pub key:          Option<String>,
     pub lua_function: Option<String>,
 }
 
+// The keys of a map definition. `Map` cannot use deny_unknown_fields
+// because of the flattened submaps, so `check_map_keys` uses this list.
+const MAP_KEYS: &[&str] = &[
+    "key", "keys", "key_alias", "key_lowercase", "key_trim", "key_regex", "key_max_length",
+    "key_numeric", "lua_function", "type", "format", "file", "output", "writable", "enumerate",
+    "cache_max_age", "group_map", "passwd_map", "maps", "merge",
+];
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Map {
     #[serde(skip, default)]
@@ -78,6 +171,19 @@ pub struct Map {
     pub keys: Vec<String>,
     #[serde(default)]
     pub key_alias: HashMap<String, String>,
+    /// key rules: lowercase and/or trim the key before lookup.
+    #[serde(default)]
+    pub key_lowercase: bool,
+    #[serde(default)]
+    pub key_trim: bool,
+    /// key rules: the key must match this regular expression.
+    #[serde(default, deserialize_with = "deserialize_key_regex")]
+    pub key_regex: Option<Regex>,
+    /// key rules: maximum length of the key.
+    pub key_max_length: Option<usize>,
+    /// key rules: key must be numeric (uid, gid).
+    #[serde(default)]
+    pub key_numeric: bool,
     /// LUA function to call.
     pub lua_function: Option<String>,
     /// type: gdbm, json, lua
@@ -91,12 +197,31 @@ pub struct Map {
     pub map_file: Option<String>,
     /// optional args for types like 'fields'
     #[serde(rename = "output")]
-    pub map_output: Option<HashMap<String, String>>,
+    pub map_output: Option<Output>,
+    /// entries can be changed using the admin API.
+    #[serde(default)]
+    pub writable: bool,
+    /// all entries can be listed.
+    #[serde(default)]
+    pub enumerate: bool,
+    /// max-age for the Cache-Control header of lookups in this map.
+    pub cache_max_age: Option<u32>,
+    /// for type 'gidlist': the group map to index.
+    pub group_map: Option<String>,
+    /// for type 'gidlist': optional passwd map for the primary gid.
+    pub passwd_map: Option<String>,
+    /// for type 'chain': the maps to look in, in order.
+    #[serde(default, rename = "maps")]
+    pub chain: Vec<String>,
+    /// for type 'chain': merge the results instead of returning the first.
+    #[serde(default)]
+    pub merge: bool,
     #[serde(flatten)]
     pub submaps: HashMap<String, Map>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct LuaConfig {
     pub script: String,
     #[serde(skip)]
@@ -161,12 +286,48 @@ where D: Deserializer<'de> {
 	Ok(res)
 }
 
+// The key_regex must match the whole key.
+fn deserialize_key_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    Regex::new(&format!("^(?:{})$", s)).map(Some).map_err(serde::de::Error::custom)
+}
+
+impl Map {
+    /// Apply the key rules of this map to a key: normalize it, then validate it.
+    pub fn check_key(&self, key: &str) -> Result<String, WnError> {
+        let mut key = if self.key_trim { key.trim() } else { key }.to_string();
+        if self.key_lowercase {
+            key = key.to_lowercase();
+        }
+        if let Some(max) = self.key_max_length {
+            if key.chars().count() > max {
+                return Err(WnError::InvalidKey("Key too long"));
+            }
+        }
+        if self.key_numeric && (key == "" || !key.chars().all(|c| c.is_ascii_digit())) {
+            return Err(WnError::InvalidKey("Key must be numeric"));
+        }
+        if let Some(ref re) = self.key_regex {
+            if !re.is_match(&key) {
+                return Err(WnError::InvalidKey("Key not allowed"));
+            }
+        }
+        Ok(key)
+    }
+}
+
 fn map_inherit(key: &str, map: &Map, base: &Map) -> Map {
     Map {
         name:         String::new(),
         key:          map.key.clone().or_else(|| Some(key.to_string())),
         keys:         map.keys.clone(),
         key_alias:    map.key_alias.clone(),
+        key_lowercase: map.key_lowercase || base.key_lowercase,
+        key_trim:     map.key_trim || base.key_trim,
+        key_regex:    map.key_regex.clone().or_else(|| base.key_regex.clone()),
+        key_max_length: map.key_max_length.or(base.key_max_length),
+        key_numeric:  map.key_numeric || base.key_numeric,
         lua_function: map.lua_function.clone().or_else(|| base.lua_function.clone()),
         map_type:     if map.map_type != MapType::None {
             map.map_type.clone()
@@ -176,10 +337,133 @@ fn map_inherit(key: &str, map: &Map, base: &Map) -> Map {
         map_format:   map.map_format.clone().or_else(|| base.map_format.clone()),
         map_file:     map.map_file.clone().or_else(|| base.map_file.clone()),
         map_output:   map.map_output.clone().or_else(|| base.map_output.clone()),
+        writable:     map.writable || base.writable,
+        enumerate:    map.enumerate || base.enumerate,
+        cache_max_age: map.cache_max_age.or(base.cache_max_age),
+        group_map:    map.group_map.clone().or_else(|| base.group_map.clone()),
+        passwd_map:   map.passwd_map.clone().or_else(|| base.passwd_map.clone()),
+        chain:        if map.chain.len() > 0 {
+            map.chain.clone()
+        } else {
+            base.chain.clone()
+        },
+        merge:        map.merge || base.merge,
         submaps:      HashMap::new(),
     }
 }
 
+// The text of a configuration file, to find line numbers for error messages.
+struct Source {
+    // name of the file, if it is not the main configuration file.
+    file:       Option<String>,
+    text:       String,
+    // prefix of map sections: "map." in the main file, nothing in include_maps.
+    map_prefix: &'static str,
+}
+
+impl Source {
+    // Line number of the `[section]` header, or of `key = ...` in that section.
+    fn line(&self, section: &str, key: Option<&str>) -> Option<usize> {
+        let header = format!("[{}]", section);
+        let mut found = false;
+        for (n, line) in self.text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.starts_with('[') {
+                found = line == header;
+                if found && key.is_none() {
+                    return Some(n + 1);
+                }
+            } else if found {
+                if let Some(rest) = key.and_then(|k| line.strip_prefix(k)) {
+                    if rest.trim_start().starts_with('=') {
+                        return Some(n + 1);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Line number of the first line that contains `"needle"`
+    // in the `[[domain]]` section with this name.
+    fn domain_line(&self, name: &str, needle: &str) -> Option<usize> {
+        let mut sections: Vec<Vec<(usize, &str)>> = Vec::new();
+        for (n, line) in self.text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.starts_with('[') {
+                sections.push(Vec::new());
+            }
+            if let Some(s) = sections.last_mut() {
+                s.push((n + 1, line));
+            }
+        }
+        let name = format!("\"{}\"", name);
+        let needle = format!("\"{}\"", needle);
+        let section = sections.iter().find(|s| {
+            s[0].1 == "[[domain]]" && s.iter().any(|(_, l)| l.starts_with("name") && l.contains(&name))
+        })?;
+        section
+            .iter()
+            .find(|(_, l)| !l.starts_with("name") && l.contains(&needle))
+            .map(|(n, _)| *n)
+    }
+
+    // Line number of a map section, or of a key in it. If the map
+    // only has submaps, like [map.passwd.uid], the first of those.
+    fn map_line(&self, map: &str, key: Option<&str>) -> Option<usize> {
+        let section = format!("{}{}", self.map_prefix, map);
+        self.line(&section, key).or_else(|| {
+            if key.is_some() {
+                return None;
+            }
+            let prefix = format!("[{}.", section);
+            self.text.lines().position(|l| l.trim_start().starts_with(&prefix)).map(|n| n + 1)
+        })
+    }
+
+    // name of the file, for error messages.
+    fn name(&self) -> &str {
+        self.file.as_ref().map(|s| s.as_str()).unwrap_or("the main configuration file")
+    }
+
+    // " (line 12)", to append to an error message.
+    fn at(&self, line: Option<usize>) -> String {
+        match (line, &self.file) {
+            (Some(n), Some(f)) => format!(" ({} line {})", f, n),
+            (Some(n), None) => format!(" (line {})", n),
+            (None, _) => String::new(),
+        }
+    }
+}
+
+// Apply expand_env or secret to an optional setting.
+fn resolve(value: &mut Option<String>, f: fn(&str) -> Result<String, String>, what: String) -> io::Result<()> {
+    if let Some(v) = value.as_mut() {
+        *v = f(v).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("config: {}: {}", what, e)))?;
+    }
+    Ok(())
+}
+
+// Unknown keys in a map definition are an error. Tables that are not
+// known keys are submaps, like [map.passwd.uid].
+fn check_map_keys(name: &str, table: &toml::value::Table, src: &Source) -> io::Result<()> {
+    for (key, value) in table {
+        if MAP_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        match value {
+            toml::Value::Table(t) => check_map_keys(&format!("{}.{}", name, key), t, src)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: unknown key {:?}{}", name, key, src.at(src.map_line(name, Some(key)))),
+                ));
+            },
+        }
+    }
+    Ok(())
+}
+
 fn abs_path(toml_file: &Path, file: &str) -> PathBuf {
     match toml_file.parent() {
         Some(parent) => parent.join(Path::new(file)),
@@ -187,40 +471,380 @@ fn abs_path(toml_file: &Path, file: &str) -> PathBuf {
     }
 }
 
+// Match a file name against a pattern with `*` and `?`.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.len() == 0,
+        Some(('*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some(('?', rest)) => name.len() > 0 && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+// Expand an `include` pattern. Wildcards can only be used in the last
+// component of the path. The result is sorted, hidden files are skipped.
+fn include_files(pattern: &Path) -> io::Result<Vec<PathBuf>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("include {:?}: {}", pattern, msg));
+    let wild = |s: &str| s.contains('*') || s.contains('?');
+    let file_name = pattern.file_name().and_then(|f| f.to_str()).unwrap_or("");
+    if !wild(file_name) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let dir = pattern.parent().unwrap_or(Path::new("."));
+    if wild(&dir.to_string_lossy()) {
+        return Err(invalid("wildcards can only be used in the file name".to_string()));
+    }
+    let pat = file_name.chars().collect::<Vec<_>>();
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| invalid(e.to_string()))? {
+        let entry = entry.map_err(|e| invalid(e.to_string()))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') && wildcard_match(&pat, &name.chars().collect::<Vec<_>>()) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Remember in which source a domain, map or auth section was defined.
+// It can only be defined once.
+fn define(seen: &mut HashMap<String, usize>, what: &str, name: &str, idx: usize, sources: &[Source]) -> io::Result<()> {
+    if let Some(&prev) = seen.get(name) {
+        let msg = if prev == idx {
+            format!("config: {} {}: defined more than once in {}", what, name, sources[idx].name())
+        } else {
+            format!("config: {} {}: defined in both {} and {}", what, name, sources[prev].name(), sources[idx].name())
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    seen.insert(name.to_string(), idx);
+    Ok(())
+}
+
+// Build the definitions of one map from its section(s), and do some
+// basic validity checks.
+fn build_map(k: &str, v: &MapOrMaps) -> io::Result<Vec<Map>> {
+    //
+    // there are 3 variants here:
+    //
+    // 1. simple map definition: [passwd] => MapOrMaps::Map( MapDef )
+    //
+    // 2. a map definition with the keyname included in the name.
+    //    There can be multiple definitions with the same basename.
+    //    E.g [passwd.username] and [passwd.uid] => MapOrMaps::Maps( HashMap<String, Map> )
+    //    The hashmap has two entries here, with keys "username" and "uid".
+    //
+    // 3. Like 2, but with a basemap definition.
+    //    E.g [passwd], [passwd.username], [passwd.uid].
+    //    This results in a single Map (MapOrMaps::Map), where the
+    //    passwd.username and passwd.uid maps can be found in the map.submaps member.
+    //
+    // We put all definitions with the same basename together in a Vec.
+    let mut mm = Vec::new();
+    match v {
+        MapOrMaps::Map(m) => {
+            if m.submaps.len() > 0 {
+                // basemap with submaps.
+                if m.key.is_some() || m.keys.len() > 0 || m.key_alias.len() > 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("map {}: basemap cannot have a key", k),
+                    ));
+                }
+                for (key, submap) in m.submaps.iter() {
+                    mm.push(map_inherit(key, submap, m));
+                }
+            } else {
+                // single map.
+                mm.push(m.to_owned());
+            }
+        },
+        MapOrMaps::Maps(m) => {
+            for (key, map) in m.iter() {
+                let mut newmap = map.clone();
+                if newmap.key.is_none() {
+                    newmap.key = Some(key.to_owned());
+                }
+                mm.push(newmap);
+            }
+        },
+    }
+
+    // Now walk over all maps and do some basic validity checks.
+    for m in &mut mm {
+        m.name = k.to_string();
+
+        // Map type must be set.
+        if m.map_type == MapType::None {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("map {}: map_type not set", m.name),
+            ));
+        }
+
+        // format = "..." only works with MapType::Gdbm at this time.
+        if m.map_type != MapType::Gdbm && m.map_format.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("map {}: cannot use format with map type {:?}", m.name, m.map_type),
+            ));
+        }
+
+        // group_map / passwd_map only work with MapType::Gidlist.
+        if m.map_type != MapType::Gidlist && (m.group_map.is_some() || m.passwd_map.is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("map {}: group_map and passwd_map need map type \"gidlist\"", m.name),
+            ));
+        }
+
+        // maps / merge only work with MapType::Chain.
+        if m.map_type != MapType::Chain && (m.chain.len() > 0 || m.merge) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("map {}: maps and merge need map type \"chain\"", m.name),
+            ));
+        }
+
+        // output mapping works on the data in the map file.
+        let virtual_map = m.map_type == MapType::Lua || m.map_type == MapType::Gidlist || m.map_type == MapType::Chain;
+        if m.map_output.is_some() && virtual_map {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("map {}: cannot use output with map type {:?}", m.name, m.map_type),
+            ));
+        }
+
+        // only gdbm and json maps can be written, and the
+        // entry must be stored as-is, so no output mapping.
+        if m.writable {
+            if m.map_type != MapType::Gdbm && m.map_type != MapType::Json {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: map type {:?} cannot be writable", m.name, m.map_type),
+                ));
+            }
+            if m.map_output.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: cannot use output with a writable map", m.name),
+                ));
+            }
+        }
+
+        // virtual maps cannot be listed.
+        if m.enumerate && m.map_type != MapType::Gdbm && m.map_type != MapType::Json {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("map {}: map type {:?} cannot be enumerated", m.name, m.map_type),
+            ));
+        }
+
+        if m.map_type == MapType::Chain {
+            // Type Chain, maps must be set, and it's a virtual map.
+            if m.chain.len() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: maps not set", m.name),
+                ));
+            }
+            if m.map_file.is_some() || m.lua_function.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: cannot use file or lua_function with map type \"chain\"", m.name),
+                ));
+            }
+            if m.key.is_none() && m.keys.len() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: no key", m.name),
+                ));
+            }
+        } else if m.map_type == MapType::Lua {
+            // Type Lua, function must be set.
+            if m.lua_function.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: lua_function not set", m.name),
+                ));
+            }
+        } else if m.map_type == MapType::Gidlist {
+            // Type Gidlist, group_map must be set, and it's a virtual map.
+            if m.group_map.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: group_map not set", m.name),
+                ));
+            }
+            if m.map_file.is_some() || m.lua_function.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: cannot use file or lua_function with map type \"gidlist\"", m.name),
+                ));
+            }
+            if m.key.is_none() && m.keys.len() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: no key", m.name),
+                ));
+            }
+        } else {
+            // lua_function must not be set.
+            if m.lua_function.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: lua_function set, map_type must be \"lua\"", m.name),
+                ));
+            }
+
+            // Must have a key.
+            if m.key.is_none() && m.keys.len() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: no key", m.name),
+                ));
+            }
+
+            // Must have a filename.
+            if m.map_file.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: map file not set", m.name),
+                ));
+            }
+
+        }
+    }
+    Ok(mm)
+}
+
 // Read the TOML config into a config::Condig struct.
 pub fn read(toml_file: impl AsRef<Path>) -> io::Result<Config> {
     let buffer = std::fs::read_to_string(&toml_file)?;
 
+    // check the map definitions for unknown keys first, serde
+    // cannot do that for us because of the flattened submaps.
+    let main = Source { file: None, text: buffer, map_prefix: "map." };
+    let raw: toml::Value = toml::from_str(&main.text)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if let Some(maps) = raw.get("map").and_then(|m| m.as_table()) {
+        for (name, map) in maps {
+            if let Some(t) = map.as_table() {
+                check_map_keys(name, t, &main)?;
+            }
+        }
+    }
+
     // initial parse.
-    let mut config: Config = match toml::from_str(&buffer) {
+    let mut config: Config = match toml::from_str(&main.text) {
         Ok(v) => Ok(v),
         Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
     }?;
 
+    // where the domains, maps and auth sections were defined: an index in `sources`.
+    let mut sources = vec![main];
+    let mut domain_src = HashMap::new();
+    let mut map_src = HashMap::new();
+    let mut auth_src = HashMap::new();
+    for d in &config.domain {
+        define(&mut domain_src, "domain", &d.name, 0, &sources)?;
+    }
+    for name in config.map.keys() {
+        define(&mut map_src, "map", name, 0, &sources)?;
+    }
+    for name in config.auth.keys() {
+        define(&mut auth_src, "auth", name, 0, &sources)?;
+    }
+
     // see if "include_maps" is set- if so, read a separate map definition file.
     if let Some(ref extra) = config.include_maps {
         // relative to main config file.
-        let include_maps = match toml_file.as_ref().parent() {
-            Some(parent) => parent.join(Path::new(extra)),
-            None => PathBuf::from(extra),
-        };
+        let include_maps = abs_path(toml_file.as_ref(), extra);
         let buffer = std::fs::read_to_string(&include_maps)
             .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", include_maps, e)))?;
-        let maps: HashMap<String, MapOrMaps> = match toml::from_str(&buffer) {
-            Ok(v) => Ok(v),
-            Err(e) => {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("include_maps {:?}: {}", include_maps, e),
-                ))
-            },
-        }?;
+        let src = Source { file: Some(format!("{:?}", include_maps)), text: buffer, map_prefix: "" };
+        let invalid = |e: toml::de::Error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("include_maps {:?}: {}", include_maps, e),
+            )
+        };
+        let raw: toml::value::Table = toml::from_str(&src.text).map_err(invalid)?;
+        for (name, map) in &raw {
+            if let Some(t) = map.as_table() {
+                check_map_keys(name, t, &src)?;
+            }
+        }
+        let maps: HashMap<String, MapOrMaps> = toml::from_str(&src.text).map_err(invalid)?;
+        sources.push(src);
         // add to main config.
         for (name, map) in maps.into_iter() {
+            define(&mut map_src, "map", &name, sources.len() - 1, &sources)?;
             config.map.insert(name, map);
         }
     }
 
+    // "include" files can add domains, maps and auth sections.
+    let mut files = Vec::new();
+    for pattern in &config.include {
+        files.extend(include_files(&abs_path(toml_file.as_ref(), pattern))?);
+    }
+    for file in files {
+        let buffer = std::fs::read_to_string(&file)
+            .map_err(|e| io::Error::new(e.kind(), format!("include {:?}: {}", file, e)))?;
+        let src = Source { file: Some(format!("{:?}", file)), text: buffer, map_prefix: "map." };
+        let invalid = |e: toml::de::Error| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("include {:?}: {}", file, e))
+        };
+        let raw: toml::Value = toml::from_str(&src.text).map_err(invalid)?;
+        if let Some(maps) = raw.get("map").and_then(|m| m.as_table()) {
+            for (name, map) in maps {
+                if let Some(t) = map.as_table() {
+                    check_map_keys(name, t, &src)?;
+                }
+            }
+        }
+        let inc: Include = toml::from_str(&src.text).map_err(invalid)?;
+        sources.push(src);
+        let idx = sources.len() - 1;
+        for d in inc.domain.into_iter() {
+            define(&mut domain_src, "domain", &d.name, idx, &sources)?;
+            config.domain.push(d);
+        }
+        for (name, map) in inc.map.into_iter() {
+            define(&mut map_src, "map", &name, idx, &sources)?;
+            config.map.insert(name, map);
+        }
+        for (name, auth) in inc.auth.into_iter() {
+            define(&mut auth_src, "auth", &name, idx, &sources)?;
+            config.auth.insert(name, auth);
+        }
+    }
+    if config.domain.len() == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "config: no domains defined"));
+    }
+
+    // " (file line N)" for an error message, from the source where
+    // the domain, map or auth section was defined.
+    let at = |seen: &HashMap<String, usize>, name: &str, f: &dyn Fn(&Source) -> Option<usize>| {
+        match seen.get(name) {
+            Some(&idx) => sources[idx].at(f(&sources[idx])),
+            None => String::new(),
+        }
+    };
+    let map_at = |name: &str| at(&map_src, name, &|s| s.map_line(name, None));
+
+    // Environment variables and secrets from files.
+    resolve(&mut config.server.crt_file, expand_env, "server: crt_file".to_string())?;
+    resolve(&mut config.server.key_file, expand_env, "server: key_file".to_string())?;
+    config.server.cert_password = secret(&config.server.cert_password)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("config: server: cert_password: {}", e)))?;
+    for d in config.domain.iter_mut() {
+        resolve(&mut d.http_authtoken, secret, format!("domain {}: http_authtoken", d.name))?;
+        resolve(&mut d.admin_authtoken, secret, format!("domain {}: admin_authtoken", d.name))?;
+    }
+
     // Resolve non-absolute paths.
     for file in &config.server.securenets {
         config.server.securenets_.push(abs_path(toml_file.as_ref(), file));
@@ -229,135 +853,124 @@ pub fn read(toml_file: impl AsRef<Path>) -> io::Result<Config> {
         lua.script_ = abs_path(toml_file.as_ref(), &lua.script);
     }
 
-    // Build the `map_ `HashMap.
-    for (k, v) in config.map.iter() {
-        //
-        // there are 3 variants here:
-        //
-        // 1. simple map definition: [passwd] => MapOrMaps::Map( MapDef )
-        //
-        // 2. a map definition with the keyname included in the name.
-        //    There can be multiple definitions with the same basename.
-        //    E.g [passwd.username] and [passwd.uid] => MapOrMaps::Maps( HashMap<String, Map> )
-        //    The hashmap has two entries here, with keys "username" and "uid".
-        //
-        // 3. Like 2, but with a basemap definition.
-        //    E.g [passwd], [passwd.username], [passwd.uid].
-        //    This results in a single Map (MapOrMaps::Map), where the
-        //    passwd.username and passwd.uid maps can be found in the map.submaps member.
-        //
-        // We put all definitions with the same basename together in a Vec.
-        let mut mm = Vec::new();
-        match v {
-            MapOrMaps::Map(m) => {
-                if m.submaps.len() > 0 {
-                    // basemap with submaps.
-                    if m.key.is_some() || m.keys.len() > 0 || m.key_alias.len() > 0 {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("map {}: basemap cannot have a key", k),
-                        ));
-                    }
-                    for (key, submap) in m.submaps.iter() {
-                        mm.push(map_inherit(key, submap, m));
-                    }
-                } else {
-                    // single map.
-                    mm.push(m.to_owned());
-                }
-            },
-            MapOrMaps::Maps(m) => {
-                for (key, map) in m.iter() {
-                    let mut newmap = map.clone();
-                    if newmap.key.is_none() {
-                        newmap.key = Some(key.to_owned());
-                    }
-                    mm.push(newmap);
+    // Datalog can be just a filename, or a section.
+    config.server.datalog_ = match config.server.datalog {
+        Some(FileOrDatalog::File(ref file)) => {
+            Some(Datalog {
+                file: Some(file.clone()),
+                ..Datalog::default()
+            })
+        },
+        Some(FileOrDatalog::Datalog(ref d)) => Some(d.clone()),
+        None => None,
+    };
+    if let Some(ref d) = config.server.datalog_ {
+        let n = [&d.file, &d.unix, &d.tcp, &d.udp].iter().filter(|x| x.is_some()).count();
+        if n != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "config: datalog: set exactly one of file, unix, tcp, udp",
+            ));
+        }
+        if d.facility.is_some() && d.format != DatalogFormat::Syslog {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "config: datalog: facility can only be used with format \"syslog\"",
+            ));
+        }
+    }
+
+    // With chroot, all files that are used after startup must be inside it.
+    if let Some(ref dir) = config.server.chroot {
+        if !Path::new(dir).is_absolute() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "config: chroot: must be an absolute path"));
+        }
+        let mut files = config.domain.iter().map(|d| (format!("domain {}: db_dir", d.name), Some(&d.db_dir))).collect::<Vec<_>>();
+        files.push(("server: audit_log".to_string(), config.server.audit_log.as_ref()));
+        if let Some(ref d) = config.server.datalog_ {
+            files.push(("server: datalog: file".to_string(), d.file.as_ref()));
+            files.push(("server: datalog: unix".to_string(), d.unix.as_ref()));
+        }
+        for (what, file) in files {
+            if let Some(file) = file {
+                if !Path::new(file).starts_with(dir) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("config: {}: {} is not inside chroot {}", what, file, dir),
+                    ));
                 }
-            },
+            }
         }
+    }
 
-        // Now walk over all maps and do some basic validity checks.
-        for m in &mut mm {
-            m.name = k.to_string();
+    // Build the `map_ `HashMap.
+    for (k, v) in config.map.iter() {
+        let mm = build_map(k, v).map_err(|e| io::Error::new(e.kind(), format!("{}{}", e, map_at(k))))?;
+        config.map_.insert(k.to_string(), mm);
+    }
 
-            // Map type must be set.
-            if m.map_type == MapType::None {
+    // The group map of a gidlist map must be a gdbm map in group
+    // format, or a json map. The passwd map must be keyed on username.
+    for m in config.map_.values().flatten().filter(|m| m.map_type == MapType::Gidlist) {
+        let group_map = m.group_map.as_ref().unwrap();
+        if config.group_map_file(group_map).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "map {}: group_map {}: not a json map or gdbm map in group format{}",
+                    m.name,
+                    group_map,
+                    map_at(&m.name)
+                ),
+            ));
+        }
+        if let Some(ref passwd_map) = m.passwd_map {
+            if config.find_map(passwd_map, "username").is_none() {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    format!("map {}: map_type not set", m.name),
+                    format!(
+                        "map {}: passwd_map {}: not found or no key \"username\"{}",
+                        m.name,
+                        passwd_map,
+                        map_at(&m.name)
+                    ),
                 ));
             }
+        }
+    }
 
-            // format = "..." only works with MapType::Gdbm at this time.
-            if m.map_type != MapType::Gdbm && m.map_format.is_some() {
+    // The maps in a chain must exist, and a chain cannot contain itself.
+    for m in config.map_.values().flatten().filter(|m| m.map_type == MapType::Chain) {
+        for name in &m.chain {
+            if !config.map_.contains_key(name) {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    format!("map {}: cannot use format with map type {:?}", m.name, m.map_type),
+                    format!("map {}: maps: map {} not defined{}", m.name, name, map_at(&m.name)),
                 ));
             }
-
-            if m.map_type == MapType::Lua {
-                // Type Lua, function must be set.
-                if m.lua_function.is_none() {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("map {}: lua_function not set", m.name),
-                    ));
-                }
-            } else {
-                // lua_function must not be set.
-                if m.lua_function.is_some() {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("map {}: lua_function set, map_type must be \"lua\"", m.name),
-                    ));
-                }
-
-                // Must have a key.
-                if m.key.is_none() && m.keys.len() == 0 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("map {}: no key", m.name),
-                    ));
-                }
-
-                // Must have a filename.
-                if m.map_file.is_none() {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("map {}: map file not set", m.name),
-                    ));
-                }
-
-                // output mapping doesn't work (yet) with all formats.
-                if m.map_output.is_some() {
-                    match m.map_format {
-                        Some(Format::Json) |
-                        Some(Format::Passwd) |
-                        Some(Format::Group) |
-                        Some(Format::Adjunct) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                format!("map {}: cannot use output with format {:?}", m.name, m.map_format),
-                            ));
-                        },
-                        _ => {},
-                    }
-                }
-            }
         }
-        config.map_.insert(k.to_string(), mm);
+        if config.chain_loops(&m.name, &m.chain, 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("map {}: maps: chain refers back to itself{}", m.name, map_at(&m.name)),
+            ));
+        }
     }
 
     // Check domains for validity
     for d in &config.domain {
         if let Some(ref auth_name) = d.auth {
+            let auth_at = || at(&auth_src, auth_name, &|s| s.line(&format!("auth.{}", auth_name), None));
             let auth = match config.auth.get(auth_name) {
                 None => {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidData,
-                        format!("config: domain {}: auth {} not defined", d.name, auth_name),
+                        format!(
+                            "config: domain {}: auth {} not defined{}",
+                            d.name,
+                            auth_name,
+                            at(&domain_src, &d.name, &|s| s.domain_line(&d.name, auth_name))
+                        ),
                     ));
                 },
                 Some(a) => a,
@@ -366,17 +979,106 @@ pub fn read(toml_file: impl AsRef<Path>) -> io::Result<Config> {
                 if auth.key.is_none() {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidData,
-                        format!("config: auth {}: 'key' not set", auth_name),
+                        format!("config: auth {}: 'key' not set{}", auth_name, auth_at()),
                     ));
                 }
                 if auth.map.is_none() {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidData,
-                        format!("config: auth {}: 'map' not set", auth_name),
+                        format!("config: auth {}: 'map' not set{}", auth_name, auth_at()),
                     ));
                 }
             }
         }
+        if let Some(ref url) = d.replicate_from {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "config: domain {}: replicate_from must be a http(s) url{}",
+                        d.name,
+                        at(&domain_src, &d.name, &|s| s.domain_line(&d.name, url))
+                    ),
+                ));
+            }
+            if d.admin_authschema.is_none() || d.admin_authtoken.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("config: domain {}: replicate_from needs admin_authschema and admin_authtoken", d.name),
+                ));
+            }
+        }
+    }
+
+    // The maps of a domain must be defined.
+    for d in &config.domain {
+        for name in &d.maps {
+            if !config.map_.contains_key(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "config: domain {}: map {} not defined{}",
+                        d.name,
+                        name,
+                        at(&domain_src, &d.name, &|s| s.domain_line(&d.name, name))
+                    ),
+                ));
+            }
+        }
+    }
+
+    // The map and key of an auth section must resolve to a map.
+    for (name, auth) in &config.auth {
+        if let (Some(map), Some(key)) = (auth.map.as_ref(), auth.key.as_ref()) {
+            if config.find_map(map, key).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "config: auth {}: map {} with key {} not found{}",
+                        name,
+                        map,
+                        key,
+                        at(&auth_src, name, &|s| s.line(&format!("auth.{}", name), Some("map")))
+                    ),
+                ));
+            }
+        }
+    }
+
+    // A key alias must point to a key of its own map definition, and it
+    // cannot be a key or an alias in another definition of the same map.
+    let alias_at = |name: &str, section: &str| {
+        let src = &sources[*map_src.get(name)?];
+        src.map_line(section, Some("key_alias")).map(|n| src.at(Some(n)))
+    };
+    for (name, maps) in &config.map_ {
+        let keys = maps.iter().flat_map(|m| m.key.iter().chain(m.keys.iter())).collect::<Vec<_>>();
+        let mut seen = Vec::new();
+        for m in maps {
+            for (alias, target) in &m.key_alias {
+                let msg = if keys.contains(&alias) {
+                    "is also a key".to_string()
+                } else if seen.contains(&alias) {
+                    "used in more than one definition of this map".to_string()
+                } else if !m.key.iter().chain(m.keys.iter()).any(|k| k == target) {
+                    format!("{} is not a key of this map", target)
+                } else {
+                    seen.push(alias);
+                    continue;
+                };
+                // the alias is either in [map.name.key] or in [map.name].
+                let line = m
+                    .key
+                    .as_ref()
+                    .and_then(|k| alias_at(name, &format!("{}.{}", name, k)))
+                    .or_else(|| alias_at(name, name))
+                    .unwrap_or_default();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("map {}: key_alias {}: {}{}", name, alias, msg, line),
+                ));
+            }
+        }
     }
 
     // Check if TLS settings are valid.
@@ -428,6 +1130,36 @@ impl Config {
         None
     }
 
+    // see if a chain map refers to `name`, directly or indirectly.
+    fn chain_loops(&self, name: &str, chain: &[String], depth: u32) -> bool {
+        if depth > MAX_CHAIN_DEPTH {
+            return true;
+        }
+        for member in chain {
+            if member == name {
+                return true;
+            }
+            let maps = self.map_.get(member).map(|v| v.as_slice()).unwrap_or(&[]);
+            for m in maps.iter().filter(|m| m.map_type == MapType::Chain) {
+                if self.chain_loops(name, &m.chain, depth + 1) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Find the map definition with the file to index for a gidlist map.
+    pub fn group_map_file(&self, mapname: &str) -> Option<&Map> {
+        self.map_.get(mapname)?.iter().find(|m| {
+            match m.map_type {
+                MapType::Gdbm => matches!(m.map_format, Some(Format::Group)),
+                MapType::Json => true,
+                _ => false,
+            }
+        })
+    }
+
     /// Like find_map, but map must be in the allowed list for the domain
     pub fn find_allowed_map<'b, 'a: 'b>(
         &'a self,
@@ -495,3 +1227,111 @@ pub fn read_securenets(file: impl AsRef<Path>, iplist: &mut IpList) -> io::Resul
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_check_key() {
+        let m: Map = toml::from_str(r#"
+            key = "username"
+            key_lowercase = true
+            key_trim = true
+            key_max_length = 8
+            key_regex = "[a-z][a-z0-9]*"
+        "#).unwrap();
+        assert_eq!(m.check_key(" Alice ").unwrap(), "alice");
+        assert!(m.check_key("alice-b").is_err());
+        assert!(m.check_key("verylongname").is_err());
+        assert!(m.check_key("").is_err());
+
+        let m: Map = toml::from_str(r#"
+            key = "uid"
+            key_numeric = true
+        "#).unwrap();
+        assert_eq!(m.check_key("1000").unwrap(), "1000");
+        assert!(m.check_key(" 1000").is_err());
+        assert!(m.check_key("-1").is_err());
+    }
+
+    #[test]
+    fn t_cross_refs() {
+        let file = std::env::temp_dir().join(format!("webnis-config-{}.toml", std::process::id()));
+        let base = "[server]\nlisten = \"127.0.0.1:3245\"\n\
+                    [[domain]]\nname = \"d\"\ndb_dir = \"/tmp\"\nmaps = [ \"passwd\" ]\n\
+                    [map.passwd]\ntype = \"json\"\nkeys = [ \"username\", \"uid\" ]\nfile = \"passwd.json\"\n";
+        let read_with = |extra: &str| {
+            std::fs::write(&file, format!("{}{}", base, extra)).unwrap();
+            read(&file).map_err(|e| e.to_string())
+        };
+        assert!(read_with("").is_ok());
+        assert!(read_with("key_alias = { user = \"username\" }\n").is_ok());
+        assert_eq!(read_with("fiel = \"x\"\n").unwrap_err(), "map passwd: unknown key \"fiel\" (line 11)");
+        assert_eq!(
+            read_with("key_alias = { uid = \"username\" }\n").unwrap_err(),
+            "map passwd: key_alias uid: is also a key (line 11)"
+        );
+        assert_eq!(
+            read_with("[auth.a]\nmap = \"passwd\"\nkey = \"name\"\n").unwrap_err(),
+            "config: auth a: map passwd with key name not found (line 12)"
+        );
+        std::env::set_var("WEBNIS_T_DIR", std::env::temp_dir());
+        let token = std::env::temp_dir().join(format!("webnis-token-{}", std::process::id()));
+        std::fs::write(&token, "sekrit\n").unwrap();
+        let config = read_with(&format!(
+            "[[domain]]\nname = \"e\"\ndb_dir = \"${{WEBNIS_T_DIR}}\"\nmaps = []\n\
+             http_authtoken = \"@${{WEBNIS_T_DIR}}/webnis-token-{}\"\nadmin_authtoken = \"file:{}\"\n",
+            std::process::id(), token.to_str().unwrap()
+        )).unwrap();
+        assert_eq!(config.domain[1].http_authtoken.as_ref().unwrap(), "sekrit");
+        assert_eq!(config.domain[1].admin_authtoken.as_ref().unwrap(), "sekrit");
+        assert!(read_with("[[domain]]\nname = \"e\"\ndb_dir = \"/\"\nmaps = []\nhttp_authtoken = \"${WEBNIS_T_UNSET}\"\n")
+            .unwrap_err()
+            .contains("environment variable WEBNIS_T_UNSET not set"));
+        std::fs::remove_file(&token).unwrap();
+
+        std::fs::write(&file, base.replace("[ \"passwd\" ]", "[ \"passwd\", \"group\" ]")).unwrap();
+        assert_eq!(read(&file).unwrap_err().to_string(), "config: domain d: map group not defined (line 6)");
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn t_include() {
+        let dir = std::env::temp_dir().join(format!("webnis-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let file = dir.join("webnis-server.toml");
+        std::fs::write(&file, "include = [ \"conf.d/*.toml\" ]\n[server]\nlisten = \"127.0.0.1:3245\"\n").unwrap();
+        std::fs::write(dir.join("conf.d/a.toml"),
+            "[[domain]]\nname = \"a\"\ndb_dir = \"/tmp\"\nmaps = [ \"passwd\" ]\nauth = \"a\"\n\
+             [auth.a]\nmap = \"passwd\"\nkey = \"username\"\n").unwrap();
+        std::fs::write(dir.join("conf.d/b.toml"),
+            "[map.passwd.username]\ntype = \"json\"\nfile = \"passwd.json\"\n").unwrap();
+        std::fs::write(dir.join("conf.d/.hidden.toml"), "garbage").unwrap();
+        std::fs::write(dir.join("conf.d/c.toml.orig"), "garbage").unwrap();
+        let config = read(&file).unwrap();
+        assert_eq!(config.domain[0].name, "a");
+        assert!(config.find_map("passwd", "username").is_some());
+
+        let b = dir.join("conf.d/b.toml");
+        std::fs::write(dir.join("conf.d/c.toml"), "[[domain]]\nname = \"a\"\ndb_dir = \"/tmp\"\nmaps = []\n").unwrap();
+        assert_eq!(
+            read(&file).unwrap_err().to_string(),
+            format!("config: domain a: defined in both {:?} and {:?}", dir.join("conf.d/a.toml"), dir.join("conf.d/c.toml"))
+        );
+        std::fs::write(dir.join("conf.d/c.toml"), "[map.passwd.username]\ntype = \"json\"\n").unwrap();
+        assert_eq!(
+            read(&file).unwrap_err().to_string(),
+            format!("config: map passwd: defined in both {:?} and {:?}", b, dir.join("conf.d/c.toml"))
+        );
+        std::fs::remove_file(dir.join("conf.d/c.toml")).unwrap();
+        std::fs::write(&b, "[map.passwd.username]\ntype = \"json\"\n").unwrap();
+        assert_eq!(
+            read(&file).unwrap_err().to_string(),
+            format!("map passwd: map file not set ({:?} line 1)", b)
+        );
+        std::fs::write(&b, "[server]\n").unwrap();
+        assert!(read(&file).unwrap_err().to_string().starts_with(&format!("include {:?}: unknown field `server`", b)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
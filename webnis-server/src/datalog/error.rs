@@ -0,0 +1,165 @@
+// Authentication error codes, as used in the datalog.
+//
+// The codes used to be a hardcoded enum copied from the XS4ALL Radius
+// code. They are now a table, which can be replaced from the configuration
+// file ([[server.datalog.error]]). The builtin table is the legacy XS4ALL
+// one, so that existing Lua scripts keep working.
+//
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+
+use crate::config;
+
+// One entry in the error code table.
+struct ErrorCode {
+    // name, as exported to Lua in the `error` table.
+    name:       String,
+    // message that is sent back to the client (radius attr 18).
+    reply:      String,
+    // message that is logged (radius attr 24).
+    message:    String,
+}
+
+// name, reply, log message.
+static BUILTIN: &[(&str, &str, &str)] = &[
+    ("RQ_USERNAME",         "No username in request",               "no username in request"),
+    ("RQ_PASSWD",           "No password in request",               "no password in request"),
+    ("RQ_CIRCUIT",          "No circuit in request",                "no circuit in request"),
+    ("RQ_IMSI",             "No IMSI in request",                   "No IMSI in request"),
+    ("RQ_CALLERID",         "No caller-id in request",              "no caller-id in request"),
+    ("RQ_CHAP",             "CHAP authentication not supported",    "CHAP authentication not supported"),
+    ("BAD_USERNAME",        "Login incorrect",                      "user unknown"),
+    ("BAD_PASSWD",          "Login incorrect",                      "password incorrect"),
+    ("UC_USERNAME",         "Uppercase letters in username",        "uppercase letters in username"),
+    ("NO_IPADDR",           "No IP address found in database",      "no IP address found in database"),
+    ("NO_CIRCUIT",          "Circuit not found in database",        "circuit not found in database"),
+    ("WRONG_NRP",           "Login from invalid NRP",               "login from invalid NRP"),
+    ("WRONG_ENCAPS",        "Wrong encapsulation",                  "wrong encapsulation"),
+    ("DIALIN_BLACKLIST",    "Login incorrect",                      "blacklisted caller-id"),
+    ("XSH_ABUSE",           "Login incorrect",                      "xsh-abuse shell"),
+    ("XSH_NETIQUETTE",      "Login incorrect",                      "xsh-netiquette shell"),
+    ("XSH_DELETED",         "Login incorrect",                      "xsh-deleted shell"),
+    ("XSH_NOPAY",           "Login incorrect",                      "xsh-nopay shell"),
+    ("XSH_GENERIC",         "Login incorrect",                      "xsh shell"),
+    ("INV_SHELL",           "Login incorrect",                      "invalid shell"),
+    ("INV_VANGPAGINA",      "Login incorrect",                      "invalid vangpagina"),
+    ("NO_SHELLHOST",        "Login incorrect",                      "Shell host does not resolve"),
+    ("NO_VLAN",             "VLAN not found in database",           "VLAN not found in database"),
+    ("WRONG_APN",           "Login from invalid APN",               "Login from invalid APN"),
+    ("NO_OTPHOST",          "OTP server unavailable",               "OTP host does not resolve"),
+    ("NO_OTPHOST_SECRET",   "OTP server unavailable",               "OTP host not in radiushosts"),
+    ("OTP_SERVER_TIMEOUT",  "OTP server unavailable",               "OTP server timeout"),
+    ("DES_PASSWD",          "Login incorrect",                      "password invalid (DES)"),
+    ("GENERIC",             "Login incorrect",                      "login incorrect"),
+];
+
+// The name of the catch-all error. Always present in the table.
+const GENERIC: &str = "GENERIC";
+
+lazy_static! {
+    static ref ERROR_CODES: RwLock<Arc<Vec<ErrorCode>>> = RwLock::new(Arc::new(builtin()));
+}
+
+fn builtin() -> Vec<ErrorCode> {
+    BUILTIN
+        .iter()
+        .map(|&(name, reply, message)| {
+            ErrorCode {
+                name:       name.to_string(),
+                reply:      reply.to_string(),
+                message:    message.to_string(),
+            }
+        })
+        .collect()
+}
+
+fn codes() -> Arc<Vec<ErrorCode>> {
+    ERROR_CODES.read().unwrap().clone()
+}
+
+/// Replace the builtin error code table with the one from the config.
+/// If the configured table is empty, the builtin table is kept.
+pub(crate) fn set_error_codes(errors: &[config::DatalogError]) {
+    if errors.is_empty() {
+        return;
+    }
+    let mut table = errors
+        .iter()
+        .map(|e| {
+            let reply = e.reply.clone().unwrap_or("Login incorrect".to_string());
+            ErrorCode {
+                name:       e.name.clone(),
+                message:    e.message.clone().unwrap_or_else(|| reply.to_lowercase()),
+                reply,
+            }
+        })
+        .collect::<Vec<_>>();
+    if !table.iter().any(|e| e.name == GENERIC) {
+        table.push(ErrorCode {
+            name:       GENERIC.to_string(),
+            reply:      "Login incorrect".to_string(),
+            message:    "login incorrect".to_string(),
+        });
+    }
+    *ERROR_CODES.write().unwrap() = Arc::new(table);
+}
+
+/// An authentication error. This is an index into the error code table.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Error(usize);
+
+impl Error {
+    /// The catch-all error.
+    pub fn generic() -> Error {
+        let codes = codes();
+        Error(codes.iter().position(|e| e.name == GENERIC).unwrap_or(codes.len() - 1))
+    }
+
+    /// Numeric value, as exported to Lua.
+    pub fn code(&self) -> usize {
+        self.0
+    }
+
+    /// Symbolic name.
+    pub fn name(&self) -> String {
+        codes()[self.0].name.clone()
+    }
+
+    /// Message that is sent back to the client.
+    pub fn as_reply(&self) -> String {
+        codes()[self.0].reply.clone()
+    }
+
+    /// Message that is logged.
+    pub fn as_log_message(&self) -> String {
+        codes()[self.0].message.clone()
+    }
+}
+
+// integer -> error. Out of range maps to the generic error.
+impl From<usize> for Error {
+    fn from(num: usize) -> Error {
+        if num < codes().len() {
+            Error(num)
+        } else {
+            Error::generic()
+        }
+    }
+}
+
+/// Iterate over all entries in the table, returning the
+/// error, the usize value, and the name.
+pub(crate) fn error_iter() -> impl Iterator<Item=(Error, usize, String)> {
+    let codes = codes();
+    let mut count = 0;
+    std::iter::from_fn(move || {
+        if count < codes.len() {
+            let c = count;
+            count += 1;
+            Some((Error(c), c, codes[c].name.clone()))
+        } else {
+            None
+        }
+    })
+}
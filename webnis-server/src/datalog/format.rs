@@ -0,0 +1,361 @@
+// Datalog output formats.
+//
+// A format turns a `Datalog` record into a single blob of text,
+// without a trailing newline. The sink decides on the framing.
+//
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+
+use super::Datalog;
+use crate::errors::WnError;
+
+/// Format of a datalog record.
+pub(crate) trait LogFormat: Send {
+    fn format(&self, item: &Datalog) -> String;
+}
+
+/// Format type, as set in the config (`format = "..."`).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DatalogFormat {
+    /// Legacy XS4ALL two-line radius-like format.
+    #[default]
+    Datalog,
+    /// One JSON object per record.
+    Json,
+    /// RFC5424 syslog message.
+    Syslog,
+}
+
+impl FromStr for DatalogFormat {
+    type Err = WnError;
+
+    fn from_str(s: &str) -> Result<DatalogFormat, WnError> {
+        let f = match s {
+            "datalog" => DatalogFormat::Datalog,
+            "json" => DatalogFormat::Json,
+            "syslog" => DatalogFormat::Syslog,
+            _ => return Err(WnError::UnknownFormat),
+        };
+        Ok(f)
+    }
+}
+
+// Serde helper
+pub fn deserialize_datalog_format<'de, D>(deserializer: D) -> Result<DatalogFormat, D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    DatalogFormat::from_str(&s).map_err(serde::de::Error::custom)
+}
+
+// This will never fail, but _if_ it does, it simply
+// puts in the time/date of Towelday 2019.
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| (d.as_secs(), d.subsec_millis()))
+        .unwrap_or((1558735200, 0))
+}
+
+fn ip_to_string(ip: &IpAddr) -> String {
+    match *ip {
+        IpAddr::V4(ref addr) => addr.to_string(),
+        IpAddr::V6(ref addr) => addr.to_string(),
+    }
+}
+
+//
+// The legacy XS4ALL "datalog" format.
+//
+pub(crate) struct XsDatalog;
+
+// 25:XS4/401:<data>
+// "data" is extra escaped. Well, escaped ... troublesome chars replaced with a dot.
+fn attr_xs401(attr: usize, msg: impl AsRef<str>) -> String {
+    let msg = msg.as_ref().replace([':', ';', '"', ','], ".");
+    attr_string(attr, format!("XS4/401:{}", msg))
+}
+
+// sanitize a string.
+fn sanitize(s: impl AsRef<str>) -> String {
+    const C_ESC: &str = "'\"`()[]\\,";
+    let s = s.as_ref();
+    let mut r = String::with_capacity(s.len() * 2);
+    for c in s.chars() {
+        let cx = c as u32;
+        if cx < 256 {
+            if !(32..=126).contains(&cx) || C_ESC.contains(c) {
+                r.push_str(&format!("\\{:03o}", cx));
+            } else {
+                r.push(c);
+            }
+        } else {
+            r.extend(c.escape_unicode());
+        }
+    }
+    r
+}
+
+// 1:"username"
+fn attr_string(attr: usize, msg: impl AsRef<str>) -> String {
+    format!("{}:\"{}\"", attr, sanitize(msg))
+}
+
+// 4:194.109.6.66 or 15:8
+fn attr_item(attr: usize, item: impl std::fmt::Display) -> String {
+    format!("{}:{}", attr, item)
+}
+
+impl LogFormat for XsDatalog {
+
+    // Generate 2 lines in "datalog" format.
+    fn format(&self, item: &Datalog) -> String {
+        let mut request = Vec::new();
+        let mut reply = Vec::new();
+
+        let (time, _) = unix_time(item.time);
+        let src_ip = ip_to_string(&item.src_ip);
+
+        // request fields.
+        request.push(time.to_string());
+        request.push(src_ip.clone());
+        request.push("1".to_string());
+        request.push(attr_string(1, &item.username));
+        request.push("2:\"\"".to_string());
+        request.push(attr_item(4, &src_ip));
+        if let Some(ref s) = item.callingsystem {
+            request.push(attr_string(32, s));
+        }
+        if let Some(ref ip) = item.clientip {
+            request.push(attr_string(31, ip_to_string(ip)));
+        }
+
+        // reply fields.
+        reply.push(time.to_string());
+        reply.push(src_ip);
+        let reply_code = match item.status {
+            Ok(_) => 2,
+            Err(_) => 3,
+        };
+        reply.push(reply_code.to_string());
+        if let Some(ref account) = item.account {
+            if account != &item.username {
+                reply.push(attr_string(1, account));
+                reply.push(attr_xs401(25, account));
+            }
+        }
+        // if no attrs pushed, add an empty attr (i.e. a comma).
+        if reply.len() == 3 {
+            reply.push("".to_string());
+        }
+
+        if let Err(ref e) = item.status {
+            match item.message {
+                Some(ref m) => reply.push(attr_string(24, m)),
+                None => reply.push(attr_string(24, e.as_log_message())),
+            }
+            reply.push(attr_string(18, e.as_reply()));
+        }
+
+        // we're done.
+        format!("{}\n{}", request.as_slice().join(","), reply.as_slice().join(","))
+    }
+}
+
+//
+// JSON lines.
+//
+pub(crate) struct JsonLines;
+
+impl LogFormat for JsonLines {
+    fn format(&self, item: &Datalog) -> String {
+        let (time, millis) = unix_time(item.time);
+        let mut obj = serde_json::Map::new();
+        obj.insert("time".into(), json!(time as f64 + millis as f64 / 1000.0));
+        obj.insert("src_ip".into(), json!(ip_to_string(&item.src_ip)));
+        obj.insert("username".into(), json!(item.username));
+        if let Some(ref account) = item.account {
+            obj.insert("account".into(), json!(account));
+        }
+        if let Some(ref ip) = item.clientip {
+            obj.insert("clientip".into(), json!(ip_to_string(ip)));
+        }
+        if let Some(ref s) = item.callingsystem {
+            obj.insert("callingsystem".into(), json!(s));
+        }
+        match item.status {
+            Ok(_) => {
+                obj.insert("status".into(), json!("accept"));
+            },
+            Err(ref e) => {
+                obj.insert("status".into(), json!("reject"));
+                obj.insert("error".into(), json!(e.name()));
+                obj.insert("code".into(), json!(e.code()));
+                obj.insert("reply".into(), json!(e.as_reply()));
+                let message = item.message.clone().unwrap_or_else(|| e.as_log_message());
+                obj.insert("message".into(), json!(message));
+            },
+        }
+        serde_json::Value::Object(obj).to_string()
+    }
+}
+
+//
+// RFC5424 syslog.
+//
+pub(crate) struct Syslog {
+    facility:   u32,
+    hostname:   String,
+    pid:        u32,
+}
+
+// Private enterprise number 32473 is reserved for documentation (RFC5612).
+const SD_ID: &str = "webnis@32473";
+
+const LOG_INFO: u32 = 6;
+const LOG_NOTICE: u32 = 5;
+
+impl Syslog {
+    pub fn new(facility: Option<&str>) -> Result<Syslog, WnError> {
+        let facility = match facility {
+            Some(f) => syslog::Facility::from_str(f).map_err(|_| WnError::Other)?,
+            None => syslog::Facility::LOG_AUTH,
+        };
+        Ok(Syslog {
+            facility:   facility as u32,
+            hostname:   hostname(),
+            pid:        std::process::id(),
+        })
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "-".to_string();
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(s) if !s.is_empty() => s.to_string(),
+        _ => "-".to_string(),
+    }
+}
+
+// RFC3339 timestamp, UTC, with milliseconds.
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let (secs, millis) = unix_time(time);
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // days since the epoch to civil date (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, (rem / 60) % 60, rem % 60, millis
+    )
+}
+
+// escape a SD-PARAM value.
+fn sd_escape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            r.push('\\');
+        }
+        r.push(c);
+    }
+    r
+}
+
+impl LogFormat for Syslog {
+    fn format(&self, item: &Datalog) -> String {
+        let mut sd = Vec::new();
+        sd.push(format!("src_ip=\"{}\"", ip_to_string(&item.src_ip)));
+        sd.push(format!("username=\"{}\"", sd_escape(&item.username)));
+        if let Some(ref account) = item.account {
+            sd.push(format!("account=\"{}\"", sd_escape(account)));
+        }
+        if let Some(ref ip) = item.clientip {
+            sd.push(format!("clientip=\"{}\"", ip_to_string(ip)));
+        }
+        if let Some(ref s) = item.callingsystem {
+            sd.push(format!("callingsystem=\"{}\"", sd_escape(s)));
+        }
+
+        let (severity, msg) = match item.status {
+            Ok(_) => {
+                sd.push("status=\"accept\"".to_string());
+                (LOG_INFO, format!("accept {}", item.username))
+            },
+            Err(ref e) => {
+                sd.push("status=\"reject\"".to_string());
+                sd.push(format!("error=\"{}\"", sd_escape(&e.name())));
+                let message = item.message.clone().unwrap_or_else(|| e.as_log_message());
+                (LOG_NOTICE, format!("reject {}: {}", item.username, message))
+            },
+        };
+
+        format!(
+            "<{}>1 {} {} webnis-server {} auth [{} {}] {}",
+            self.facility | severity,
+            rfc3339(item.time),
+            self.hostname,
+            self.pid,
+            SD_ID,
+            sd.join(" "),
+            msg
+        )
+    }
+}
+
+/// Build a formatter from the config.
+pub(crate) fn new(format: &DatalogFormat, facility: Option<&str>) -> Result<Box<dyn LogFormat>, WnError> {
+    let f: Box<dyn LogFormat> = match format {
+        DatalogFormat::Datalog => Box::new(XsDatalog),
+        DatalogFormat::Json => Box::new(JsonLines),
+        DatalogFormat::Syslog => Box::new(Syslog::new(facility)?),
+    };
+    Ok(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn t_rfc3339() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_558_735_200_123);
+        assert_eq!(rfc3339(t), "2019-05-24T22:00:00.123Z");
+        let t = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(rfc3339(t), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn t_datalog() {
+        let item = Datalog {
+            time:       UNIX_EPOCH + Duration::from_secs(1558735200),
+            src_ip:     [10, 1, 2, 3].into(),
+            username:   "truus".to_string(),
+            status:     Err(7.into()),
+            ..Datalog::default()
+        };
+        assert_eq!(
+            XsDatalog.format(&item),
+            "1558735200,10.1.2.3,1,1:\"truus\",2:\"\",4:10.1.2.3\n\
+             1558735200,10.1.2.3,3,,24:\"password incorrect\",18:\"Login incorrect\""
+        );
+    }
+}
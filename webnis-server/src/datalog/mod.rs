@@ -0,0 +1,200 @@
+// This started out as a legacy logging interface internal to XS4ALL.
+//
+// Authentication results are logged as `Datalog` records. A logging
+// thread formats the records (see `format.rs`) and sends them to
+// a sink (see `sink.rs`): a file, a unix datagram socket, or a
+// TCP/UDP collector.
+//
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
+
+use crossfire::mpsc::bounded_tx_future_rx_blocking as channel;
+use crossfire::mpsc::{RxBlocking, TxFuture, SharedSenderFRecvB};
+
+use lazy_static::lazy_static;
+use tokio::{task, time, time::Duration};
+
+use crate::config;
+
+mod error;
+mod format;
+mod sink;
+
+pub(crate) use self::error::{error_iter, set_error_codes, Error};
+pub use self::format::{deserialize_datalog_format, DatalogFormat};
+pub(crate) use self::format::rfc3339;
+use self::format::LogFormat;
+use self::sink::LogSink;
+
+// LogSender, send data to the logging thread.
+struct LogSender {
+    tx:         TxFuture<LogItem, SharedSenderFRecvB>,
+    wait:       std::sync::mpsc::Receiver<()>,
+}
+
+// Producer side of the log channel.
+lazy_static! {
+    static ref LOGGER: Mutex<Option<LogSender>> = Mutex::new(None);
+}
+
+/// Returned by datalog::init().
+pub(crate) struct LogGuard;
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        // close the channel to the logging thread, then wait for it to exit.
+        let wait = {
+            let mut guard = LOGGER.lock().unwrap();
+            let logger = guard.take().unwrap();
+            let _ = logger.tx.send_blocking(LogItem::Quit);
+            logger.wait
+        };
+        let _ = wait.recv();
+    }
+}
+
+/// Log a `Datalog` item. Synchronous and thus blocking.
+/// panics if datalog::init() has not yet been called.
+pub(crate) fn log_sync(item: Datalog) {
+    let mut guard = LOGGER.lock().unwrap();
+    let logger = guard.as_mut().unwrap();
+    //let _ = futures::executor::block_on(async move {
+    //    logger.tx.send(LogItem::Item(item)).await
+    //});
+    let _ = logger.tx.send_blocking(LogItem::Item(item));
+}
+
+/// Log a `Datalog` item. Asynchronous.
+/// panics if datalog::init() has not yet been called.
+#[allow(dead_code)]
+pub(crate) async fn log_async(item: Datalog) -> io::Result<()> {
+    let logger = {
+        let mut guard = LOGGER.lock().unwrap();
+        guard.as_mut().unwrap().tx.clone()
+    };
+    logger.send(LogItem::Item(item)).await.map_err(io::Error::other)
+}
+
+/// Initialize the datalog logging system.
+///
+/// Returns a guard handle. When the handle is dropped, the logging thread
+/// will process all remaining datalog items in the channel and then exit.
+///
+/// The error codes are set with `set_error_codes`, before Lua is initialized.
+pub(crate) async fn init(cfg: &config::Datalog) -> io::Result<LogGuard> {
+    LogWriter::init(cfg).await?;
+    Ok(LogGuard)
+}
+
+// LogWriter, receives log messages, formats them, and sends them to the sink.
+struct LogWriter {
+    format: Box<dyn LogFormat>,
+    sink:   Box<dyn LogSink>,
+    recv:   RxBlocking<LogItem, SharedSenderFRecvB>,
+    wait:   std::sync::mpsc::SyncSender<()>,
+}
+
+// used by LogWriter::run().
+enum LogItem {
+    Item(Datalog),
+    Tick,
+    Quit,
+}
+
+impl LogWriter {
+
+    // Initialize logwriter. If the datalog sink cannot be
+    // opened, return an error. Otherwise spawn a background
+    // thread to process log messages and return the thread handle.
+    async fn init(cfg: &config::Datalog) -> io::Result<thread::JoinHandle<()>> {
+        let format = format::new(&cfg.format, cfg.facility.as_deref())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let sink = sink::new(cfg)?;
+
+        let (log_tx, log_rx) = channel(1);
+        let (wait_tx, wait_rx) =  std::sync::mpsc::sync_channel(0);
+
+        let mut guard = LOGGER.lock().unwrap();
+        *guard = Some(LogSender{
+            tx:         log_tx.clone(),
+            wait:       wait_rx,
+        });
+
+        let mut d = LogWriter {
+            format,
+            sink,
+            recv:   log_rx,
+            wait:   wait_tx,
+        };
+
+        // Every second, send a LogItem::Tick.
+        task::spawn(async move {
+            loop {
+                time::sleep(Duration::from_millis(1000)).await;
+                if log_tx.send(LogItem::Tick).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Spawn a thread for the sync DataLog::run method.
+        Ok(thread::spawn(move || {
+            d.run();
+        }))
+    }
+
+    // main logging loop.
+    fn run(&mut self) {
+        while let Ok(item) = self.recv.recv() {
+            match item {
+                LogItem::Tick => self.sink.tick(),
+                LogItem::Item(item) => {
+                    let record = self.format.format(&item);
+                    self.sink.write(&record);
+                },
+                LogItem::Quit => break,
+            }
+        }
+
+        let _ = self.wait.send(());
+    }
+}
+
+// A log entry.
+#[derive(Debug, Clone)]
+pub(crate) struct Datalog {
+    // timestamp
+    pub time:           SystemTime,
+    // ip address where the request came from
+    pub src_ip:         IpAddr,
+    // "username" as sent in auth-request (radius attr 1)
+    pub username:       String,
+    // username got mapped to this underlying account (datalog 25:"XS4/401:<account>")
+    pub account:        Option<String>,
+    // "clientip" sent in auth-request (radius attr 31)
+    pub clientip:       Option<IpAddr>,
+    // "callingsystem" sent in auth-request (radius attr 32)
+    pub callingsystem:  Option<String>,
+    // Accept = Ok(()), Reject - Err(e).
+    pub status:         Result<(), Error>,
+    // error message overriding default error log_message (radius attr 24)
+    pub message:        Option<String>,
+}
+
+impl Default for Datalog {
+    fn default() -> Datalog {
+        Datalog {
+            time:           SystemTime::UNIX_EPOCH,
+            src_ip:         [0u8, 0u8, 0u8, 0u8].into(),
+            username:       "".to_string(),
+            account:        None,
+            clientip:       None,
+            callingsystem:  None,
+            status:         Ok(()),
+            message:        None,
+        }
+    }
+}
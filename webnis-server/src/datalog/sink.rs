@@ -0,0 +1,308 @@
+// Datalog sinks.
+//
+// A sink receives formatted records from the logging thread and
+// delivers them somewhere. All methods are synchronous.
+//
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use crate::config;
+
+const NET_TIMEOUT_MS: u64 = 5000;
+
+/// Destination of datalog records.
+pub(crate) trait LogSink: Send {
+    /// Write one record.
+    fn write(&mut self, record: &str);
+    /// Called every second.
+    fn tick(&mut self) {}
+}
+
+// Warn about a failing network sink, but not more than once a minute.
+struct Complainer {
+    name:       String,
+    last:       Option<Instant>,
+}
+
+impl Complainer {
+    fn new(name: impl Into<String>) -> Complainer {
+        Complainer { name: name.into(), last: None }
+    }
+
+    fn complain(&mut self, e: io::Error) {
+        let now = Instant::now();
+        if self.last.map(|t| now.duration_since(t).as_secs() >= 60).unwrap_or(true) {
+            warn!("datalog: {}: {} (dropping log records)", self.name, e);
+            self.last = Some(now);
+        }
+    }
+}
+
+//
+// Data is logged to a file, which gets moved away every second or
+// so by an external logshipping process.
+//
+pub(crate) struct FileSink {
+    file:           Option<fs::File>,
+    name:           String,
+    dev:            u64,
+    ino:            u64,
+    log_is_empty:   bool,
+}
+
+impl FileSink {
+    // If the datalog file cannot be opened, return an error.
+    pub fn new(filename: impl ToString) -> io::Result<FileSink> {
+        let mut f = FileSink {
+            file:           None,
+            name:           filename.to_string(),
+            dev:            0,
+            ino:            0,
+            log_is_empty:   false,
+        };
+        f.reopen(false).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", f.name, e)))?;
+        Ok(f)
+    }
+
+    // re-open the datalog file.
+    fn reopen(&mut self, retry: bool) -> io::Result<()> {
+        self.file.take();
+        loop {
+            let res = fs::OpenOptions::new()
+                .append(true)
+                .truncate(false)
+                .create(true)
+                .open(&self.name);
+            match res {
+                Ok(file) => {
+                    let meta = file.metadata().unwrap();
+                    self.dev = meta.dev();
+                    self.ino = meta.ino();
+                    self.file = Some(file);
+                    break;
+                },
+                Err(e) => {
+                    if !retry {
+                        return Err(e);
+                    }
+                    thread::sleep(Duration::from_millis(1000));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // lock file, then check to see if it has changed. if it has,
+    // reopen file, and keep trying until it is stable.
+    fn check_and_lock(&mut self) -> bool {
+        let mut did_reopen = false;
+        loop {
+            if let Some(ref mut file) = self.file {
+                if file.lock_exclusive().is_ok() {
+                    if let Ok(meta) = fs::metadata(&self.name) {
+                        if meta.dev() == self.dev && meta.ino() == self.ino {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = self.reopen(true);
+            did_reopen = true;
+        }
+        did_reopen
+    }
+}
+
+impl LogSink for FileSink {
+    fn write(&mut self, record: &str) {
+        loop {
+            self.check_and_lock();
+            let file = self.file.as_mut().unwrap();
+            if writeln!(file, "{}", record).is_ok() {
+                if file.unlock().is_err() {
+                    self.file.take();
+                }
+                break;
+            }
+            self.file.take();
+        }
+        self.log_is_empty = false;
+    }
+
+    fn tick(&mut self) {
+        if !self.log_is_empty {
+            self.log_is_empty = self.check_and_lock();
+            let file = self.file.as_mut().unwrap();
+            if file.unlock().is_err() {
+                self.file.take();
+            }
+        }
+    }
+}
+
+//
+// Unix datagram socket, e.g. a local log collector. One record per datagram.
+//
+pub(crate) struct UnixSink {
+    socket:     Option<UnixDatagram>,
+    path:       String,
+    complainer: Complainer,
+}
+
+impl UnixSink {
+    pub fn new(path: impl ToString) -> io::Result<UnixSink> {
+        let mut s = UnixSink {
+            socket:     None,
+            path:       path.to_string(),
+            complainer: Complainer::new(path.to_string()),
+        };
+        s.connect().map_err(|e| io::Error::new(e.kind(), format!("{}: {}", s.path, e)))?;
+        Ok(s)
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&self.path)?;
+        socket.set_write_timeout(Some(Duration::from_millis(NET_TIMEOUT_MS)))?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    fn try_write(&mut self, record: &str) -> io::Result<()> {
+        if self.socket.is_none() {
+            self.connect()?;
+        }
+        self.socket.as_ref().unwrap().send(record.as_bytes()).map(|_| ())
+    }
+}
+
+impl LogSink for UnixSink {
+    fn write(&mut self, record: &str) {
+        // on error, reconnect and try once more.
+        if self.try_write(record).is_err() {
+            self.socket.take();
+            if let Err(e) = self.try_write(record) {
+                self.socket.take();
+                self.complainer.complain(e);
+            }
+        }
+    }
+}
+
+//
+// TCP collector. Records are newline-terminated.
+//
+pub(crate) struct TcpSink {
+    stream:     Option<TcpStream>,
+    addr:       String,
+    complainer: Complainer,
+}
+
+impl TcpSink {
+    pub fn new(addr: impl ToString) -> io::Result<TcpSink> {
+        let addr = addr.to_string();
+        // check that the address resolves, but connect lazily.
+        addr.to_socket_addrs().map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+        Ok(TcpSink {
+            stream:     None,
+            complainer: Complainer::new(addr.as_str()),
+            addr,
+        })
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        let timeout = Duration::from_millis(NET_TIMEOUT_MS);
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "address did not resolve");
+        for sa in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&sa, timeout) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(timeout))?;
+                    self.stream = Some(stream);
+                    return Ok(());
+                },
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn try_write(&mut self, record: &str) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.connect()?;
+        }
+        let stream = self.stream.as_mut().unwrap();
+        writeln!(stream, "{}", record)
+    }
+}
+
+impl LogSink for TcpSink {
+    fn write(&mut self, record: &str) {
+        // on error, reconnect and try once more.
+        if self.try_write(record).is_err() {
+            self.stream.take();
+            if let Err(e) = self.try_write(record) {
+                self.stream.take();
+                self.complainer.complain(e);
+            }
+        }
+    }
+}
+
+//
+// UDP collector. One record per datagram.
+//
+pub(crate) struct UdpSink {
+    socket:     UdpSocket,
+    complainer: Complainer,
+}
+
+impl UdpSink {
+    pub fn new(addr: impl ToString) -> io::Result<UdpSink> {
+        let addr = addr.to_string();
+        let sa = addr
+            .to_socket_addrs()
+            .and_then(|mut a| a.next().ok_or(io::ErrorKind::NotFound.into()))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+        let socket = if sa.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        socket.connect(sa)?;
+        Ok(UdpSink {
+            socket,
+            complainer: Complainer::new(addr),
+        })
+    }
+}
+
+impl LogSink for UdpSink {
+    fn write(&mut self, record: &str) {
+        if let Err(e) = self.socket.send(record.as_bytes()) {
+            self.complainer.complain(e);
+        }
+    }
+}
+
+/// Build a sink from the config.
+pub(crate) fn new(cfg: &config::Datalog) -> io::Result<Box<dyn LogSink>> {
+    let sink: Box<dyn LogSink> = if let Some(ref file) = cfg.file {
+        Box::new(FileSink::new(file)?)
+    } else if let Some(ref path) = cfg.unix {
+        Box::new(UnixSink::new(path)?)
+    } else if let Some(ref addr) = cfg.tcp {
+        Box::new(TcpSink::new(addr)?)
+    } else if let Some(ref addr) = cfg.udp {
+        Box::new(UdpSink::new(addr)?)
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no datalog destination"));
+    };
+    Ok(sink)
+}
@@ -1,9 +1,10 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::{self, File};
+use std::io;
 use std::path::Path;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, Weak};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
 use std::time::SystemTime;
 
 use serde::{Deserialize, Deserializer};
@@ -14,7 +15,6 @@ use tokio::time::{self, Duration};
 use crate::errors::*;
 
 struct GdbmDb {
-    #[allow(unused)]
     file_name: String,
     modified: Option<SystemTime>,
     lastcheck: SystemTime,
@@ -32,6 +32,30 @@ lazy_static! {
     static ref GLOBAL_MAPS: Mutex<Vec<Weak<Mutex<Option<GdbmDb>>>>> = Mutex::new(Vec::new());
 }
 
+// Held while a map is being changed, from reading the old entry until
+// all files of the map are written. Writes are rare, so one lock for all
+// maps is fine.
+lazy_static! {
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// gdbm does not allow a writer while there are readers. Readers hold this
+// for reading while they open a file (until the handle can be found by
+// gdbm_close), a writer holds it for writing.
+lazy_static! {
+    static ref OPEN_LOCK: RwLock<()> = RwLock::new(());
+}
+
+/// Held while a map is being changed. See `write_lock()`.
+pub struct WriteLock {
+    _guard: MutexGuard<'static, ()>,
+}
+
+/// Take the lock for changing maps. `gdbm_write` and `json_write` need it.
+pub fn write_lock() -> WriteLock {
+    WriteLock { _guard: WRITE_LOCK.lock().unwrap() }
+}
+
 #[derive(Default)]
 pub(crate) struct Timer;
 
@@ -129,10 +153,10 @@ pub fn gdbm_lookup(db_path: impl AsRef<str>, key: &str) -> Result<String, WnErro
             m.remove(path);
         }
 
-        // try to open, then lookup, and save handle.
+        // open, lookup, and save handle. this waits for a writer to finish.
+        let _open = OPEN_LOCK.read().unwrap();
         let metadata = fs::metadata(path).map_err(|_| WnError::MapNotFound)?;
-        let handle =
-            gdbm::Gdbm::new(Path::new(path), 0, gdbm::Open::READER, 0).map_err(|_| WnError::MapNotFound)?;
+        let handle = gdbm::Gdbm::new(Path::new(path), 0, gdbm::Open::READER, 0).map_err(|_| WnError::MapNotFound)?;
         let db = GdbmDb {
             file_name: path.to_string(),
             handle:    handle,
@@ -151,6 +175,132 @@ pub fn gdbm_lookup(db_path: impl AsRef<str>, key: &str) -> Result<String, WnErro
     })
 }
 
+// close all cached reader handles of a gdbm file, in all threads.
+fn gdbm_close(path: &str) {
+    let maps = GLOBAL_MAPS.lock().unwrap();
+    for weak in maps.iter() {
+        if let Some(arc) = weak.upgrade() {
+            let mut opt_db = arc.lock().unwrap();
+            if opt_db.as_ref().map(|db| db.file_name == path).unwrap_or(false) {
+                opt_db.take();
+            }
+        }
+    }
+}
+
+/// Check that a map file exists and can be written to.
+pub fn check_writable(db_path: impl AsRef<str>) -> Result<(), WnError> {
+    let path = db_path.as_ref();
+    match fs::OpenOptions::new().write(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(WnError::MapNotFound),
+        Err(e) => {
+            warn!("{}: open for writing: {}", path, e);
+            Err(WnError::DbOther)
+        },
+    }
+}
+
+/// Store (`Some(line)`) or delete (`None`) an entry in a gdbm map.
+/// Returns the entry as it was before.
+pub fn gdbm_write(
+    _lock: &WriteLock,
+    db_path: impl AsRef<str>,
+    key: &str,
+    line: Option<&str>,
+) -> Result<Option<String>, WnError>
+{
+    let path = db_path.as_ref();
+
+    // close the readers, and keep new ones out until we are done.
+    let _open = OPEN_LOCK.write().unwrap();
+    gdbm_close(path);
+    let handle = gdbm::Gdbm::new(Path::new(path), 0, gdbm::Open::WRITER, 0).map_err(|e| {
+        warn!("{}: open for writing: {}", path, e);
+        WnError::DbOther
+    })?;
+    let prev = handle.fetch(key).ok();
+    let res = match line {
+        Some(line) => handle.store(key, line, true),
+        None => handle.delete(key),
+    };
+    res.map(|_| prev).map_err(|e| {
+        warn!("{}: write {}: {}", path, key, e);
+        WnError::DbOther
+    })
+}
+
+// see if a json value matches a key value. numbers match too.
+pub fn json_key_matches(value: Option<&serde_json::Value>, keyval: &str) -> bool {
+    match value {
+        Some(serde_json::Value::String(s)) => s == keyval,
+        Some(serde_json::Value::Number(n)) => n.to_string() == keyval,
+        _ => false,
+    }
+}
+
+/// Replace (`Some(entry)`) or delete (`None`) the entry with `keyname` == `keyval`
+/// in a JSON map, or add it if it is not there. The file is rewritten atomically.
+/// Returns the entry as it was before.
+pub fn json_write(
+    _lock: &WriteLock,
+    db_path: impl AsRef<str>,
+    keyname: &str,
+    keyval: &str,
+    entry: Option<&serde_json::Value>,
+) -> Result<Option<serde_json::Value>, WnError>
+{
+    let path = db_path.as_ref();
+    let file = File::open(path).map_err(|_| WnError::MapNotFound)?;
+    let perms = file.metadata().map_err(|_| WnError::DbOther)?.permissions();
+    let mut entries: Vec<serde_json::Value> = serde_json::from_reader(file).map_err(|_| WnError::DbOther)?;
+    let idx = entries.iter().position(|e| json_key_matches(e.get(keyname), keyval));
+    let prev = match (idx, entry) {
+        (Some(idx), Some(entry)) => Some(std::mem::replace(&mut entries[idx], entry.clone())),
+        (Some(idx), None) => Some(entries.remove(idx)),
+        (None, Some(entry)) => {
+            entries.push(entry.clone());
+            None
+        },
+        (None, None) => return Err(WnError::KeyNotFound),
+    };
+
+    let tmp = format!("{}.tmp.{}", path, std::process::id());
+    let data = serde_json::to_vec_pretty(&entries).map_err(WnError::SerializeJson)?;
+    fs::write(&tmp, &data)
+        .and_then(|_| fs::set_permissions(&tmp, perms))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map(|_| prev)
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            warn!("{}: write: {}", path, e);
+            WnError::DbOther
+        })
+}
+
+/// All entries of a gdbm file.
+pub fn gdbm_list(db_path: impl AsRef<str>) -> Result<Vec<String>, WnError> {
+    let path = db_path.as_ref();
+    // this handle is not cached, so keep writers out while it is open.
+    let _open = OPEN_LOCK.read().unwrap();
+    let handle = gdbm::Gdbm::new(Path::new(path), 0, gdbm::Open::READER, 0).map_err(|_| WnError::MapNotFound)?;
+    let mut lines = Vec::new();
+    let mut key = handle.firstkey().ok();
+    while let Some(k) = key {
+        if let Ok(line) = handle.fetch(&k) {
+            lines.push(line);
+        }
+        key = handle.nextkey(&k).ok();
+    }
+    Ok(lines)
+}
+
+/// All entries of a json file.
+pub fn json_list(db_path: impl AsRef<str>) -> Result<Vec<serde_json::Value>, WnError> {
+    let file = File::open(db_path.as_ref()).map_err(|_| WnError::MapNotFound)?;
+    serde_json::from_reader(file).map_err(|_| WnError::DbOther)
+}
+
 pub fn json_lookup(
     db_path: impl AsRef<str>,
     keyname: &str,
@@ -182,6 +332,8 @@ pub enum MapType {
     Gdbm,
     Json,
     Lua,
+    Gidlist,
+    Chain,
     None,
 }
 
@@ -193,6 +345,8 @@ impl FromStr for MapType {
             "gdbm" => MapType::Gdbm,
             "json" => MapType::Json,
             "lua" => MapType::Lua,
+            "gidlist" => MapType::Gidlist,
+            "chain" => MapType::Chain,
             _ => return Err(WnError::UnknownMapType),
         };
         Ok(f)
@@ -10,6 +10,8 @@ pub enum WnError {
     KeyNotFound,
     #[fail(display = "No such map")]
     MapNotFound,
+    #[fail(display = "Invalid key: {}", _0)]
+    InvalidKey(&'static str),
     #[fail(display = "Database error")]
     DbOther,
     #[fail(display = "Json serialization failed")]
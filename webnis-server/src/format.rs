@@ -1,4 +1,3 @@
-use regex::Regex;
 use std::collections::HashMap;
 use std::str::FromStr;
 
@@ -104,109 +103,357 @@ impl<'a> NumOrText<'a> {
 pub struct KeyValue<'a>(HashMap<&'a str, NumOrText<'a>>);
 
 impl<'a> KeyValue<'a> {
-    pub fn from_line(
-        line: &'a str,
-        output: &'a Option<HashMap<String, String>>,
-    ) -> Result<KeyValue<'a>, WnError>
-    {
-        // first split on whitespace, which gives us a bunch of
-        // key=value items. Then split those on '=' and put them
-        // into a HashMap.
-        let mut hm = HashMap::new();
-        for kv in line.split_whitespace() {
-            let mut w = kv.splitn(2, '=');
-            let k = w.next().unwrap();
-            let v = w.next().unwrap_or("");
-            hm.insert(k, NumOrText::parse(v));
-        }
+    pub fn from_line(line: &'a str) -> KeyValue<'a> {
+        KeyValue(key_value_fields(line).into_iter().map(|(k, v)| (k, NumOrText::parse(v))).collect())
+    }
+}
+
+// split a line on whitespace, which gives us a bunch of
+// key=value items. Then split those on '=' and put them
+// into a HashMap.
+fn key_value_fields<'a>(line: &'a str) -> HashMap<&'a str, &'a str> {
+    let mut hm = HashMap::new();
+    for kv in line.split_whitespace() {
+        let mut w = kv.splitn(2, '=');
+        let k = w.next().unwrap();
+        let v = w.next().unwrap_or("");
+        hm.insert(k, v);
+    }
+    hm
+}
+
+pub struct Fields;
+
+impl Fields {
+    // This could be a free-standing function. It's defined as Fields::from_line() only
+    // to have parity with the other from_line methods.
+    //
+    // Returns a hashmap keyed by the index number, starting at 1.
+    // { 1 => "username", 2 => "passwd", 3 => uid, ... }
+    pub fn from_line<'a>(line: &'a str, separator: &str) -> HashMap<NumOrText<'a>, NumOrText<'a>> {
+        split_fields(line, separator)
+            .into_iter()
+            .enumerate()
+            .map(|(num, val)| (NumOrText::Number((num + 1) as i64), NumOrText::parse(val)))
+            .collect::<HashMap<_, _>>()
+    }
+}
+
+// split line into parts.
+fn split_fields<'a>(line: &'a str, separator: &str) -> Vec<&'a str> {
+    let separator = separator.chars().nth(0).unwrap_or('\0');
+    if separator == '\0' {
+        line.split_whitespace().collect::<Vec<_>>()
+    } else {
+        line.split(separator).collect::<Vec<_>>()
+    }
+}
+
+/// Output mapping (`output = ...` in the map definition).
+///
+/// This is a template for the JSON value that is returned. Strings can
+/// contain `{field}` placeholders, tables and arrays are output as JSON
+/// objects and arrays, and other values (numbers, booleans) as-is.
+#[derive(Debug, Clone)]
+pub enum Output {
+    Template(Template),
+    Object(HashMap<String, Output>),
+    Array(Vec<Output>),
+    Value(serde_json::Value),
+}
+
+/// A string with `{field}`, `{field:modifier}` or `{field|default}` placeholders.
+#[derive(Debug, Clone)]
+pub struct Template(Vec<Part>);
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name:    String,
+    cast:    Option<Cast>,
+    default: Option<String>,
+}
 
-        // no output mapping? then we're done.
-        if output.is_none() {
-            return Ok(KeyValue(hm));
+#[derive(Debug, Clone, PartialEq)]
+enum Cast {
+    Int,
+    Str,
+    Bool,
+    List(String),
+}
+
+// The value of a field in the source data.
+#[derive(Debug, Clone, Copy)]
+enum FieldValue<'a> {
+    Text(&'a str),
+    Json(&'a serde_json::Value),
+}
+
+// Where the values for the fields come from: the fields
+// of a line of text, or the members of a JSON object.
+enum Source<'a> {
+    Text(HashMap<String, &'a str>),
+    Json(&'a serde_json::Value),
+}
+
+impl<'a> Source<'a> {
+    // "name" or, for JSON, "name.member.0".
+    fn get(&self, name: &str) -> Option<FieldValue<'a>> {
+        match self {
+            Source::Text(hm) => hm.get(name).map(|v| FieldValue::Text(*v)),
+            Source::Json(v) => {
+                let mut v: &'a serde_json::Value = *v;
+                for elem in name.split('.') {
+                    v = match v {
+                        serde_json::Value::Object(o) => o.get(elem)?,
+                        serde_json::Value::Array(a) => a.get(elem.parse::<usize>().ok()?)?,
+                        _ => return None,
+                    };
+                }
+                Some(FieldValue::Json(v))
+            },
         }
+    }
+}
 
-        // apply output mapping.
-        lazy_static! {
-            // matches { (ident) (:modifier) }
-            // modifier is ignored for now
-            static ref RE: Regex = Regex::new(r"^\{([0-9a-zA-Z_-]+)(:[a-z])?\}$").unwrap();
+impl<'a> FieldValue<'a> {
+    fn to_text(&self) -> String {
+        match *self {
+            FieldValue::Text(s) => s.to_string(),
+            FieldValue::Json(serde_json::Value::String(s)) => s.to_string(),
+            FieldValue::Json(v) => v.to_string(),
         }
+    }
 
-        let mut res = HashMap::new();
+    // without a modifier, text that looks like a number becomes a number.
+    fn auto(&self) -> serde_json::Value {
+        match *self {
+            FieldValue::Text(s) => serde_json::to_value(NumOrText::parse(s)).unwrap(),
+            FieldValue::Json(v) => v.clone(),
+        }
+    }
 
-        // apply output format. result goes into "res".
-        for (k, v) in output.as_ref().unwrap().iter() {
-            // interpolate 'v'. so replace {field} with the corresponding field.
-            let nv = if let Some(caps) = RE.captures(v) {
-                if let Some(val) = hm.remove(&caps[1]) {
-                    val
+    fn cast(&self, cast: &Cast) -> Option<serde_json::Value> {
+        use serde_json::Value;
+        let v = match (cast, *self) {
+            (Cast::Int, FieldValue::Json(Value::Number(n))) => Value::from(n.as_i64()?),
+            (Cast::Int, _) => Value::from(self.to_text().trim().parse::<i64>().ok()?),
+            (Cast::Str, _) => Value::String(self.to_text()),
+            (Cast::Bool, FieldValue::Json(Value::Bool(b))) => Value::Bool(*b),
+            (Cast::Bool, FieldValue::Json(Value::Number(n))) => Value::Bool(n.as_f64() != Some(0.0)),
+            (Cast::Bool, _) => {
+                match self.to_text().trim().to_lowercase().as_str() {
+                    "1" | "y" | "yes" | "true" | "on" => Value::Bool(true),
+                    "0" | "n" | "no" | "false" | "off" | "" => Value::Bool(false),
+                    _ => return None,
+                }
+            },
+            (Cast::List(_), FieldValue::Json(Value::Array(a))) => Value::Array(a.clone()),
+            (Cast::List(sep), _) => {
+                let text = self.to_text();
+                if text == "" {
+                    Value::Array(Vec::new())
                 } else {
-                    continue;
+                    Value::Array(text.split(sep.as_str()).map(|s| Value::String(s.to_string())).collect())
                 }
-            } else {
-                NumOrText::Text(v.as_str())
-            };
-            // and insert into output hashmap.
-            res.insert(k.as_str(), nv);
-        }
-        Ok(KeyValue(res))
+            },
+        };
+        Some(v)
     }
 }
 
-pub struct Fields;
-
-impl Fields {
-    // This could be a free-standing function. It's defined as Fields::from_line() only
-    // to have parity with the other from_line methods.
-    pub fn from_line<'a>(
-        line: &'a str,
-        output: &'a Option<HashMap<String, String>>,
-        separator: &str,
-    ) -> Result<HashMap<NumOrText<'a>, NumOrText<'a>>, WnError>
-    {
-        // split line into parts.
-        let separator = separator.chars().nth(0).unwrap_or('\0');
-        let fields = if separator == '\0' {
-            line.split_whitespace().collect::<Vec<_>>()
-        } else {
-            line.split(separator).collect::<Vec<_>>()
+impl Field {
+    // value of the field, cast if needed. if the field is not
+    // present or cannot be cast, the default value is used.
+    fn value(&self, src: &Source) -> Option<serde_json::Value> {
+        let cast = |v: FieldValue| match self.cast {
+            Some(ref c) => v.cast(c),
+            None => Some(v.auto()),
         };
+        src.get(&self.name)
+            .and_then(|v| cast(v))
+            .or_else(|| self.default.as_ref().and_then(|d| cast(FieldValue::Text(d))))
+    }
+
+    fn text(&self, src: &Source) -> Option<String> {
+        src.get(&self.name)
+            .map(|v| v.to_text())
+            .or_else(|| self.default.clone())
+    }
+}
 
-        // no output mapping, return hashmap keyed by the index number, starting at 1.
-        // { 1 => "username", 2 => "passwd", 3 => uid, ... }
-        if output.is_none() {
-            let res = fields
-                .into_iter()
-                .enumerate()
-                .map(|(num, val)| (NumOrText::Number((num + 1) as i64), NumOrText::parse(val)))
-                .collect::<HashMap<_, _>>();
-            return Ok(res);
+impl Template {
+    /// Parse a template string.
+    pub fn parse(s: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(format!("{}: missing }}", s)),
+                        }
+                    }
+                    if text.len() > 0 {
+                        parts.push(Part::Text(std::mem::replace(&mut text, String::new())));
+                    }
+                    parts.push(Part::Field(Template::parse_field(&spec).map_err(|e| format!("{}: {}", s, e))?));
+                },
+                '}' => return Err(format!("{}: unexpected }}", s)),
+                c => text.push(c),
+            }
+        }
+        if text.len() > 0 {
+            parts.push(Part::Text(text));
         }
 
-        // apply output mapping.
-        lazy_static! {
-            // matches { (index) (:modifier) }
-            // modifier is ignored for now
-            static ref RE: Regex = Regex::new(r"^\{([0-9]+)(:[a-z])?\}$").unwrap();
+        // a modifier only makes sense if the field is the whole value.
+        let fields = parts.iter().filter(|p| match p { Part::Field(_) => true, _ => false }).count();
+        let casts = parts.iter().any(|p| match p { Part::Field(f) => f.cast.is_some(), _ => false });
+        if casts && (fields != 1 || parts.len() != 1) {
+            return Err(format!("{}: a modifier can only be used if the value is a single {{field}}", s));
         }
+        Ok(Template(parts))
+    }
 
-        let mut hm = HashMap::new();
+    // field ":" modifier "|" default
+    fn parse_field(spec: &str) -> Result<Field, String> {
+        let end = spec.find(|c| c == ':' || c == '|').unwrap_or(spec.len());
+        let name = &spec[..end];
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+        if name == "" || !name.chars().all(valid) {
+            return Err(format!("invalid field name \"{}\"", name));
+        }
+        let mut rest = &spec[end..];
 
-        // apply output format. result goes into "hm".
-        for (k, v) in output.as_ref().unwrap().iter() {
-            // interpolate 'v'. so replace {1}, {2} etc with the corresponding field.
-            let mut nv = v.as_str();
-            if let Some(caps) = RE.captures(v) {
-                if let Ok(n) = caps[1].parse::<usize>() {
-                    if n > 0 && n <= fields.len() {
-                        nv = fields[n - 1];
+        let mut cast = None;
+        if rest.starts_with(':') {
+            rest = &rest[1..];
+            if rest.starts_with("list(") {
+                let close = rest.find(')').ok_or_else(|| "list: missing )".to_string())?;
+                cast = Some(Cast::List(rest[5..close].to_string()));
+                rest = &rest[close + 1..];
+            } else {
+                let end = rest.find('|').unwrap_or(rest.len());
+                cast = Some(match &rest[..end] {
+                    "int" => Cast::Int,
+                    "string" | "str" => Cast::Str,
+                    "bool" => Cast::Bool,
+                    "list" => Cast::List(",".to_string()),
+                    m => return Err(format!("unknown modifier \"{}\"", m)),
+                });
+                rest = &rest[end..];
+            }
+        }
+
+        let default = if rest.starts_with('|') {
+            Some(rest[1..].to_string())
+        } else if rest == "" {
+            None
+        } else {
+            return Err(format!("unexpected \"{}\"", rest));
+        };
+        Ok(Field { name: name.to_string(), cast, default })
+    }
+
+    fn eval(&self, src: &Source) -> Option<serde_json::Value> {
+        match self.0.as_slice() {
+            [Part::Field(f)] => f.value(src),
+            parts => {
+                let mut s = String::new();
+                for part in parts {
+                    match part {
+                        Part::Text(t) => s.push_str(t),
+                        Part::Field(f) => s.push_str(&f.text(src)?),
                     }
                 }
-            }
-            // and insert into output hashmap.
-            hm.insert(NumOrText::Text(k), NumOrText::parse(nv));
+                Some(serde_json::Value::String(s))
+            },
+        }
+    }
+}
+
+impl Output {
+    // Values that refer to a field that is not present, and that do not
+    // have a default, are left out of the output.
+    fn eval(&self, src: &Source) -> Option<serde_json::Value> {
+        match self {
+            Output::Template(t) => t.eval(src),
+            Output::Object(o) => {
+                let o = o.iter().filter_map(|(k, v)| v.eval(src).map(|v| (k.clone(), v))).collect();
+                Some(serde_json::Value::Object(o))
+            },
+            Output::Array(a) => Some(serde_json::Value::Array(a.iter().filter_map(|v| v.eval(src)).collect())),
+            Output::Value(v) => Some(v.clone()),
         }
-        Ok(hm)
+    }
+
+    /// Apply the output mapping to a JSON value.
+    pub fn apply(&self, value: &serde_json::Value) -> serde_json::Value {
+        self.eval(&Source::Json(value)).unwrap_or(serde_json::Value::Null)
+    }
+
+    // Apply the output mapping to fields of text.
+    fn apply_text(&self, fields: HashMap<String, &str>) -> serde_json::Value {
+        self.eval(&Source::Text(fields)).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+// what `output` looks like in the config file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawOutput {
+    Text(String),
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Array(Vec<RawOutput>),
+    Object(HashMap<String, RawOutput>),
+}
+
+impl Output {
+    fn from_raw(raw: RawOutput) -> Result<Output, String> {
+        let o = match raw {
+            RawOutput::Text(s) => Output::Template(Template::parse(&s)?),
+            RawOutput::Bool(b) => Output::Value(serde_json::Value::from(b)),
+            RawOutput::Int(n) => Output::Value(serde_json::Value::from(n)),
+            RawOutput::Float(n) => Output::Value(serde_json::Value::from(n)),
+            RawOutput::Array(a) => Output::Array(a.into_iter().map(Output::from_raw).collect::<Result<_, _>>()?),
+            RawOutput::Object(o) => {
+                let mut hm = HashMap::new();
+                for (k, v) in o.into_iter() {
+                    hm.insert(k, Output::from_raw(v)?);
+                }
+                Output::Object(hm)
+            },
+        };
+        Ok(o)
+    }
+}
+
+impl<'de> Deserialize<'de> for Output {
+    fn deserialize<D>(deserializer: D) -> Result<Output, D::Error>
+    where D: Deserializer<'de> {
+        let raw = RawOutput::deserialize(deserializer)?;
+        Output::from_raw(raw).map_err(serde::de::Error::custom)
     }
 }
 
@@ -260,18 +507,206 @@ where D: Deserializer<'de> {
 pub fn line_to_json(
     line: &str,
     format: &Format,
-    output: &Option<HashMap<String, String>>,
+    output: &Option<Output>,
 ) -> Result<serde_json::Value, WnError>
 {
-    match format {
+    // for the text formats, the output mapping works on the fields of the line.
+    if let Some(output) = output {
+        let fields = match format {
+            Format::KeyValue => Some(key_value_fields(line).into_iter().map(|(k, v)| (k.to_string(), v)).collect()),
+            Format::ColSep => Some(index_fields(split_fields(line, ":"))),
+            Format::WsSep => Some(index_fields(split_fields(line, ""))),
+            Format::TabSep => Some(index_fields(split_fields(line, "\t"))),
+            Format::Line => Some(index_fields(split_fields(line, "\n"))),
+            _ => None,
+        };
+        if let Some(fields) = fields {
+            return Ok(output.apply_text(fields));
+        }
+    }
+
+    let value = match format {
         Format::Passwd => to_json(&Passwd::from_line(line)?),
         Format::Group => to_json(&Group::from_line(line)?),
         Format::Adjunct => to_json(&Adjunct::from_line(line)?),
-        Format::KeyValue => to_json(&KeyValue::from_line(line, output)?),
-        Format::ColSep => to_json(&Fields::from_line(line, output, ":")?),
-        Format::WsSep => to_json(&Fields::from_line(line, output, "")?),
-        Format::TabSep => to_json(&Fields::from_line(line, output, "\t")?),
-        Format::Line => to_json(&Fields::from_line(line, output, "\n")?),
+        Format::KeyValue => to_json(&KeyValue::from_line(line)),
+        Format::ColSep => to_json(&Fields::from_line(line, ":")),
+        Format::WsSep => to_json(&Fields::from_line(line, "")),
+        Format::TabSep => to_json(&Fields::from_line(line, "\t")),
+        Format::Line => to_json(&Fields::from_line(line, "\n")),
         Format::Json => serde_json::from_str(line).map_err(WnError::SerializeJson),
+    }?;
+
+    // for the other formats, on the JSON object.
+    match output {
+        Some(output) => Ok(output.apply(&value)),
+        None => Ok(value),
+    }
+}
+
+/// Turn a JSON entry into a line in the map format. This is the reverse
+/// of `line_to_json` (without output mapping), and it checks that the
+/// entry has all the fields the format needs.
+pub fn json_to_line(value: &serde_json::Value, format: &Format) -> Result<String, WnError> {
+    let obj = value.as_object().ok_or(WnError::DeserializeData)?;
+
+    // a field must be present, and must not contain the separator.
+    let text = |name: &str, sep: &str| -> Result<String, WnError> {
+        let s = match obj.get(name) {
+            Some(serde_json::Value::String(s)) => s.to_string(),
+            Some(serde_json::Value::Number(n)) => n.to_string(),
+            Some(serde_json::Value::Bool(b)) => b.to_string(),
+            _ => return Err(WnError::DeserializeData),
+        };
+        if s.contains('\n') || (sep != "" && s.contains(sep)) || (sep == "" && s.contains(char::is_whitespace)) {
+            return Err(WnError::DeserializeData);
+        }
+        Ok(s)
+    };
+    let number = |name: &str| -> Result<String, WnError> {
+        match obj.get(name).and_then(|n| n.as_u64()) {
+            Some(n) if n <= u32::MAX as u64 => Ok(n.to_string()),
+            _ => Err(WnError::DeserializeData),
+        }
+    };
+    // fields "1" .. "n".
+    let fields = |sep: &str| -> Result<String, WnError> {
+        let mut f = Vec::new();
+        for n in 1..=obj.len() {
+            f.push(text(&n.to_string(), sep)?);
+        }
+        if f.len() == 0 {
+            return Err(WnError::DeserializeData);
+        }
+        Ok(f.join(if sep == "" { " " } else { sep }))
+    };
+
+    let line = match format {
+        Format::Passwd => {
+            let f = vec![
+                text("username", ":")?,
+                text("passwd", ":")?,
+                number("uid")?,
+                number("gid")?,
+                text("gecos", ":")?,
+                text("home", ":")?,
+                text("shell", ":")?,
+            ];
+            f.join(":")
+        },
+        Format::Group => {
+            let members = match obj.get("members").and_then(|m| m.as_array()) {
+                Some(m) => m,
+                None => return Err(WnError::DeserializeData),
+            };
+            let mut mm = Vec::new();
+            for m in members {
+                match m.as_str() {
+                    Some(m) if m != "" && !m.contains(|c| c == ',' || c == ':' || c == '\n') => mm.push(m),
+                    _ => return Err(WnError::DeserializeData),
+                }
+            }
+            format!("{}:{}:{}:{}", text("group", ":")?, text("passwd", ":")?, number("gid")?, mm.join(","))
+        },
+        Format::Adjunct => format!("{}:{}", text("username", ":")?, text("passwd", ":")?),
+        Format::KeyValue => {
+            let mut kv = Vec::new();
+            for k in obj.keys() {
+                if k == "" || k.contains(|c: char| c == '=' || c.is_whitespace()) {
+                    return Err(WnError::DeserializeData);
+                }
+                kv.push(format!("{}={}", k, text(k, "")?));
+            }
+            kv.join(" ")
+        },
+        Format::ColSep => fields(":")?,
+        Format::WsSep => fields("")?,
+        Format::TabSep => fields("\t")?,
+        Format::Line => {
+            if obj.len() != 1 {
+                return Err(WnError::DeserializeData);
+            }
+            text("1", "\n")?
+        },
+        Format::Json => value.to_string(),
+    };
+    Ok(line)
+}
+
+// fields keyed by the index number, starting at 1.
+fn index_fields(fields: Vec<&str>) -> HashMap<String, &str> {
+    fields.into_iter().enumerate().map(|(num, val)| ((num + 1).to_string(), val)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn output(toml: &str) -> Option<Output> {
+        #[derive(Deserialize)]
+        struct O {
+            output: Output,
+        }
+        Some(toml::from_str::<O>(toml).unwrap().output)
+    }
+
+    #[test]
+    fn t_template() {
+        let out = output(r#"
+            [output]
+            name = "{1}"
+            uid = "{3:int}"
+            home = "/home/{1}"
+            shell = "{7|/bin/sh}"
+            groups = "{5:list( )}"
+            admin = "{6:bool|no}"
+            x = "{{{1}}}"
+            lit = 42
+            ids = { uid = "{3}", gid = "{4:string}" }
+            list = [ "{1}", "{9}" ]
+        "#);
+        let v = line_to_json("mikevs:x:1000:100:wheel users:yes:", &Format::ColSep, &out).unwrap();
+        assert_eq!(v, json!({
+            "name": "mikevs", "uid": 1000, "home": "/home/mikevs", "shell": "",
+            "groups": [ "wheel", "users" ], "admin": true, "x": "{mikevs}", "lit": 42,
+            "ids": { "uid": 1000, "gid": "100" }, "list": [ "mikevs" ],
+        }));
+
+        // missing fields: default, or left out.
+        let v = line_to_json("name=joop uid=abc", &Format::KeyValue, &out).unwrap();
+        assert_eq!(v, json!({ "shell": "/bin/sh", "admin": false, "lit": 42, "ids": {}, "list": [] }));
+
+        // json format.
+        let out = output(r#"output = { user = "{username}", first = "{members.0}", n = "{gid:string}" }"#);
+        let v = line_to_json(r#"{"username":"mikevs","gid":10,"members":["a","b"]}"#, &Format::Json, &out).unwrap();
+        assert_eq!(v, json!({ "user": "mikevs", "first": "a", "n": "10" }));
+    }
+
+    #[test]
+    fn t_json_to_line() {
+        let line = "mikevs:x:1000:100:Mike:/home/mikevs:/bin/sh";
+        let v = line_to_json(line, &Format::Passwd, &None).unwrap();
+        assert_eq!(json_to_line(&v, &Format::Passwd).unwrap(), line);
+
+        let v = json!({ "group": "wheel", "passwd": "x", "gid": 10, "members": [ "root", "mikevs" ] });
+        assert_eq!(json_to_line(&v, &Format::Group).unwrap(), "wheel:x:10:root,mikevs");
+
+        let v = json!({ "1": "a", "2": 3 });
+        assert_eq!(json_to_line(&v, &Format::ColSep).unwrap(), "a:3");
+        assert!(json_to_line(&json!({ "1": "a:b" }), &Format::ColSep).is_err());
+        assert!(json_to_line(&json!({ "1": "a", "3": "c" }), &Format::ColSep).is_err());
+        assert!(json_to_line(&json!({ "username": "x", "uid": -1 }), &Format::Passwd).is_err());
+        assert_eq!(json_to_line(&json!({ "name": "joop", "uid": 2020 }), &Format::KeyValue).unwrap(), "name=joop uid=2020");
+    }
+
+    #[test]
+    fn t_template_errors() {
+        assert!(Template::parse("{1").is_err());
+        assert!(Template::parse("1}").is_err());
+        assert!(Template::parse("{1:float}").is_err());
+        assert!(Template::parse("/home/{1:int}").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{1:list(:)|}").is_ok());
     }
 }
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::db::{self, MapType};
+use crate::errors::*;
+use crate::format::Group;
+
+// In-memory reverse index of a group map: username -> gids.
+struct GidIndex {
+    modified:  Option<SystemTime>,
+    lastcheck: SystemTime,
+    members:   HashMap<String, Vec<u32>>,
+}
+
+// One index per group map file.
+lazy_static! {
+    static ref INDEXES: Mutex<HashMap<String, Arc<Mutex<Option<GidIndex>>>>> = Mutex::new(HashMap::new());
+}
+
+// add a group and its members to the index.
+fn add_group<'a>(index: &mut HashMap<String, Vec<u32>>, gid: u32, members: impl Iterator<Item = &'a str>) {
+    for m in members.filter(|m| m != &"") {
+        let gids = index.entry(m.to_string()).or_insert_with(Vec::new);
+        if !gids.contains(&gid) {
+            gids.push(gid);
+        }
+    }
+}
+
+// build the index from a JSON group map: an array of group objects.
+fn index_json(entries: &serde_json::Value) -> HashMap<String, Vec<u32>> {
+    let mut index = HashMap::new();
+    let entries = match entries.as_array() {
+        Some(e) => e,
+        None => return index,
+    };
+    for obj in entries {
+        let gid = match obj.get("gid").and_then(|g| g.as_u64()) {
+            Some(gid) => gid as u32,
+            None => continue,
+        };
+        let members = match obj.get("members").and_then(|m| m.as_array()) {
+            Some(m) => m,
+            None => continue,
+        };
+        add_group(&mut index, gid, members.iter().filter_map(|m| m.as_str()));
+    }
+    index
+}
+
+// build the index from a GDBM group map, with lines in /etc/group format.
+// db::gdbm_list keeps writers out while the file is open.
+fn index_gdbm(path: &str) -> Result<HashMap<String, Vec<u32>>, WnError> {
+    let mut index = HashMap::new();
+    for line in db::gdbm_list(path)? {
+        match Group::from_line(&line) {
+            Ok(g) => add_group(&mut index, g.gid, g.members.into_iter()),
+            Err(_) => warn!("{}: {}: not in group format", path, line),
+        }
+    }
+    Ok(index)
+}
+
+fn build_index(path: &str, map_type: &MapType) -> Result<GidIndex, WnError> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).map_err(|_| WnError::MapNotFound)?;
+    let members = match map_type {
+        MapType::Gdbm => index_gdbm(path)?,
+        MapType::Json => {
+            let file = File::open(path).map_err(|_| WnError::MapNotFound)?;
+            let entries: serde_json::Value = serde_json::from_reader(file).map_err(|_| WnError::DbOther)?;
+            index_json(&entries)
+        },
+        _ => return Err(WnError::UnknownMapType),
+    };
+    debug!("gidlist: indexed {}: {} users", path, members.len());
+    Ok(GidIndex {
+        modified:  Some(modified),
+        lastcheck: SystemTime::now(),
+        members:   members,
+    })
+}
+
+// see if the file changed. checks at most every 5 seconds.
+fn index_check(path: &str, index: &mut GidIndex, now: SystemTime) -> bool {
+    let mut valid = true;
+    if let Ok(d) = now.duration_since(index.lastcheck) {
+        if d.as_secs() > 5 {
+            valid = match (fs::metadata(path).and_then(|m| m.modified()), index.modified) {
+                (Ok(m1), Some(m2)) => m1 == m2,
+                _ => false,
+            };
+            if valid {
+                index.lastcheck = now;
+            }
+        }
+    }
+    valid
+}
+
+/// Find all the groups a user is a member of, in the group map
+/// at `db_path`. The reverse index of the group map is built on
+/// first use, and rebuilt when the file changes.
+pub fn gidlist_lookup(db_path: impl AsRef<str>, map_type: &MapType, username: &str) -> Result<Vec<u32>, WnError> {
+    let path = db_path.as_ref();
+    let entry = INDEXES
+        .lock()
+        .unwrap()
+        .entry(path.to_string())
+        .or_insert_with(|| Arc::new(Mutex::new(None)))
+        .clone();
+
+    // only one thread builds the index, the others wait for it.
+    let mut guard = entry.lock().unwrap();
+    let now = SystemTime::now();
+    let valid = match guard.as_mut() {
+        Some(index) => index_check(path, index, now),
+        None => false,
+    };
+    if !valid {
+        guard.take();
+        *guard = Some(build_index(path, map_type)?);
+    }
+    let index = guard.as_ref().unwrap();
+    Ok(index.members.get(username).cloned().unwrap_or_else(Vec::new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn t_index_json() {
+        let entries = json!([
+            { "group": "wheel", "passwd": "x", "gid": 10, "members": [ "root", "mikevs" ] },
+            { "group": "users", "passwd": "x", "gid": 100, "members": [ "mikevs", "" ] },
+            { "group": "empty", "passwd": "x", "gid": 200, "members": [] },
+        ]);
+        let index = index_json(&entries);
+        assert_eq!(index.get("mikevs"), Some(&vec![10, 100]));
+        assert_eq!(index.get("root"), Some(&vec![10]));
+        assert_eq!(index.get(""), None);
+        assert_eq!(index.len(), 2);
+    }
+}
@@ -0,0 +1,145 @@
+//! Serving on sockets that were not bound by warp: sockets from
+//! systemd, or sockets that were bound before dropping privileges.
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::Future;
+use futures::stream::{self, StreamExt};
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, Request, Response};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Config;
+
+// max number of TLS handshakes in progress on a socket.
+const MAX_HANDSHAKES: usize = 64;
+
+/// The address of the client. `warp::addr::remote()` does not know
+/// it, so it is passed to the filters as a request extension.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// TLS settings, the same as warp uses.
+pub fn tls_acceptor(config: &Config) -> io::Result<TlsAcceptor> {
+    let read = |name: &str| -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        File::open(name).and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+        Ok(data)
+    };
+    let invalid = |name: &str, msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, msg));
+
+    let crt_file = config.server.crt_file.as_ref().unwrap();
+    let certs = pemfile::certs(&mut BufReader::new(&read(crt_file)?[..]))
+        .map_err(|_| invalid(crt_file, "cannot parse certificate"))?;
+
+    let key_file = config.server.key_file.as_ref().unwrap();
+    let key = read(key_file)?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut &key[..]).unwrap_or_default();
+    if keys.len() == 0 {
+        keys = pemfile::rsa_private_keys(&mut &key[..]).unwrap_or_default();
+    }
+    if keys.len() == 0 {
+        return Err(invalid(key_file, "no private key found"));
+    }
+
+    let mut tls = ServerConfig::new(NoClientAuth::new());
+    tls.set_single_cert(certs, keys.remove(0))
+        .map_err(|e| invalid(crt_file, &e.to_string()))?;
+    tls.set_protocols(&["h2".into(), "http/1.1".into()]);
+    Ok(TlsAcceptor::from(Arc::new(tls)))
+}
+
+// Accept connections. Errors (like EMFILE) are logged, they should not stop the server.
+fn accept(listener: tokio::net::TcpListener) -> impl stream::Stream<Item = TcpStream> + Send {
+    stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => return Some((socket, listener)),
+                Err(e) => {
+                    log::error!("accept: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                },
+            }
+        }
+    })
+}
+
+/// Serve `service` on a listening socket, with or without TLS,
+/// until `signal` completes.
+///
+/// `service` is `warp::service(routes)`, the peer address of the
+/// connection is added to every request as a `PeerAddr` extension.
+pub fn serve<S>(
+    service: S,
+    listener: &TcpListener,
+    tls: Option<TlsAcceptor>,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<impl Future<Output = ()> + Send + 'static>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let incoming = accept(tokio::net::TcpListener::from_std(listener.try_clone()?)?);
+
+    // add the peer address to the request, then call the warp service.
+    let make_service = move |peer: Option<SocketAddr>| {
+        let service = service.clone();
+        service_fn(move |mut req: Request<Body>| {
+            if let Some(peer) = peer {
+                req.extensions_mut().insert(PeerAddr(peer));
+            }
+            service.clone().call(req)
+        })
+    };
+
+    let fut = async move {
+        let res = match tls {
+            None => {
+                let make = make_service_fn(move |conn: &TcpStream| {
+                    let svc = make_service(conn.peer_addr().ok());
+                    async move { Ok::<_, Infallible>(svc) }
+                });
+                let incoming = incoming.map(Ok::<_, io::Error>);
+                hyper::Server::builder(hyper::server::accept::from_stream(incoming))
+                    .serve(make)
+                    .with_graceful_shutdown(signal)
+                    .await
+            },
+            Some(acceptor) => {
+                // handshakes run concurrently, failed handshakes are dropped.
+                let incoming = incoming
+                    .map(move |socket| acceptor.accept(socket))
+                    .buffer_unordered(MAX_HANDSHAKES)
+                    .filter_map(|res| async move {
+                        match res {
+                            Ok(stream) => Some(Ok::<_, io::Error>(stream)),
+                            Err(e) => {
+                                log::debug!("TLS handshake: {}", e);
+                                None
+                            },
+                        }
+                    });
+                let make = make_service_fn(move |conn: &tokio_rustls::server::TlsStream<TcpStream>| {
+                    let svc = make_service(conn.get_ref().0.peer_addr().ok());
+                    async move { Ok::<_, Infallible>(svc) }
+                });
+                hyper::Server::builder(hyper::server::accept::from_stream(incoming))
+                    .serve(make)
+                    .with_graceful_shutdown(signal)
+                    .await
+            },
+        };
+        if let Err(e) = res {
+            log::error!("server error: {}", e);
+        }
+    };
+    Ok(fut)
+}
@@ -319,6 +319,14 @@ impl UserData for Request {
     }
 }
 
+/// See if a function is defined in the lua script.
+pub(crate) fn lua_function_exists(funcname: &str) -> bool {
+    LUA.with(|lua_tls| {
+        let lua_state = lua_tls.borrow();
+        lua_state.lua.context(|ctx| ctx.globals().get::<_, Function>(funcname).is_ok())
+    })
+}
+
 /// lua_map calls a lua function. The return value is usually a map, or nil.
 pub(crate) fn lua_map(
     webnis: &Webnis,
@@ -466,14 +474,14 @@ pub(crate) fn lua_auth(
             match res {
                 Err(ref e) => {
                     // internal error, override log status.
-                    dl.status = Err(datalog::Error::GENERIC);
+                    dl.status = Err(datalog::Error::generic());
                     dl.message = Some(format!("{:?}", e));
                 },
                 Ok(ref v) => {
                     if v.0 == serde_json::Value::Null || v.1 >= 400 {
                         // It's a reject, if status was not set do it now.
                         if dl.status.is_ok() {
-                            dl.status = Err(datalog::Error::GENERIC);
+                            dl.status = Err(datalog::Error::generic());
                         }
                     }
                 }
@@ -18,15 +18,23 @@ use tokio_stream::StreamExt;
 use tokio::task;
 use warp::Filter;
 
+pub(crate) mod audit;
+pub(crate) mod check;
 pub(crate) mod datalog;
 #[macro_use]
 pub(crate) mod errors;
 pub(crate) mod config;
 pub(crate) mod db;
 pub(crate) mod format;
+pub(crate) mod gidlist;
 pub(crate) mod iplist;
+pub(crate) mod listen;
 pub(crate) mod lua;
+pub(crate) mod notify;
 pub(crate) mod remoteip;
+pub(crate) mod replica;
+pub(crate) mod sandbox;
+pub(crate) mod systemd;
 pub(crate) mod util;
 pub(crate) mod webnis;
 
@@ -60,56 +68,47 @@ struct Opts {
     /// syntax check configuration files
     #[structopt(short = "x", long = "syntaxcheck")]
     syntax: bool,
-}
-
-async fn async_main() {
-    let opts = Opts::from_args();
 
-    let config = match config::read(&opts.cfg) {
-        Err(e) => die!(std => "{}: {}: {}", PROGNAME, opts.cfg, e),
-        Ok(c) => c,
-    };
-    if config.domain.len() == 0 {
-        die!(std => "{}: no domains defined in {}", PROGNAME, opts.cfg);
-    }
+    /// check the configuration and all map files, then exit
+    #[structopt(long = "check")]
+    check: bool,
 
-    // read /etc/ypserv.securenets if configured.
-    let securenets = if config.server.securenets_.len() > 0 {
-        let mut iplist = IpList::new();
-        for file in &config.server.securenets_ {
-            if let Err(e) = config::read_securenets(file, &mut iplist) {
-                die!(std => "{}: {:?}: {}", PROGNAME, file, e);
-            }
-        }
-        Some(iplist)
-    } else {
-        None
-    };
+    /// with --check, only check the first <n> entries of every map
+    #[structopt(long = "sample")]
+    sample: Option<usize>,
+}
 
-    // arbitrary limit, really.
-    raise_rlimit_nofile(64000);
+// Sockets and TLS settings that were set up before dropping privileges.
+struct Listeners {
+    sockets:    Vec<std::net::TcpListener>,
+    activated:  bool,
+    tls:        Option<tokio_rustls::TlsAcceptor>,
+}
 
+async fn async_main(opts: Opts, config: config::Config, securenets: Option<IpList>, listeners: Listeners) {
     // initialize webnis stuff
     let webnis = Webnis::new(config.clone(), securenets);
 
+    // check the domains and maps. this does not need the datalog, and
+    // should not open or connect to its destination.
+    if opts.check {
+        if !check::check(&config, opts.sample) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // initialize datalog stuff.
-    let _datalog_guard = match config.server.datalog {
+    let _datalog_guard = match config.server.datalog_ {
         Some(ref datalog) => {
             match datalog::init(datalog).await {
                 Ok(g) => Some(g),
-                Err(e) => die!(std => "{}: {}: {}", PROGNAME, datalog, e),
+                Err(e) => die!(std => "{}: datalog: {}", PROGNAME, e),
             }
         },
         None => None,
     };
 
-    // initialize lua stuff
-    if let Some(ref l) = config.lua {
-        if let Err(e) = lua::lua_init(&l.script_) {
-            die!(std => "{}: {:?} {}", PROGNAME, l.script_, e);
-        }
-    }
-
     if opts.syntax {
         println!("configuration parsed succesfully");
         return;
@@ -123,7 +122,8 @@ async fn async_main() {
         .and(warp::query::raw())
         .and(warp::path::end())
         .and(warp::filters::method::get())
-        .and_then(move |webnis: Webnis, domain: String, _ip: IpAddr, map: String, query: String| async move {
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(move |webnis: Webnis, domain: String, _ip: IpAddr, map: String, query: String, inm: Option<String>| async move {
             let keyname = if query == "" {
                 None
             } else {
@@ -135,7 +135,35 @@ async fn async_main() {
             });
             let query = HashMap::from_iter(query);
             debug!("handle_map: [{}] [{}] [{:?}]", domain, map, query);
-            webnis.handle_map(&domain, &map, keyname, &query)
+            webnis.handle_map(&domain, &map, keyname, &query, inm.as_ref().map(|s| s.as_str()))
+        });
+
+    // /{domain}/map/{map}/batch
+    let batch = check_authorization(&webnis, "map")
+        .and(warp::path::param())
+        .and(warp::path("batch"))
+        .and(warp::path::end())
+        .and(warp::filters::method::post())
+        .and(warp::header("content-type"))
+        .and(warp::body::bytes())
+        .and_then(move |webnis: Webnis, domain: String, _ip: IpAddr, map: String, ct: String, body: bytes::Bytes| async move {
+            let ct = ct.split(';').next().unwrap().trim();
+            if ct != APPL_JSON && ct != TEXT_JSON {
+                return Err(Reject::status(StatusCode::UNSUPPORTED_MEDIA_TYPE, "content-type must be json"));
+            }
+            debug!("handle_map_batch: [{}] [{}]", domain, map);
+            webnis.handle_map_batch(&domain, &map, &body)
+        });
+
+    // /{domain}/map/{map}/list
+    let list = check_authorization(&webnis, "map")
+        .and(warp::path::param())
+        .and(warp::path("list"))
+        .and(warp::path::end())
+        .and(warp::filters::method::get())
+        .and_then(move |webnis: Webnis, domain: String, _ip: IpAddr, map: String| async move {
+            debug!("handle_map_list: [{}] [{}]", domain, map);
+            webnis.handle_map_list(&domain, &map)
         });
 
     // /{domain}/{auth}
@@ -154,6 +182,69 @@ async fn async_main() {
             webnis.handle_auth(domain, ip, is_json, body.to_vec())
         });
 
+    // PUT /{domain}/map/{map}?key=value, DELETE /{domain}/map/{map}?key=value
+    // the method is checked first, so that a GET that was rejected by
+    // the other routes does not end up as an admin authorization error.
+    let write = warp::filters::method::put().map(|| true).or(warp::filters::method::delete().map(|| false)).unify()
+        .and(check_admin_authorization(&webnis, "map"))
+        .and(warp::path::param())
+        .and(warp::query::raw())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and_then(move |put: bool, webnis: Webnis, domain: String, ip: IpAddr, map: String, query: String, ct: Option<String>, body: bytes::Bytes| async move {
+            let keyname = if query == "" {
+                None
+            } else {
+                query.split('=').next()
+            };
+            let query = query.split('&').into_iter().map(|param| {
+                let mut kv = param.splitn(2, '=');
+                (kv.next().unwrap().to_string(), kv.next().unwrap_or("").to_string())
+            });
+            let query = HashMap::from_iter(query);
+            let body = if put {
+                let ct = ct.as_ref().map(|ct| ct.split(';').next().unwrap().trim()).unwrap_or("");
+                if ct != APPL_JSON && ct != TEXT_JSON {
+                    return Err(Reject::status(StatusCode::UNSUPPORTED_MEDIA_TYPE, "content-type must be json"));
+                }
+                Some(&body[..])
+            } else {
+                None
+            };
+            debug!("handle_map_write: [{}] [{}] [{:?}] put={}", domain, map, query, put);
+            webnis.handle_map_write(&domain, &map, ip, keyname, &query, body)
+        });
+
+    // /{domain}/changes
+    let changes = check_authorization(&webnis, "changes")
+        .and(warp::path::end())
+        .and(warp::filters::method::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |webnis: Webnis, domain: String, _ip: IpAddr, query: HashMap<String, String>| async move {
+            debug!("handle_changes: [{}] [{:?}]", domain, query);
+            webnis.handle_changes(&domain, &query).await
+        });
+
+    // /{domain}/export
+    let export = check_admin_authorization(&webnis, "export")
+        .and(warp::path::end())
+        .and(warp::filters::method::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |webnis: Webnis, domain: String, _ip: IpAddr, query: HashMap<String, String>| async move {
+            debug!("handle_export: [{}] [{:?}]", domain, query);
+            webnis.handle_export(&domain, &query).await
+        });
+
+    // /{domain}/export/{file}
+    let export_file = check_admin_authorization(&webnis, "export")
+        .and(warp::path::tail())
+        .and(warp::filters::method::get())
+        .and_then(move |webnis: Webnis, domain: String, _ip: IpAddr, tail: warp::path::Tail| async move {
+            debug!("handle_export_file: [{}] [{}]", domain, tail.as_str());
+            webnis.handle_export_file(&domain, tail.as_str()).await
+        });
+
     // /{domain}/{info}
     let info = check_authorization(&webnis, "info")
         .and(warp::path::end())
@@ -162,24 +253,61 @@ async fn async_main() {
             webnis.handle_info(&domain)
         });
 
-    let api = map.or(auth).or(info);
+    let api = map.or(batch).or(list).or(write).or(auth).or(changes).or(export).or(export_file).or(info);
     let routes = warp::path("webnis").or(warp::path!(".well-known" / "webnis" / ..)).unify().and(api);
     let routes = routes.recover(Reject::handle_rejection);
 
     // start db housekeeping task.
     db::Timer::start_timer().await;
 
+    // watch the map files for changes.
+    notify::Notifier::start(webnis.clone());
+
+    // copy map files from the primary server, if this is a replica.
+    replica::start(&config);
+
     // listener for SIGTERM / SIGHUP etc.
     let sig_listener = SigListener::new().await.unwrap_or_else(|e| {
         die!(log => "installing signal handlers: {}", e);
     });
 
+    // ping the systemd watchdog, if enabled.
+    systemd::start_watchdog();
+
     loop {
         let mut sl = sig_listener.lock().await;
 
-        // start a server for each listen address.
+        // sockets from systemd or sockets that were bound before dropping
+        // privileges replace the listen addresses in the config.
         let mut handles = Vec::new();
-        for (addr, name) in &config.server.listen {
+        let tls = match listeners.tls {
+            Some(ref tls) => Some(tls.clone()),
+            None if config.server.tls && listeners.sockets.len() > 0 => {
+                match listen::tls_acceptor(&config) {
+                    Ok(tls) => Some(tls),
+                    Err(e) => die!(log => "{}", e),
+                }
+            },
+            None => None,
+        };
+        for listener in &listeners.sockets {
+            let name = listener.local_addr().map(|a| a.to_string()).unwrap_or_default();
+            let signal = sl.add_listener();
+            match listen::serve(warp::service(routes.clone()), listener, tls.clone(), signal) {
+                Ok(srv) => {
+                    if listeners.activated {
+                        log::info!("Listening on {} (socket activation)", name);
+                    } else {
+                        log::info!("Listening on {}", name);
+                    }
+                    handles.push(task::spawn(srv));
+                },
+                Err(e) => die!(log => "{}: {}", name, e),
+            }
+        }
+
+        // start a server for each listen address.
+        for (addr, name) in config.server.listen.into_iter().filter(|_| listeners.sockets.len() == 0) {
             let signal = sl.add_listener();
             if config.server.tls {
                 // why no try_bind in the TlsServer?
@@ -202,6 +330,7 @@ async fn async_main() {
             }
         }
         drop(sl);
+        systemd::notify("READY=1");
 
         // Wait for tasks to finish.
         let mut task_waiter = FuturesUnordered::new();
@@ -230,6 +359,10 @@ async fn async_main() {
         if !sl.unwrap().got_sighup {
             break;
         }
+        // the key file cannot be read anymore after dropping privileges.
+        if listeners.tls.is_some() {
+            log::warn!("TLS key and certificate are not reloaded when sandboxed, restart to load new ones");
+        }
     }
 }
 
@@ -237,6 +370,77 @@ fn main() {
     let env = env_logger::Env::default().default_filter_or("info");
     env_logger::init_from_env(env);
 
+    // before the runtime starts any threads.
+    let activated = systemd::listen_fds().unwrap_or_else(|e| {
+        die!(std => "{}: socket activation: {}", PROGNAME, e);
+    });
+
+    let opts = Opts::from_args();
+
+    let mut config = match config::read(&opts.cfg) {
+        Err(e) => die!(std => "{}: {}: {}", PROGNAME, opts.cfg, e),
+        Ok(c) => c,
+    };
+    if config.domain.len() == 0 {
+        die!(std => "{}: no domains defined in {}", PROGNAME, opts.cfg);
+    }
+
+    // the datalog error codes are exported to lua, so set them before lua_init.
+    if let Some(ref datalog) = config.server.datalog_ {
+        datalog::set_error_codes(&datalog.error);
+    }
+
+    // read /etc/ypserv.securenets if configured.
+    let securenets = if config.server.securenets_.len() > 0 {
+        let mut iplist = IpList::new();
+        for file in &config.server.securenets_ {
+            if let Err(e) = config::read_securenets(file, &mut iplist) {
+                die!(std => "{}: {:?}: {}", PROGNAME, file, e);
+            }
+        }
+        Some(iplist)
+    } else {
+        None
+    };
+
+    // arbitrary limit, really.
+    raise_rlimit_nofile(64000);
+
+    // initialize lua stuff
+    if let Some(ref l) = config.lua {
+        if let Err(e) = lua::lua_init(&l.script_) {
+            die!(std => "{}: {:?} {}", PROGNAME, l.script_, e);
+        }
+    }
+
+    // bind the sockets and load the TLS key while we still can, then
+    // chroot, drop privileges and enable landlock. This must be done
+    // before the runtime starts any threads.
+    let mut listeners = Listeners {
+        activated:  activated.len() > 0,
+        sockets:    activated,
+        tls:        None,
+    };
+    if sandbox::enabled(&config) && !opts.syntax && !opts.check {
+        if listeners.sockets.len() == 0 {
+            for (addr, name) in &config.server.listen {
+                match std::net::TcpListener::bind(addr).and_then(|l| l.set_nonblocking(true).map(|_| l)) {
+                    Ok(l) => listeners.sockets.push(l),
+                    Err(e) => die!(std => "{}: {}: {}", PROGNAME, name, e),
+                }
+            }
+        }
+        if config.server.tls {
+            match listen::tls_acceptor(&config) {
+                Ok(tls) => listeners.tls = Some(tls),
+                Err(e) => die!(std => "{}: {}", PROGNAME, e),
+            }
+        }
+        if let Err(e) = sandbox::enter(&mut config) {
+            die!(std => "{}: {}", PROGNAME, e);
+        }
+    }
+
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .on_thread_start(|| {
@@ -250,7 +454,7 @@ fn main() {
         })
         .build()
         .unwrap();
-    rt.block_on(async_main());
+    rt.block_on(async_main(opts, config, securenets, listeners));
 }
 
 use std::future::Future;
@@ -283,6 +487,7 @@ impl SigListener {
                 tokio::select! {
                     _ = sig_hup.recv() => {
                         log::info!("got SIGHUP, restarting http server");
+                        systemd::notify_reloading();
                         got_sighup = true;
                     }
                     _ = sig_int.recv() => {
@@ -295,6 +500,9 @@ impl SigListener {
                         log::info!("got SIGTERM, exiting")
                     }
                 }
+                if !got_sighup {
+                    systemd::notify("STOPPING=1");
+                }
                 let mut this = listener.lock().await;
                 this.got_sighup = got_sighup;
                 for l in this.listeners.drain(..) {
@@ -325,6 +533,22 @@ impl SigListener {
 fn check_authorization(
     webnis: &Webnis,
     pathelem: &'static str,
+) -> impl Filter<Extract = (Webnis, String, IpAddr,), Error = warp::reject::Rejection> + Clone {
+    authorization(webnis, pathelem, false)
+}
+
+// Like check_authorization, but checks the admin credential of the domain.
+fn check_admin_authorization(
+    webnis: &Webnis,
+    pathelem: &'static str,
+) -> impl Filter<Extract = (Webnis, String, IpAddr,), Error = warp::reject::Rejection> + Clone {
+    authorization(webnis, pathelem, true)
+}
+
+fn authorization(
+    webnis: &Webnis,
+    pathelem: &'static str,
+    admin: bool,
 ) -> impl Filter<Extract = (Webnis, String, IpAddr,), Error = warp::reject::Rejection> + Clone {
     let webnis_ = webnis.clone();
 
@@ -334,7 +558,7 @@ fn check_authorization(
         .and(warp::path(pathelem))
         .and(remoteip::remoteip(false))
         .and(warp::header::optional("authorization"))
-        .and_then(|webnis: Webnis, domain: String, sa: Option<SocketAddr>, authz: Option<String>| async move {
+        .and_then(move |webnis: Webnis, domain: String, sa: Option<SocketAddr>, authz: Option<String>| async move {
 
             let ip = sa
                 .map(|sa| sa.ip())
@@ -354,9 +578,15 @@ fn check_authorization(
                 None => return Err(warp::reject::not_found()),
                 Some(d) => d,
             };
-            match check_http_auth(authz, domdef) {
+            let res = if admin {
+                check_admin_auth(authz, domdef)
+            } else {
+                check_http_auth(authz, domdef)
+            };
+            match res {
                 AuthResult::NoAuth | AuthResult::BadAuth => {
-                    Err(http_unauthorized(&domdef.name, domdef.http_authschema.as_ref()))
+                    let schema = if admin { &domdef.admin_authschema } else { &domdef.http_authschema };
+                    Err(http_unauthorized(&domdef.name, schema.as_ref()))
                 },
                 AuthResult::AuthOk => Ok((webnis, domain, ip)),
             }
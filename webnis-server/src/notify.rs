@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::task;
+use tokio::time;
+
+use crate::config::{self, Config};
+use crate::db::MapType;
+use crate::webnis::Webnis;
+
+// how often we check the map files for changes.
+const CHECK_INTERVAL_MS: u64 = 2000;
+
+// A map file, and the maps that are affected when it changes.
+struct Watched {
+    // name relative to db_dir, and the full path.
+    name:       String,
+    path:       String,
+    maps:       Vec<String>,
+    modified:   Option<SystemTime>,
+    // generation at which the file last changed.
+    generation: u64,
+}
+
+struct DomainState {
+    files:      Vec<Watched>,
+    // generation at which each map last changed.
+    map_gen:    HashMap<String, u64>,
+    generation: u64,
+}
+
+struct DomainWatch {
+    state:  Mutex<DomainState>,
+    tx:     watch::Sender<u64>,
+    // keeps the channel open, and is cloned for every waiter.
+    rx:     watch::Receiver<u64>,
+}
+
+/// Changes since a generation number.
+#[derive(Serialize, Debug)]
+pub struct Changes {
+    pub epoch:      u64,
+    pub generation: u64,
+    pub maps:       Vec<String>,
+}
+
+/// Keeps track of changes to the map files of all domains.
+///
+/// Every domain has a generation number that goes up by one every time
+/// one of its map files changes. The epoch is the time the server was
+/// started; if it changes, generation numbers start over.
+pub struct Notifier {
+    epoch:      u64,
+    domains:    HashMap<String, DomainWatch>,
+}
+
+// All files a map depends on. For virtual maps, those are
+// the files of the maps they are built from.
+fn map_files(config: &Config, name: &str, depth: u32, files: &mut Vec<String>) {
+    if depth > config::MAX_CHAIN_DEPTH {
+        return;
+    }
+    let maps = match config.map_.get(name) {
+        Some(m) => m,
+        None => return,
+    };
+    for m in maps {
+        if let Some(ref file) = m.map_file {
+            files.push(file.to_string());
+        }
+        match m.map_type {
+            MapType::Gidlist => {
+                if let Some(file) = m.group_map.as_ref().and_then(|g| config.group_map_file(g)).and_then(|g| g.map_file.as_ref()) {
+                    files.push(file.to_string());
+                }
+                if let Some(ref passwd_map) = m.passwd_map {
+                    map_files(config, passwd_map, depth + 1, files);
+                }
+            },
+            MapType::Chain => {
+                for member in &m.chain {
+                    map_files(config, member, depth + 1, files);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Notifier {
+    pub fn new(config: &Config) -> Notifier {
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut domains = HashMap::new();
+        for dom in &config.domain {
+            let mut files: Vec<Watched> = Vec::new();
+            for name in &dom.maps {
+                let mut names = Vec::new();
+                map_files(config, name, 0, &mut names);
+                for file in names.into_iter() {
+                    match files.iter_mut().find(|w| w.name == file) {
+                        Some(w) => {
+                            if !w.maps.contains(name) {
+                                w.maps.push(name.to_string());
+                            }
+                        },
+                        None => {
+                            let path = format!("{}/{}", dom.db_dir, file);
+                            files.push(Watched {
+                                modified:   modified(&path),
+                                name:       file,
+                                path:       path,
+                                maps:       vec![name.to_string()],
+                                generation: 0,
+                            });
+                        },
+                    }
+                }
+            }
+            let state = DomainState { files, map_gen: HashMap::new(), generation: 0 };
+            let (tx, rx) = watch::channel(0);
+            domains.insert(dom.name.clone(), DomainWatch { state: Mutex::new(state), tx, rx });
+        }
+        Notifier { epoch, domains }
+    }
+
+    /// Check all files for changes, and bump the generation
+    /// number of every domain that has changed maps.
+    pub fn check(&self) {
+        for (name, dw) in &self.domains {
+            let mut state = dw.state.lock().unwrap();
+            let mut changed = Vec::new();
+            let generation = state.generation + 1;
+            for w in state.files.iter_mut() {
+                let m = modified(&w.path);
+                if m != w.modified {
+                    w.modified = m;
+                    w.generation = generation;
+                    changed.extend(w.maps.iter().cloned());
+                }
+            }
+            if changed.len() == 0 {
+                continue;
+            }
+            state.generation = generation;
+            for map in changed.into_iter() {
+                state.map_gen.insert(map, generation);
+            }
+            debug!("domain {}: maps changed, generation {}", name, generation);
+            let _ = dw.tx.send(generation);
+        }
+    }
+
+    /// Start a background task that checks for changes.
+    pub fn start(webnis: Webnis) {
+        task::spawn(async move {
+            loop {
+                time::sleep(Duration::from_millis(CHECK_INTERVAL_MS)).await;
+                webnis.inner.notifier.check();
+            }
+        });
+    }
+
+    // the changes in a domain since generation `since`.
+    fn changes(&self, domain: &str, since: u64) -> Option<Changes> {
+        let dw = self.domains.get(domain)?;
+        let state = dw.state.lock().unwrap();
+        let mut maps = state
+            .map_gen
+            .iter()
+            .filter(|(_, &g)| g > since)
+            .map(|(m, _)| m.to_string())
+            .collect::<Vec<_>>();
+        maps.sort();
+        Some(Changes { epoch: self.epoch, generation: state.generation, maps })
+    }
+
+    /// The map files of a domain that changed after generation `since`,
+    /// or all of them. Returns (name relative to db_dir, path).
+    pub fn files(&self, domain: &str, since: Option<u64>) -> Vec<(String, String)> {
+        let dw = match self.domains.get(domain) {
+            Some(dw) => dw,
+            None => return Vec::new(),
+        };
+        let state = dw.state.lock().unwrap();
+        state
+            .files
+            .iter()
+            .filter(|w| since.map(|s| w.generation > s).unwrap_or(true))
+            .map(|w| (w.name.clone(), w.path.clone()))
+            .collect()
+    }
+
+    /// The epoch of this server.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Wait until something changes in a domain after generation `since`,
+    /// or until `timeout` has passed.
+    ///
+    /// If the epoch does not match, or `since` is from the future, the
+    /// client's idea of the generation is useless, and all maps of the
+    /// domain are returned right away. Without `since`, the current
+    /// generation is returned right away.
+    pub async fn wait_changes(
+        &self,
+        domain: &config::Domain,
+        epoch: Option<u64>,
+        since: Option<u64>,
+        timeout: Duration,
+    ) -> Option<Changes>
+    {
+        let dw = self.domains.get(&domain.name)?;
+        let mut rx = dw.rx.clone();
+        let current = *rx.borrow();
+        let since = match since {
+            Some(since) => since,
+            None => return self.changes(&domain.name, current),
+        };
+        if epoch != Some(self.epoch) || since > current {
+            let mut changes = self.changes(&domain.name, current)?;
+            changes.maps = domain.maps.clone();
+            return Some(changes);
+        }
+        let deadline = time::Instant::now() + timeout;
+        while *rx.borrow() <= since {
+            match time::timeout_at(deadline, rx.changed()).await {
+                Ok(Ok(())) => {},
+                _ => break,
+            }
+        }
+        self.changes(&domain.name, since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_check() {
+        let dir = std::env::temp_dir().join(format!("webnis-notify-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let db_dir = dir.to_str().unwrap();
+        fs::write(dir.join("passwd"), "[]").unwrap();
+        fs::write(dir.join("group"), "[]").unwrap();
+        fs::write(dir.join("webnis-server.toml"), format!(r#"
+            [server]
+              listen = [ "127.0.0.1:0" ]
+            [[domain]]
+              name = "default"
+              db_dir = "{}"
+              maps = [ "passwd", "group", "all" ]
+            [map.passwd]
+              key = "username"
+              type = "json"
+              file = "passwd"
+            [map.group]
+              key = "group"
+              type = "json"
+              file = "group"
+            [map.all]
+              key = "username"
+              type = "chain"
+              maps = [ "passwd" ]
+        "#, db_dir)).unwrap();
+        let config = config::read(dir.join("webnis-server.toml")).unwrap();
+
+        let notifier = Notifier::new(&config);
+        notifier.check();
+        assert_eq!(notifier.changes("default", 0).unwrap().generation, 0);
+
+        fs::remove_file(dir.join("passwd")).unwrap();
+        notifier.check();
+        let changes = notifier.changes("default", 0).unwrap();
+        assert_eq!(changes.generation, 1);
+        assert_eq!(changes.maps, vec!["all".to_string(), "passwd".to_string()]);
+        assert_eq!(notifier.changes("default", 1).unwrap().maps.len(), 0);
+        assert_eq!(notifier.files("default", None).len(), 2);
+        assert_eq!(notifier.files("default", Some(0)), vec![("passwd".to_string(), format!("{}/passwd", db_dir))]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,6 +3,8 @@
 use std::net::{IpAddr, SocketAddr};
 use warp::Filter;
 
+use crate::listen::PeerAddr;
+
 // Get the first IP address from a comma-separared list.
 fn parse_xff(s: &str) -> Option<SocketAddr> {
     s.split(",").next().map(|s| s.trim()).and_then(|s| {
@@ -85,6 +87,8 @@ pub fn remoteip(
     do_xff: bool,
 ) -> impl Filter<Extract = (Option<SocketAddr>,), Error = warp::reject::Rejection> + Copy {
     warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .map(|addr: Option<SocketAddr>, peer: Option<PeerAddr>| addr.or(peer.map(|p| p.0)))
         .and(warp::header::optional::<String>("X-Forwarded-For"))
         .and(warp::header::optional::<String>("X-Real-Ip"))
         .and(warp::header::optional::<String>("Forwarded"))
@@ -0,0 +1,313 @@
+//! Replication of map files from a primary server.
+//!
+//! A primary exports the map files of a domain (see `Webnis::handle_export`).
+//! A replica that has `replicate_from` set for a domain asks the primary
+//! which files have changed, fetches them into temporary files, checks their
+//! size and checksum, and when all of them are fine renames them into its `db_dir`.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use http::StatusCode;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task;
+use tokio::time;
+
+use crate::config;
+use crate::datalog::rfc3339;
+
+// default time between checks for changes.
+const REPLICATE_INTERVAL: u64 = 300;
+// the primary does not wait longer than this for changes.
+const MAX_WAIT: u64 = 300;
+// extra time on top of the wait time before a request times out.
+const REQUEST_TIMEOUT: u64 = 30;
+// time to wait before trying again after an error.
+const RETRY_DELAY: u64 = 30;
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// A file in an export.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportFile {
+    /// name relative to `db_dir`.
+    pub name:       String,
+    pub size:       u64,
+    pub checksum:   String,
+}
+
+/// The list of files that changed since a generation, or all files.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Export {
+    pub epoch:      u64,
+    pub generation: u64,
+    /// true if this is a list of all files of the domain.
+    pub full:       bool,
+    pub files:      Vec<ExportFile>,
+}
+
+#[derive(Deserialize)]
+struct ExportResult {
+    result: Export,
+}
+
+/// Replication status of a domain, shown by `handle_info`.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Status {
+    pub primary:        String,
+    pub epoch:          Option<u64>,
+    pub generation:     Option<u64>,
+    pub last_sync:      Option<String>,
+    pub last_error:     Option<String>,
+    pub files_copied:   u64,
+}
+
+lazy_static! {
+    static ref STATUS: Mutex<HashMap<String, Status>> = Mutex::new(HashMap::new());
+}
+
+/// The replication status of a domain, if it is a replica.
+pub fn status(domain: &str) -> Option<Status> {
+    STATUS.lock().unwrap().get(domain).cloned()
+}
+
+fn set_status(domain: &str, f: impl FnOnce(&mut Status)) {
+    let mut status = STATUS.lock().unwrap();
+    if let Some(s) = status.get_mut(domain) {
+        f(s);
+    }
+}
+
+/// Checksum of a map file: SHA-256, as 64 hex digits.
+pub fn checksum(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Run blocking file I/O on a thread where that is allowed.
+pub async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    task::spawn_blocking(f).await.unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))
+}
+
+/// Size and checksum of a file, for the export list.
+pub async fn export_file(name: &str, path: &str) -> io::Result<ExportFile> {
+    let name = name.to_string();
+    let path = path.to_string();
+    blocking(move || {
+        let data = fs::read(&path)?;
+        Ok(ExportFile {
+            name:     name,
+            size:     data.len() as u64,
+            checksum: checksum(&data),
+        })
+    })
+    .await
+}
+
+/// Start a replication task for every domain that has `replicate_from` set.
+pub fn start(config: &config::Config) {
+    for domain in &config.domain {
+        let primary = match domain.replicate_from {
+            Some(ref url) => url.trim_end_matches('/').to_string(),
+            None => continue,
+        };
+        let status = Status { primary: primary.clone(), ..Status::default() };
+        STATUS.lock().unwrap().insert(domain.name.clone(), status);
+        let domain = domain.clone();
+        task::spawn(async move {
+            replicate(domain, primary).await;
+        });
+    }
+}
+
+async fn replicate(domain: config::Domain, primary: String) {
+    let client: HttpsClient = Client::builder().build(HttpsConnector::with_native_roots());
+    let interval = domain.replicate_interval.unwrap_or(REPLICATE_INTERVAL);
+    let watch = domain.replicate_watch.unwrap_or(true);
+
+    let mut last: Option<(u64, u64)> = None;
+    loop {
+        let wait = if watch && last.is_some() { std::cmp::min(interval, MAX_WAIT) } else { 0 };
+        match sync(&client, &domain, &primary, last, wait).await {
+            Ok((export, copied)) => {
+                if copied > 0 {
+                    info!("replica: domain {}: copied {} files from {}", domain.name, copied, primary);
+                }
+                last = Some((export.epoch, export.generation));
+                set_status(&domain.name, |s| {
+                    s.epoch = Some(export.epoch);
+                    s.generation = Some(export.generation);
+                    s.last_sync = Some(rfc3339(SystemTime::now()));
+                    s.last_error = None;
+                    s.files_copied += copied;
+                });
+                if !watch {
+                    time::sleep(Duration::from_secs(interval)).await;
+                }
+            },
+            Err(e) => {
+                warn!("replica: domain {}: {}: {}", domain.name, primary, e);
+                set_status(&domain.name, |s| s.last_error = Some(e.to_string()));
+                time::sleep(Duration::from_secs(RETRY_DELAY)).await;
+            },
+        }
+    }
+}
+
+// Get the list of changed files from the primary, and copy them.
+// Returns the list, and the number of files that were copied.
+async fn sync(
+    client: &HttpsClient,
+    domain: &config::Domain,
+    primary: &str,
+    last: Option<(u64, u64)>,
+    wait: u64,
+) -> io::Result<(Export, u64)>
+{
+    let mut uri = format!("{}/{}/export?timeout={}", primary, domain.name, wait);
+    if let Some((epoch, generation)) = last {
+        uri.push_str(&format!("&epoch={}&since={}", epoch, generation));
+    }
+    let body = get(client, domain, &uri, wait).await?;
+    let export = match serde_json::from_slice::<ExportResult>(&body) {
+        Ok(e) => e.result,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", uri, e))),
+    };
+
+    // fetch all changed files into temporary files first, and only rename
+    // them into place when all of them check out. That way the files of
+    // one map (passwd.byname, passwd.byuid) are replaced together.
+    let mut fetched = Vec::new();
+    let res = fetch(client, domain, primary, &export, &mut fetched).await;
+    let copied = fetched.len() as u64;
+    blocking(move || {
+        let res = res.and_then(|_| rename_all(&fetched));
+        if res.is_err() {
+            for (tmp, _) in &fetched {
+                let _ = fs::remove_file(tmp);
+            }
+        }
+        res
+    })
+    .await?;
+    Ok((export, copied))
+}
+
+// Fetch the files of the export that are different from ours into temporary
+// files. Their names and the final names are added to `fetched`.
+async fn fetch(
+    client: &HttpsClient,
+    domain: &config::Domain,
+    primary: &str,
+    export: &Export,
+    fetched: &mut Vec<(String, String)>,
+) -> io::Result<()>
+{
+    for file in &export.files {
+        if !valid_name(&file.name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid file name {}", file.name)));
+        }
+        // Do not copy files we already have, so that a restart of
+        // the primary or replica does not rewrite all map files.
+        let path = format!("{}/{}", domain.db_dir, file.name);
+        if let Ok(local) = export_file(&file.name, &path).await {
+            if local.size == file.size && local.checksum == file.checksum {
+                continue;
+            }
+        }
+        let uri = format!("{}/{}/export/{}", primary, domain.name, file.name);
+        let data = get(client, domain, &uri, 0).await?;
+        if data.len() as u64 != file.size || checksum(&data) != file.checksum {
+            // most likely the file changed after the list was made.
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: size or checksum mismatch", uri)));
+        }
+        let tmp = format!("{}.replica.tmp", path);
+        let tmp_ = tmp.clone();
+        blocking(move || write_tmp(&tmp_, &data)).await?;
+        fetched.push((tmp, path));
+    }
+    Ok(())
+}
+
+// GET an uri from the primary, using the admin credential of the domain.
+async fn get(client: &HttpsClient, domain: &config::Domain, uri: &str, wait: u64) -> io::Result<Bytes> {
+    let mut token = domain.admin_authtoken.clone().unwrap_or_default();
+    if domain.admin_authencoding.as_ref().map(|s| s.as_str()) == Some("base64") {
+        token = base64::encode(&token);
+    }
+    let schema = domain.admin_authschema.as_ref().map(|s| s.as_str()).unwrap_or("");
+    let req = Request::get(uri)
+        .header("authorization", format!("{} {}", schema, token))
+        .body(Body::empty())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", uri, e)))?;
+
+    let timeout = Duration::from_secs(wait + REQUEST_TIMEOUT);
+    let fetch = async {
+        let resp = client.request(req).await?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+        Ok::<_, hyper::Error>((status, body))
+    };
+    let (status, body) = match time::timeout(timeout, fetch).await {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => return Err(io::Error::new(io::ErrorKind::Other, format!("{}: {}", uri, e))),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{}: timeout", uri))),
+    };
+    if status != StatusCode::OK {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{}: {}", uri, status)));
+    }
+    Ok(body)
+}
+
+// A file name from the primary must stay inside db_dir.
+fn valid_name(name: &str) -> bool {
+    name != "" && !name.starts_with('/') && !name.split('/').any(|p| p == ".." || p == "." || p == "")
+}
+
+// Write the data to a temporary file, and sync it to disk.
+fn write_tmp(tmp: &str, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = Path::new(tmp).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let res = fs::write(tmp, data).and_then(|_| fs::File::open(tmp)).and_then(|f| f.sync_all());
+    if let Err(e) = res {
+        let _ = fs::remove_file(tmp);
+        return Err(io::Error::new(e.kind(), format!("{}: {}", tmp, e)));
+    }
+    Ok(())
+}
+
+// Rename the temporary files into place.
+fn rename_all(fetched: &[(String, String)]) -> io::Result<()> {
+    for (tmp, path) in fetched {
+        fs::rename(tmp, path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_checksum() {
+        assert_eq!(checksum(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(checksum(b"a"), "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb");
+        assert!(valid_name("passwd.byname"));
+        assert!(valid_name("nis/group"));
+        assert!(!valid_name("../etc/shadow"));
+        assert!(!valid_name("/etc/shadow"));
+        assert!(!valid_name("a//b"));
+    }
+}
@@ -0,0 +1,231 @@
+//! Dropping privileges and sandboxing, after startup.
+//!
+//! - `chroot`: chroot to a directory. The paths in the configuration
+//!   that are used after startup are rewritten to be relative to it.
+//! - `user` / `group`: run as this user and group.
+//! - `landlock`: only allow access to the `db_dir` of the domains, the
+//!   log files, and a few system files (see landlock(7)).
+//!
+//! This must be done before any threads are started: landlock only
+//! restricts the thread that enables it, and the threads it starts.
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use webnis_common::privs::{self, cstring, os_error};
+
+use crate::config::Config;
+
+// landlock(7). libc does not have the definitions yet. The syscall
+// numbers are the same on all architectures.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+// the access rights of landlock ABI version 1.
+const FS_ABI_V1: u64 = (1 << 13) - 1;
+const FS_EXECUTE: u64 = 1 << 0;
+const FS_WRITE_FILE: u64 = 1 << 1;
+const FS_READ_FILE: u64 = 1 << 2;
+const FS_READ_DIR: u64 = 1 << 3;
+const FS_REMOVE_FILE: u64 = 1 << 5;
+const FS_MAKE_DIR: u64 = 1 << 7;
+const FS_MAKE_REG: u64 = 1 << 8;
+const FS_REFER: u64 = 1 << 13;
+const FS_TRUNCATE: u64 = 1 << 14;
+const FS_IOCTL_DEV: u64 = 1 << 15;
+
+// access rights that apply to files (the others only apply to directories).
+const FS_FILE: u64 = FS_EXECUTE | FS_WRITE_FILE | FS_READ_FILE | FS_TRUNCATE | FS_IOCTL_DEV;
+
+const READ: u64 = FS_READ_FILE | FS_READ_DIR;
+const WRITE: u64 = READ | FS_WRITE_FILE | FS_MAKE_REG | FS_MAKE_DIR | FS_REMOVE_FILE | FS_REFER | FS_TRUNCATE;
+// log files can be created and appended to.
+const APPEND: u64 = FS_WRITE_FILE | FS_MAKE_REG;
+
+// needed for DNS lookups, TLS client certificates and timestamps.
+const SYSTEM_FILES: &[&str] = &[
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/host.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/localtime",
+    "/etc/ssl",
+    "/etc/pki",
+    "/usr/share/zoneinfo",
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    "/dev/urandom",
+];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd:      i32,
+}
+
+/// Does the configuration ask for any of this.
+pub fn enabled(config: &Config) -> bool {
+    let s = &config.server;
+    s.user.is_some() || s.group.is_some() || s.chroot.is_some() || s.landlock
+}
+
+// "/var/yp/example.com" in chroot "/var/yp" is "/example.com".
+fn in_chroot(dir: &str, path: &mut String) {
+    if let Ok(p) = Path::new(path.as_str()).strip_prefix(dir) {
+        *path = Path::new("/").join(p).to_string_lossy().to_string();
+    }
+}
+
+/// chroot, drop privileges, and enable landlock, as configured. The paths
+/// in the configuration are rewritten to be relative to the chroot.
+pub fn enter(config: &mut Config) -> io::Result<()> {
+    let ids = privs::lookup(config.server.user.as_ref(), config.server.group.as_ref())?;
+
+    if let Some(dir) = config.server.chroot.clone() {
+        for d in config.domain.iter_mut() {
+            in_chroot(&dir, &mut d.db_dir);
+        }
+        let datalog = config.server.datalog_.as_mut();
+        let datalog = datalog.map(|d| vec![&mut d.file, &mut d.unix]).unwrap_or_default();
+        for path in datalog.into_iter().chain(Some(&mut config.server.audit_log)).flatten() {
+            in_chroot(&dir, path);
+        }
+        let cdir = cstring(&dir)?;
+        if unsafe { libc::chroot(cdir.as_ptr()) } < 0 {
+            return Err(os_error(format!("chroot {}", dir)));
+        }
+        if unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) } < 0 {
+            return Err(os_error(format!("chroot {}: chdir /", dir)));
+        }
+        log::info!("chroot to {}", dir);
+    }
+
+    privs::switch(&ids)?;
+    if let Some(ref user) = config.server.user {
+        log::info!("running as user {}", user);
+    }
+
+    if config.server.landlock {
+        landlock(config)?;
+    }
+    Ok(())
+}
+
+// The paths and the access that is allowed.
+fn landlock_rules(config: &Config) -> Vec<(String, u64)> {
+    let mut rules = Vec::new();
+    for d in &config.domain {
+        // writable maps and replicas write to db_dir.
+        let writable = d.replicate_from.is_some() ||
+            d.maps.iter().any(|m| config.map_.get(m).map(|v| v.iter().any(|m| m.writable)).unwrap_or(false));
+        rules.push((d.db_dir.clone(), if writable { WRITE } else { READ }));
+    }
+    let mut logs = vec![config.server.audit_log.as_ref()];
+    if let Some(ref d) = config.server.datalog_ {
+        logs.push(d.file.as_ref());
+    }
+    for log in logs.into_iter().flatten() {
+        // the directory, so that a rotated logfile can be re-created.
+        if let Some(dir) = Path::new(log).parent() {
+            rules.push((dir.to_string_lossy().to_string(), APPEND));
+        }
+    }
+    for file in SYSTEM_FILES {
+        if Path::new(file).exists() {
+            rules.push((file.to_string(), READ));
+        }
+    }
+    rules
+}
+
+fn landlock(config: &Config) -> io::Result<()> {
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        log::warn!("landlock: not supported by the kernel, filesystem access is not restricted");
+        return Ok(());
+    }
+    let mut handled = FS_ABI_V1;
+    if abi >= 2 {
+        handled |= FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        handled |= FS_IOCTL_DEV;
+    }
+
+    let attr = RulesetAttr { handled_access_fs: handled };
+    let ruleset = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    } as RawFd;
+    if ruleset < 0 {
+        return Err(os_error("landlock: create ruleset"));
+    }
+    let res = landlock_apply(ruleset, handled, landlock_rules(config));
+    unsafe { libc::close(ruleset) };
+    res?;
+    log::info!("landlock: filesystem access restricted (ABI version {})", abi);
+    Ok(())
+}
+
+fn landlock_apply(ruleset: RawFd, handled: u64, rules: Vec<(String, u64)>) -> io::Result<()> {
+    for (path, access) in rules {
+        let cpath = CString::new(Path::new(&path).as_os_str().as_bytes()).unwrap();
+        let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(os_error(format!("landlock: {}", path)));
+        }
+        let is_dir = Path::new(&path).is_dir();
+        let attr = PathBeneathAttr {
+            allowed_access: access & handled & if is_dir { !0 } else { FS_FILE },
+            parent_fd:      fd,
+        };
+        let res = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset,
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        let err = os_error(format!("landlock: {}", path));
+        unsafe { libc::close(fd) };
+        if res < 0 {
+            return Err(err);
+        }
+    }
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(os_error("landlock: PR_SET_NO_NEW_PRIVS"));
+    }
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) } < 0 {
+        return Err(os_error("landlock: restrict self"));
+    }
+    Ok(())
+}
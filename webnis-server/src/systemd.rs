@@ -0,0 +1,45 @@
+//! systemd integration: socket activation, readiness notification and
+//! the watchdog. See sd_listen_fds(3), sd_notify(3) and sd_watchdog_enabled(3).
+//! Notification and the watchdog are in webnis-common.
+//!
+//! Everything here is a no-op if the server was not started by systemd.
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+pub use webnis_common::systemd::{notify, notify_reloading, start_watchdog};
+
+// first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listening sockets passed by systemd (socket activation).
+///
+/// The environment variables are removed, so that they are not passed
+/// on. Call this before any threads are started.
+pub fn listen_fds() -> io::Result<Vec<TcpListener>> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let num = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if pid != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + num {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        // fails if this is not an AF_INET or AF_INET6 socket.
+        if listener.local_addr().is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {}: not a TCP socket", fd),
+            ));
+        }
+        listener.set_nonblocking(true)?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
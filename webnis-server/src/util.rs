@@ -147,6 +147,51 @@ pub(crate) fn json_result_raw(code: StatusCode, raw: &serde_json::Value) -> Warp
         .map_err(http_to_reject)
 }
 
+/// Reply with the raw contents of a file, and its checksum in a header.
+pub(crate) fn file_result(data: Vec<u8>, checksum: &str) -> WarpResult {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/octet-stream")
+        .header("x-webnis-checksum", checksum)
+        .body(Body::from(data))
+        .map_err(http_to_reject)
+}
+
+/// Like json_result, but with an ETag and optionally a Cache-Control header.
+/// If `if_none_match` matches the ETag, the answer is `304 Not Modified`.
+pub(crate) fn json_result_etag(
+    msg: &serde_json::Value,
+    etag: &str,
+    max_age: Option<u32>,
+    if_none_match: Option<&str>,
+) -> WarpResult
+{
+    let not_modified = if_none_match.map(|inm| etag_matches(inm, etag)).unwrap_or(false);
+
+    let mut builder = Response::builder().header("etag", etag);
+    if let Some(max_age) = max_age {
+        builder = builder.header("cache-control", format!("max-age={}", max_age));
+    }
+    if not_modified {
+        return builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).map_err(http_to_reject);
+    }
+    let body = stringnl(json!({ "result": msg }).to_string());
+    builder
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(http_to_reject)
+}
+
+// If-None-Match: "*", or a list of (possibly weak) etags.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|t| t.trim())
+        .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+}
+
 /// decode POST body into simple key/value.
 ///
 /// Now wouldn't it be great if we could use serde_urlencoded! Unfortunately
@@ -246,6 +291,30 @@ pub fn check_http_auth(authz: Option<String>, domain: &config::Domain) -> AuthRe
         },
     };
 
+    check_token(authz, domain, schema, token, domain.http_authencoding.as_ref())
+}
+
+/// Check http authentication for the admin (write) API.
+/// If the admin credential is not set, access is denied.
+pub fn check_admin_auth(authz: Option<String>, domain: &config::Domain) -> AuthResult {
+    match (domain.admin_authschema.as_ref(), domain.admin_authtoken.as_ref()) {
+        (Some(schema), Some(token)) => check_token(authz, domain, schema, token, domain.admin_authencoding.as_ref()),
+        _ => {
+            debug!("check_admin_auth: domain {}: admin_authschema/admin_authtoken not set", domain.name);
+            AuthResult::BadAuth
+        },
+    }
+}
+
+// check the authorization header against schema / token.
+fn check_token(
+    authz: Option<String>,
+    domain: &config::Domain,
+    schema: &str,
+    token: &str,
+    encoding: Option<&String>,
+) -> AuthResult
+{
     // We must have an authorization header,
     let hdr = match authz {
         Some(h) => h,
@@ -259,7 +328,7 @@ pub fn check_http_auth(authz: Option<String>, domain: &config::Domain) -> AuthRe
     }
 
     // if encoding is set, decode.
-    let httptoken = match domain.http_authencoding.as_ref().map(|s| s.as_str()) {
+    let httptoken = match encoding.map(|s| s.as_str()) {
         Some("base64") => {
             // base64 decode 2nd word
             match base64::decode(w[1]).ok().and_then(|v| String::from_utf8(v).ok()) {
@@ -1,22 +1,44 @@
 use std::collections::HashMap;
+use std::fs;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
 use http::StatusCode;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{self, json};
+use sha2::{Digest, Sha256};
+use warp::Rejection;
 
+use crate::audit;
 use crate::config;
 use crate::db;
 use crate::db::MapType;
 use crate::errors::WnError;
 use crate::format;
+use crate::gidlist;
 use crate::iplist::IpList;
 use crate::lua;
+use crate::notify::Notifier;
+use crate::replica;
 use crate::util::*;
 
 type WarpResult = Result<warp::reply::Response, warp::Rejection>;
 
+// default and max time a change request waits for changes.
+const CHANGES_TIMEOUT: u64 = 30;
+const MAX_CHANGES_TIMEOUT: u64 = 300;
+
+// max number of keys in one batch request.
+const MAX_BATCH_KEYS: usize = 1000;
+
+// one key in a batch request.
+#[derive(Deserialize)]
+struct BatchKey {
+    keyname:    String,
+    keyvalue:   serde_json::Value,
+}
+
 #[derive(Clone)]
 pub(crate) struct Webnis {
     pub inner: Arc<WebnisInner>,
@@ -25,6 +47,7 @@ pub(crate) struct Webnis {
 pub(crate) struct WebnisInner {
     pub config:     config::Config,
     pub securenets: Option<IpList>,
+    pub notifier:   Notifier,
 }
 
 // Create a new Webnis instance.
@@ -32,6 +55,7 @@ impl Webnis {
     pub fn new(config: config::Config, securenets: Option<IpList>) -> Webnis {
         Webnis {
             inner: Arc::new(WebnisInner {
+                notifier:   Notifier::new(&config),
                 config:     config,
                 securenets: securenets,
             }),
@@ -43,6 +67,7 @@ impl Webnis {
     //
     // Return a JSON object with some info about this domain. Members:
     // - maps: a list of maps.
+    // - replication: replication status, if this is a replica.
     //
     pub fn handle_info(&self, domain: &str) -> WarpResult {
         // lookup domain in config
@@ -70,8 +95,10 @@ impl Webnis {
         #[derive(Serialize)]
         struct Reply<T> {
             maps: T,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            replication: Option<replica::Status>,
         }
-        let r = Reply { maps: maps };
+        let r = Reply { maps: maps, replication: replica::status(&domain.name) };
         let reply = serde_json::to_value(r).unwrap();
 
         json_result(StatusCode::OK, &reply)
@@ -151,6 +178,7 @@ impl Webnis {
             Err(WnError::MapNotFound) => {
                 Err(json_error(StatusCode::NOT_FOUND, None, "Associated auth map not found"))
             },
+            Err(WnError::InvalidKey(msg)) => Err(json_error(StatusCode::BAD_REQUEST, None, msg)),
             Err(_) => Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, None, "Internal server error"))
         }
     }
@@ -174,11 +202,12 @@ impl Webnis {
             },
             Some(m) => m,
         };
+        let username = map.check_key(username)?;
 
         // see what type of map this is and delegate to the right lookup function.
         let res = match map.map_type {
-            MapType::Gdbm => self.lookup_gdbm_map(dom, map, username),
-            MapType::Json => self.lookup_json_map(dom, map, keyname, username),
+            MapType::Gdbm => self.lookup_gdbm_map(dom, map, &username),
+            MapType::Json => self.lookup_json_map(dom, map, keyname, &username),
             _ => {
                 warn!("auth_map: map {}: unsupported {:?}", map.name, map.map_type);
                 return Err(WnError::DbOther);
@@ -220,7 +249,15 @@ impl Webnis {
     }
 
     // look something up in a map.
-    pub fn handle_map(&self, domain: &str, map: &str, keyname: Option<&str>, query: &HashMap<String, String>) -> WarpResult {
+    pub fn handle_map(
+        &self,
+        domain: &str,
+        map: &str,
+        keyname: Option<&str>,
+        query: &HashMap<String, String>,
+        if_none_match: Option<&str>,
+    ) -> WarpResult
+    {
         // lookup domain in config
         let domain = match self.inner.config.find_domain(&domain) {
             None => return Err(json_error(StatusCode::BAD_REQUEST, None, "Domain not found")),
@@ -237,26 +274,340 @@ impl Webnis {
             None => return Err(json_error(StatusCode::BAD_REQUEST, None, "query params missing")),
         };
 
-        // find the map
         debug!("webnis.handle_map: domain [{}] map [{}] keyname [{}]", domain.name, map, keyname);
-        let (map, keyname) = match self.inner.config.find_allowed_map(&domain, map, keyname) {
-            None => return Err(json_error(StatusCode::NOT_FOUND, None, "No such map/keyname combo")),
-            Some(m) => m,
+        match self.lookup_allowed_map(domain, map, keyname, keyval) {
+            Ok((r, map)) => {
+                let etag = map_etag(domain, map, &r);
+                json_result_etag(&r, &etag, map.cache_max_age, if_none_match)
+            },
+            Err((code, msg)) => Err(json_error(code, None, msg)),
+        }
+    }
+
+    // look up a batch of keys in a map.
+    //
+    // The body is a JSON array of `{ "keyname": .., "keyvalue": .. }` objects.
+    // The result is an array with, for every key, either a `{ "result": .. }`
+    // or an `{ "error": .. }` object.
+    pub fn handle_map_batch(&self, domain: &str, map: &str, body: &[u8]) -> WarpResult {
+        // lookup domain in config
+        let domain = match self.inner.config.find_domain(&domain) {
+            None => return Err(json_error(StatusCode::BAD_REQUEST, None, "Domain not found")),
+            Some(d) => d,
+        };
+
+        let keys: Vec<BatchKey> = match serde_json::from_slice(body) {
+            Ok(k) => k,
+            Err(_) => return Err(json_error(StatusCode::BAD_REQUEST, None, "Body garbled")),
         };
+        if keys.len() > MAX_BATCH_KEYS {
+            return Err(json_error(StatusCode::PAYLOAD_TOO_LARGE, None, "Too many keys"));
+        }
 
+        let results = keys.iter().map(|key| {
+            // accept both "1000" and 1000.
+            let keyval = match key.keyvalue {
+                serde_json::Value::String(ref s) => s.to_string(),
+                serde_json::Value::Number(ref n) => n.to_string(),
+                _ => return batch_error(StatusCode::BAD_REQUEST, "keyvalue must be a string"),
+            };
+            match self.lookup_allowed_map(domain, map, &key.keyname, &keyval) {
+                Ok((r, _)) => json!({ "result": r }),
+                Err((code, msg)) => batch_error(code, msg),
+            }
+        }).collect::<Vec<_>>();
+
+        json_result(StatusCode::OK, &serde_json::Value::Array(results))
+    }
+
+    // list all entries of a map.
+    pub fn handle_map_list(&self, domain: &str, mapname: &str) -> WarpResult {
+        // lookup domain in config
+        let domain = match self.inner.config.find_domain(&domain) {
+            None => return Err(json_error(StatusCode::BAD_REQUEST, None, "Domain not found")),
+            Some(d) => d,
+        };
+
+        // the map must be allowed in this domain. if there are several
+        // definitions (one per key) use the first one.
+        let map = match self.inner.config.map_.get(mapname) {
+            Some(maps) if domain.maps.iter().any(|m| m == mapname) => &maps[0],
+            _ => return Err(json_error(StatusCode::NOT_FOUND, None, "No such map")),
+        };
+        if !map.enumerate {
+            return Err(json_error(StatusCode::FORBIDDEN, None, "Map cannot be listed"));
+        }
+
+        let path = format!("{}/{}", domain.db_dir, map.map_file.as_ref().unwrap());
         let res = match map.map_type {
-            MapType::Gdbm => self.lookup_gdbm_map(domain, map, keyval),
-            MapType::Json => self.lookup_json_map(domain, map, keyname, keyval),
-            MapType::Lua => self.lookup_lua_map(domain, map, keyname, keyval),
-            MapType::None => unreachable!(),
+            MapType::Gdbm => {
+                let format = match map.map_format {
+                    Some(ref f) => f,
+                    None => return Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, None, "Map has no format")),
+                };
+                db::gdbm_list(&path).map(|lines| {
+                    lines
+                        .iter()
+                        .filter_map(|line| match format::line_to_json(line, format, &map.map_output) {
+                            Ok(v) => Some(v),
+                            Err(_) => {
+                                warn!("{}: entry not in {:?} format", path, format);
+                                None
+                            },
+                        })
+                        .collect::<Vec<_>>()
+                })
+            },
+            MapType::Json => {
+                db::json_list(&path).map(|entries| match map.map_output {
+                    Some(ref output) => entries.iter().map(|v| output.apply(v)).collect(),
+                    None => entries,
+                })
+            },
+            _ => Err(WnError::MapNotFound),
         };
         match res {
-            Err(WnError::KeyNotFound) => Err(json_error(StatusCode::NOT_FOUND, None, "No such key in map")),
-            Err(WnError::MapNotFound) => Err(json_error(StatusCode::NOT_FOUND, None, "No such map")),
-            Err(WnError::UnknownFormat) => Err(json_error(StatusCode::NOT_FOUND, None, "Unknown map format")),
-            Err(WnError::SerializeJson(_)) => Err(json_error(StatusCode::NOT_FOUND, None, "Serialize error")),
-            Err(_) => Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, None, "Error reading database")),
-            Ok(r) => json_result(StatusCode::OK, &r),
+            Ok(entries) => json_result(StatusCode::OK, &serde_json::Value::Array(entries)),
+            Err(WnError::MapNotFound) => Err(json_error(StatusCode::NOT_FOUND, None, "Map not found")),
+            Err(_) => Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, None, "Error reading map")),
+        }
+    }
+
+    // create, update (body is Some) or delete (body is None) an entry in a writable map.
+    pub fn handle_map_write(
+        &self,
+        domain: &str,
+        mapname: &str,
+        ip: IpAddr,
+        keyname: Option<&str>,
+        query: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> WarpResult
+    {
+        // lookup domain in config
+        let config = &self.inner.config;
+        let domain = match config.find_domain(&domain) {
+            None => return Err(json_error(StatusCode::BAD_REQUEST, None, "Domain not found")),
+            Some(d) => d,
+        };
+
+        let (keyname, keyval) = match keyname {
+            Some(k) => match query.get(k) {
+                Some(v) => (k, v),
+                None => return Err(json_error(StatusCode::BAD_REQUEST, None, "query params garbled")),
+            },
+            None => return Err(json_error(StatusCode::BAD_REQUEST, None, "query params missing")),
+        };
+
+        let (map, keyname) = match config.find_allowed_map(domain, mapname, keyname) {
+            None => return Err(json_error(StatusCode::NOT_FOUND, None, "No such map/keyname combo")),
+            Some(m) => m,
+        };
+        if !map.writable {
+            return Err(json_error(StatusCode::FORBIDDEN, None, "Map is not writable"));
+        }
+        let keyval = match map.check_key(keyval) {
+            Ok(k) => k,
+            Err(WnError::InvalidKey(msg)) => return Err(json_error(StatusCode::BAD_REQUEST, None, msg)),
+            Err(_) => return Err(json_error(StatusCode::BAD_REQUEST, None, "Invalid key")),
+        };
+
+        // the entry as it is now. hold the write lock from here until
+        // all files are written, so that concurrent changes do not mix.
+        let lock = db::write_lock();
+        let old = match self.lookup_map(domain, map, keyname, &keyval, 0) {
+            Ok(v) => Some(v),
+            Err(WnError::KeyNotFound) => None,
+            Err(_) => return Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, None, "Error reading database")),
+        };
+
+        // the new entry. if it has a member with the name of the key, it must match.
+        let new = match body {
+            Some(body) => {
+                let entry: serde_json::Value = match serde_json::from_slice(body) {
+                    Ok(e) => e,
+                    Err(_) => return Err(json_error(StatusCode::BAD_REQUEST, None, "Body garbled")),
+                };
+                if !entry.is_object() {
+                    return Err(json_error(StatusCode::BAD_REQUEST, None, "Entry must be an object"));
+                }
+                if entry.get(keyname).is_some() && !db::json_key_matches(entry.get(keyname), &keyval) {
+                    return Err(json_error(StatusCode::BAD_REQUEST, None, "Entry does not match key"));
+                }
+                Some(entry)
+            },
+            None => {
+                if old.is_none() {
+                    return Err(json_error(StatusCode::NOT_FOUND, None, "No such key in map"));
+                }
+                None
+            },
+        };
+
+        // a map can consist of several files, one per key. update them all.
+        let res = write_plan(domain, config.map_.get(mapname).unwrap(), keyname, &keyval, old.as_ref(), new.as_ref())
+            .map_err(|error| PlanError { error, written: false, changed: Vec::new() })
+            .and_then(|plan| execute_plan(plan, &lock));
+        drop(lock);
+
+        let op = if new.is_some() { "put" } else { "delete" };
+        let mut change = audit::Change::new(op, &domain.name, mapname, keyname, &keyval, ip);
+        change.old = old;
+        change.new = new;
+
+        if let Err(e) = res {
+            // a write failed halfway. record what is left of it.
+            if e.written {
+                change.error = Some(e.error.to_string());
+                change.changed = e.changed;
+                audit::log(&config.server, &change);
+            }
+            return match e.error {
+                WnError::DeserializeData => {
+                    Err(json_error(StatusCode::BAD_REQUEST, None, "Entry does not match map format"))
+                },
+                WnError::MapNotFound => Err(json_error(StatusCode::NOT_FOUND, None, "No such map")),
+                _ => Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, None, "Error writing database")),
+            };
+        }
+        audit::log(&config.server, &change);
+
+        match change.new {
+            Some(ref new) => json_result(StatusCode::OK, new),
+            None => json_result(StatusCode::OK, &json!({})),
+        }
+    }
+
+    // wait for map changes in a domain.
+    //
+    // Query parameters are `epoch` and `since` from the previous answer, and
+    // `timeout`, the number of seconds to wait if nothing changed yet.
+    pub async fn handle_changes(&self, domain: &str, query: &HashMap<String, String>) -> WarpResult {
+        // lookup domain in config
+        let domain = match self.inner.config.find_domain(&domain) {
+            None => return Err(json_error(StatusCode::BAD_REQUEST, None, "Domain not found")),
+            Some(d) => d,
+        };
+
+        let param = |name: &str| -> Result<Option<u64>, Rejection> {
+            match query.get(name) {
+                Some(v) => match v.parse::<u64>() {
+                    Ok(v) => Ok(Some(v)),
+                    Err(_) => Err(json_error(StatusCode::BAD_REQUEST, None, "query params garbled")),
+                },
+                None => Ok(None),
+            }
+        };
+        let epoch = param("epoch")?;
+        let since = param("since")?;
+        let timeout = std::cmp::min(param("timeout")?.unwrap_or(CHANGES_TIMEOUT), MAX_CHANGES_TIMEOUT);
+
+        let notifier = &self.inner.notifier;
+        match notifier.wait_changes(domain, epoch, since, Duration::from_secs(timeout)).await {
+            Some(changes) => json_result(StatusCode::OK, &json!(changes)),
+            None => Err(json_error(StatusCode::NOT_FOUND, None, "Domain not found")),
+        }
+    }
+
+    // list the map files of a domain, for replicas.
+    //
+    // Like handle_changes, but returns the files that changed with their
+    // size and checksum. If `since` is not set or not valid, all files.
+    pub async fn handle_export(&self, domain: &str, query: &HashMap<String, String>) -> WarpResult {
+        // lookup domain in config
+        let domain = match self.inner.config.find_domain(&domain) {
+            None => return Err(json_error(StatusCode::BAD_REQUEST, None, "Domain not found")),
+            Some(d) => d,
+        };
+
+        let param = |name: &str| -> Result<Option<u64>, Rejection> {
+            match query.get(name) {
+                Some(v) => match v.parse::<u64>() {
+                    Ok(v) => Ok(Some(v)),
+                    Err(_) => Err(json_error(StatusCode::BAD_REQUEST, None, "query params garbled")),
+                },
+                None => Ok(None),
+            }
+        };
+        let epoch = param("epoch")?;
+        let since = param("since")?;
+        let timeout = std::cmp::min(param("timeout")?.unwrap_or(0), MAX_CHANGES_TIMEOUT);
+
+        let notifier = &self.inner.notifier;
+        let changes = match notifier.wait_changes(domain, epoch, since, Duration::from_secs(timeout)).await {
+            Some(changes) => changes,
+            None => return Err(json_error(StatusCode::NOT_FOUND, None, "Domain not found")),
+        };
+        let since = since.filter(|&s| epoch == Some(notifier.epoch()) && s <= changes.generation);
+
+        let mut files = Vec::new();
+        for (name, path) in notifier.files(&domain.name, since) {
+            match replica::export_file(&name, &path).await {
+                Ok(f) => files.push(f),
+                Err(e) => warn!("export: domain {}: {}: {}", domain.name, path, e),
+            }
+        }
+        let export = replica::Export {
+            epoch:      changes.epoch,
+            generation: changes.generation,
+            full:       since.is_none(),
+            files:      files,
+        };
+        json_result(StatusCode::OK, &json!(export))
+    }
+
+    // send one map file of a domain to a replica.
+    pub async fn handle_export_file(&self, domain: &str, name: &str) -> WarpResult {
+        // lookup domain in config
+        let domain = match self.inner.config.find_domain(&domain) {
+            None => return Err(json_error(StatusCode::BAD_REQUEST, None, "Domain not found")),
+            Some(d) => d,
+        };
+
+        // only files that belong to the maps of the domain.
+        let files = self.inner.notifier.files(&domain.name, None);
+        let path = match files.into_iter().find(|(n, _)| n == name) {
+            Some((_, path)) => path,
+            None => return Err(json_error(StatusCode::NOT_FOUND, None, "No such file")),
+        };
+        let path_ = path.clone();
+        let res = replica::blocking(move || {
+            let data = fs::read(&path_)?;
+            let sum = replica::checksum(&data);
+            Ok((data, sum))
+        });
+        match res.await {
+            Ok((data, sum)) => file_result(data, &sum),
+            Err(e) => {
+                warn!("export: domain {}: {}: {}", domain.name, path, e);
+                Err(json_error(StatusCode::NOT_FOUND, None, "No such file"))
+            },
+        }
+    }
+
+    // find the map, and look up a key in it. Returns the entry and the map it was found in.
+    fn lookup_allowed_map(
+        &self,
+        domain: &config::Domain,
+        map: &str,
+        keyname: &str,
+        keyval: &str,
+    ) -> Result<(serde_json::Value, &config::Map), (StatusCode, &'static str)>
+    {
+        // find the map
+        let (map, keyname) = match self.inner.config.find_allowed_map(&domain, map, keyname) {
+            None => return Err((StatusCode::NOT_FOUND, "No such map/keyname combo")),
+            Some(m) => m,
+        };
+
+        match self.lookup_map(domain, map, keyname, keyval, 0) {
+            Err(WnError::InvalidKey(msg)) => Err((StatusCode::BAD_REQUEST, msg)),
+            Err(WnError::KeyNotFound) => Err((StatusCode::NOT_FOUND, "No such key in map")),
+            Err(WnError::MapNotFound) => Err((StatusCode::NOT_FOUND, "No such map")),
+            Err(WnError::UnknownFormat) => Err((StatusCode::NOT_FOUND, "Unknown map format")),
+            Err(WnError::SerializeJson(_)) => Err((StatusCode::NOT_FOUND, "Serialize error")),
+            Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading database")),
+            Ok(r) => Ok((r, map)),
         }
     }
 
@@ -283,19 +634,91 @@ impl Webnis {
         };
 
         // do lookup
-        let res = match map.map_type {
-            MapType::Gdbm => self.lookup_gdbm_map(domain, map, keyval),
-            MapType::Json => self.lookup_json_map(domain, map, keyname, keyval),
+        let res = map.check_key(keyval).and_then(|keyval| match map.map_type {
+            MapType::Gdbm => self.lookup_gdbm_map(domain, map, &keyval),
+            MapType::Json => self.lookup_json_map(domain, map, keyname, &keyval),
+            MapType::Gidlist => self.lookup_gidlist_map(domain, map, &keyval),
             _ => Err(WnError::Other),
-        };
+        });
 
-        // remap KeyNotFound error to json null
+        // remap KeyNotFound and InvalidKey errors to json null
         match res {
-            Err(WnError::KeyNotFound) => Ok(json!(null)),
+            Err(WnError::KeyNotFound) | Err(WnError::InvalidKey(_)) => Ok(json!(null)),
             x => x,
         }
     }
 
+    // look up a key in a map, whatever its type.
+    fn lookup_map(
+        &self,
+        dom: &config::Domain,
+        map: &config::Map,
+        keyname: &str,
+        keyval: &str,
+        depth: u32,
+    ) -> Result<serde_json::Value, WnError>
+    {
+        // normalize and validate the key before it goes anywhere near the backend.
+        let keyval = &map.check_key(keyval)?;
+        match map.map_type {
+            MapType::Gdbm => self.lookup_gdbm_map(dom, map, keyval),
+            MapType::Json => self.lookup_json_map(dom, map, keyname, keyval),
+            MapType::Lua => self.lookup_lua_map(dom, map, keyname, keyval),
+            MapType::Gidlist => self.lookup_gidlist_map(dom, map, keyval),
+            MapType::Chain => self.lookup_chain_map(dom, map, keyname, keyval, depth),
+            MapType::None => unreachable!(),
+        }
+    }
+
+    // Virtual map: look up the key in the maps of the chain, in order.
+    // Returns the first entry found, or with `merge = true`, the
+    // first entry with the members of the other entries added to it.
+    fn lookup_chain_map(
+        &self,
+        dom: &config::Domain,
+        map: &config::Map,
+        keyname: &str,
+        keyval: &str,
+        depth: u32,
+    ) -> Result<serde_json::Value, WnError>
+    {
+        if depth >= config::MAX_CHAIN_DEPTH {
+            warn!("map {}: chain nested too deep", map.name);
+            return Err(WnError::Other);
+        }
+        let mut result: Option<serde_json::Value> = None;
+        for name in &map.chain {
+            // maps in the chain that do not have this key are skipped.
+            let (m, k) = match self.inner.config.find_map(name, keyname) {
+                Some(m) => m,
+                None => continue,
+            };
+            let entry = match self.lookup_map(dom, m, k, keyval, depth + 1) {
+                Ok(entry) => entry,
+                Err(WnError::KeyNotFound) | Err(WnError::InvalidKey(_)) => continue,
+                // an optional overlay, like passwd.local, might not exist on this host.
+                Err(WnError::MapNotFound) => {
+                    debug!("map {}: map {} not found, skipped", map.name, name);
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            if !map.merge {
+                return Ok(entry);
+            }
+            match (result.as_mut(), entry) {
+                (None, entry) => result = Some(entry),
+                (Some(serde_json::Value::Object(r)), serde_json::Value::Object(e)) => {
+                    for (k, v) in e.into_iter() {
+                        r.entry(k).or_insert(v);
+                    }
+                },
+                _ => debug!("map {}: cannot merge non-object entry from map {}", map.name, name),
+            }
+        }
+        result.ok_or(WnError::KeyNotFound)
+    }
+
     fn lookup_gdbm_map(
         &self,
         dom: &config::Domain,
@@ -321,7 +744,55 @@ impl Webnis {
     ) -> Result<serde_json::Value, WnError>
     {
         let path = format!("{}/{}", dom.db_dir, map.map_file.as_ref().unwrap());
-        db::json_lookup(path, keyname, keyval)
+        let value = db::json_lookup(path, keyname, keyval)?;
+        match map.map_output {
+            Some(ref output) => Ok(output.apply(&value)),
+            None => Ok(value),
+        }
+    }
+
+    // Virtual map: find all groups the user is a member of in the group map,
+    // and add the primary gid from the passwd map if that is configured.
+    fn lookup_gidlist_map(
+        &self,
+        dom: &config::Domain,
+        map: &config::Map,
+        username: &str,
+    ) -> Result<serde_json::Value, WnError>
+    {
+        let config = &self.inner.config;
+        let group_map = config.group_map_file(map.group_map.as_ref().unwrap()).ok_or(WnError::MapNotFound)?;
+        let path = format!("{}/{}", dom.db_dir, group_map.map_file.as_ref().unwrap());
+        let mut gids = gidlist::gidlist_lookup(&path, &group_map.map_type, username)?;
+        gids.sort();
+
+        if let Some(ref passwd_map) = map.passwd_map {
+            let (passwd_map, keyname) = config.find_map(passwd_map, "username").ok_or(WnError::MapNotFound)?;
+            let res = passwd_map.check_key(username).and_then(|username| match passwd_map.map_type {
+                MapType::Gdbm => self.lookup_gdbm_map(dom, passwd_map, &username),
+                MapType::Json => self.lookup_json_map(dom, passwd_map, keyname, &username),
+                _ => Err(WnError::MapNotFound),
+            });
+            match res {
+                Ok(pw) => {
+                    // primary gid goes first.
+                    if let Some(gid) = pw.get("gid").and_then(|g| g.as_u64()) {
+                        gids.retain(|&g| g as u64 != gid);
+                        gids.insert(0, gid as u32);
+                    }
+                },
+                Err(WnError::KeyNotFound) | Err(WnError::InvalidKey(_)) => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        if gids.is_empty() {
+            return Err(WnError::KeyNotFound);
+        }
+        Ok(json!({
+            "username": username,
+            "gidlist":  gids,
+        }))
     }
 
     fn lookup_lua_map(
@@ -339,3 +810,234 @@ impl Webnis {
         }
     }
 }
+
+// The ETag of an entry is a hash of its contents and the mtime of the map file.
+// A fixed hash (SHA-256, truncated), so that it is the same across restarts
+// and on all servers.
+fn map_etag(dom: &config::Domain, map: &config::Map, entry: &serde_json::Value) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(entry.to_string().as_bytes());
+    if let Some(ref file) = map.map_file {
+        let path = format!("{}/{}", dom.db_dir, file);
+        if let Ok(mtime) = fs::metadata(&path).and_then(|m| m.modified()) {
+            if let Ok(d) = mtime.duration_since(UNIX_EPOCH) {
+                hasher.update(format!("\n{}.{:09}", d.as_secs(), d.subsec_nanos()).as_bytes());
+            }
+        }
+    }
+    let digest = hasher.finalize();
+    let hex = digest[..8].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("\"{}\"", hex)
+}
+
+// One write to one map file.
+enum MapWrite {
+    Gdbm { path: String, key: String, line: Option<String> },
+    Json { path: String, keyname: String, keyval: String, entry: Option<serde_json::Value> },
+}
+
+impl MapWrite {
+    fn path(&self) -> &str {
+        match self {
+            MapWrite::Gdbm { path, .. } => path,
+            MapWrite::Json { path, .. } => path,
+        }
+    }
+
+    // Do the write. Returns the write that undoes it.
+    fn execute(self, lock: &db::WriteLock) -> Result<MapWrite, WnError> {
+        match self {
+            MapWrite::Gdbm { path, key, line } => {
+                let prev = db::gdbm_write(lock, &path, &key, line.as_deref())?;
+                Ok(MapWrite::Gdbm { path, key, line: prev })
+            },
+            MapWrite::Json { path, keyname, keyval, entry } => {
+                let prev = db::json_write(lock, &path, &keyname, &keyval, entry.as_ref())?;
+                // the entry is found by its new key now, if it has one.
+                let keyval = entry.as_ref().and_then(|e| entry_key(e, &keyname)).unwrap_or(keyval);
+                Ok(MapWrite::Json { path, keyname, keyval, entry: prev })
+            },
+        }
+    }
+}
+
+// Why a plan could not be executed, and the files that were changed
+// before the error and could not be changed back.
+struct PlanError {
+    error:   WnError,
+    // false if nothing was written yet.
+    written: bool,
+    changed: Vec<String>,
+}
+
+// Execute the writes of a plan. First check that all files can be written
+// to. If a write fails after that, undo the writes that were already done,
+// so that the files of the map do not disagree.
+fn execute_plan(plan: Vec<MapWrite>, lock: &db::WriteLock) -> Result<(), PlanError> {
+    for w in &plan {
+        db::check_writable(w.path()).map_err(|error| PlanError { error, written: false, changed: Vec::new() })?;
+    }
+    let mut undo = Vec::new();
+    for w in plan {
+        let error = match w.execute(lock) {
+            Ok(u) => {
+                undo.push(u);
+                continue;
+            },
+            Err(e) => e,
+        };
+        let written = !undo.is_empty();
+        let mut changed = Vec::new();
+        for u in undo.into_iter().rev() {
+            let path = u.path().to_string();
+            if u.execute(lock).is_err() {
+                error!("{}: could not undo the change", path);
+                changed.push(path);
+            }
+        }
+        return Err(PlanError { error, written, changed });
+    }
+    Ok(())
+}
+
+// the value of a key in an entry, as text.
+fn entry_key(entry: &serde_json::Value, keyname: &str) -> Option<String> {
+    match entry.get(keyname)? {
+        serde_json::Value::String(s) => Some(s.to_string()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+// Work out what to write to which map file, before anything is written,
+// so that an entry that does not fit one of the files changes nothing.
+//
+// `keyname`/`keyval` is the key of the request. The other files of the map are
+// keyed on other members of the entry (for example passwd.byname and passwd.byuid).
+fn write_plan(
+    dom: &config::Domain,
+    maps: &[config::Map],
+    keyname: &str,
+    keyval: &str,
+    old: Option<&serde_json::Value>,
+    new: Option<&serde_json::Value>,
+) -> Result<Vec<MapWrite>, WnError>
+{
+    let mut plan = Vec::new();
+    let mut seen = Vec::new();
+    for m in maps {
+        let file = match m.map_file {
+            Some(ref f) => f,
+            None => continue,
+        };
+        let path = format!("{}/{}", dom.db_dir, file);
+        if seen.contains(&path) {
+            continue;
+        }
+        seen.push(path.clone());
+
+        // value of key `k` in the old and the new entry.
+        let key_of = |entry: Option<&serde_json::Value>, k: &str| -> Result<Option<String>, WnError> {
+            match entry {
+                None => Ok(None),
+                Some(_) if k == keyname => Ok(Some(keyval.to_string())),
+                Some(e) => entry_key(e, k).map(Some).ok_or(WnError::DeserializeData),
+            }
+        };
+
+        match m.map_type {
+            MapType::Gdbm => {
+                let format = m.map_format.as_ref().ok_or(WnError::UnknownFormat)?;
+                let k = m.key.as_ref().ok_or(WnError::MapNotFound)?;
+                let line = match new {
+                    Some(entry) => Some(format::json_to_line(entry, format)?),
+                    None => None,
+                };
+                let old_key = key_of(old, k)?;
+                let new_key = key_of(new, k)?;
+                if let Some(old_key) = old_key {
+                    if new_key.as_ref() != Some(&old_key) {
+                        plan.push(MapWrite::Gdbm { path: path.clone(), key: old_key, line: None });
+                    }
+                }
+                if let Some(new_key) = new_key {
+                    plan.push(MapWrite::Gdbm { path, key: new_key, line });
+                }
+            },
+            MapType::Json => {
+                if let Some(entry) = new {
+                    if let Some(format) = m.map_format.as_ref() {
+                        format::json_to_line(entry, format)?;
+                    }
+                    // without the key, the entry could never be found.
+                    if m.key.iter().chain(m.keys.iter()).any(|k| entry.get(k).is_none()) {
+                        return Err(WnError::DeserializeData);
+                    }
+                }
+                // find the entry by the key of the request if this file has it.
+                let mut keys = m.key.iter().chain(m.keys.iter());
+                let k = keys.find(|k| k.as_str() == keyname).or(m.key.as_ref()).or(m.keys.first());
+                let k = k.ok_or(WnError::MapNotFound)?;
+                let kv = match key_of(old, k)? {
+                    Some(kv) => kv,
+                    None => key_of(new, k)?.unwrap_or_default(),
+                };
+                plan.push(MapWrite::Json { path, keyname: k.to_string(), keyval: kv, entry: new.cloned() });
+            },
+            _ => return Err(WnError::UnknownMapType),
+        }
+    }
+    Ok(plan)
+}
+
+// error object for one key in a batch reply.
+fn batch_error(code: StatusCode, msg: &str) -> serde_json::Value {
+    json!({
+        "error": {
+            "code":     code.as_u16(),
+            "message":  msg,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_execute_plan_undo() {
+        let dir = std::env::temp_dir().join(format!("webnis-plan-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let byname = dir.join("passwd.byname.json").to_str().unwrap().to_string();
+        let byuid = dir.join("passwd.byuid.json").to_str().unwrap().to_string();
+        let old = json!([{ "username": "alice", "uid": 2000 }]);
+        fs::write(&byname, old.to_string()).unwrap();
+        fs::write(&byuid, "[]").unwrap();
+
+        let write = |path: &str, keyname: &str, keyval: &str, entry: Option<serde_json::Value>| {
+            MapWrite::Json { path: path.to_string(), keyname: keyname.to_string(), keyval: keyval.to_string(), entry }
+        };
+        let lock = db::write_lock();
+
+        // the second write fails (no such key), the first one is undone.
+        let plan = vec![
+            write(&byname, "username", "alice", Some(json!({ "username": "alice", "uid": 2001 }))),
+            write(&byuid, "uid", "2000", None),
+        ];
+        let e = execute_plan(plan, &lock).err().unwrap();
+        assert!(e.written);
+        assert!(e.changed.is_empty());
+        assert_eq!(db::json_list(&byname).unwrap(), old.as_array().unwrap().clone());
+
+        // a file that does not exist: nothing is written.
+        let plan = vec![
+            write(&byname, "username", "alice", None),
+            write(&format!("{}.missing", byuid), "uid", "2000", None),
+        ];
+        let e = execute_plan(plan, &lock).err().unwrap();
+        assert!(!e.written);
+        assert_eq!(db::json_list(&byname).unwrap(), old.as_array().unwrap().clone());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+//
+// wncat: print all entries of a webnis map, like ypcat.
+//
+use structopt::StructOpt;
+
+use webnis_utils::{die, entries, value_str, ClientOpts};
+
+static PROGNAME: &str = "wncat";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "wncat")]
+struct Opts {
+    #[structopt(flatten)]
+    client: ClientOpts,
+
+    /// print the value of this member before every entry, like ypcat -k
+    #[structopt(short = "k", long = "key")]
+    key: Option<String>,
+
+    /// print the entries as indented JSON
+    #[structopt(short = "p", long = "pretty")]
+    pretty: bool,
+
+    /// name of the map. it must have enumerate = true on the server.
+    map: String,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    let client = opts.client.client().unwrap_or_else(|e| die!(PROGNAME, "{}", e));
+    let answer = client.list(&opts.map).unwrap_or_else(|e| die!(PROGNAME, "{}: {}", opts.map, e));
+
+    for entry in entries(&answer) {
+        let data = if opts.pretty {
+            serde_json::to_string_pretty(entry).unwrap()
+        } else {
+            entry.to_string()
+        };
+        match opts.key {
+            Some(ref k) => println!("{} {}", value_str(&entry[k.as_str()]), data),
+            None => println!("{}", data),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//
+// wnmatch: print the entries of a webnis map that match a regular expression.
+//
+use regex::RegexBuilder;
+use serde_json::Value;
+use structopt::StructOpt;
+
+use webnis_utils::{die, entries, value_str, ClientOpts};
+
+static PROGNAME: &str = "wnmatch";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "wnmatch")]
+struct Opts {
+    #[structopt(flatten)]
+    client: ClientOpts,
+
+    /// only match the value of this member (can be used more than once)
+    #[structopt(short = "f", long = "field", number_of_values = 1)]
+    fields: Vec<String>,
+
+    /// match the member names as well as the values
+    #[structopt(short = "n", long = "names")]
+    names: bool,
+
+    /// ignore case
+    #[structopt(short = "i", long = "ignore-case")]
+    ignore_case: bool,
+
+    /// print the entries that do not match
+    #[structopt(short = "v", long = "invert-match")]
+    invert: bool,
+
+    /// name of the map. it must have enumerate = true on the server.
+    map: String,
+
+    /// regular expression
+    pattern: String,
+}
+
+// Does the regexp match one of the values (or the names) of the members?
+fn is_match(re: &regex::Regex, entry: &Value, opts: &Opts) -> bool {
+    let obj = match entry.as_object() {
+        Some(obj) => obj,
+        None => return re.is_match(&value_str(entry)),
+    };
+    obj.iter()
+        .filter(|(name, _)| opts.fields.is_empty() || opts.fields.contains(name))
+        .any(|(name, value)| {
+            let values = match value {
+                Value::Array(a) => a.iter().map(value_str).collect::<Vec<_>>(),
+                v => vec![value_str(v)],
+            };
+            (opts.names && re.is_match(name)) || values.iter().any(|v| re.is_match(v))
+        })
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    let re = RegexBuilder::new(&opts.pattern)
+        .case_insensitive(opts.ignore_case)
+        .build()
+        .unwrap_or_else(|e| die!(PROGNAME, "{}", e));
+    let client = opts.client.client().unwrap_or_else(|e| die!(PROGNAME, "{}", e));
+    let answer = client.list(&opts.map).unwrap_or_else(|e| die!(PROGNAME, "{}: {}", opts.map, e));
+
+    let mut found = false;
+    for entry in entries(&answer) {
+        if is_match(&re, entry, &opts) != opts.invert {
+            println!("{}", entry);
+            found = true;
+        }
+    }
+    std::process::exit(if found { 0 } else { 1 });
+}
@@ -0,0 +1,71 @@
+//
+// wnwhich: show which webnis server is used, or which server
+// answers a lookup, along with the raw JSON result.
+//
+use serde_json::json;
+use structopt::StructOpt;
+
+use webnis_utils::{die, ClientOpts};
+
+static PROGNAME: &str = "wnwhich";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "wnwhich")]
+struct Opts {
+    #[structopt(flatten)]
+    client: ClientOpts,
+
+    /// show the state of all servers, as JSON
+    #[structopt(short = "a", long = "all")]
+    all: bool,
+
+    /// look up a key in this map
+    map: Option<String>,
+
+    /// name of the key, like "username"
+    keyname: Option<String>,
+
+    /// value of the key
+    keyvalue: Option<String>,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    let client = opts.client.client().unwrap_or_else(|e| die!(PROGNAME, "{}", e));
+
+    if let Some(ref map) = opts.map {
+        let (keyname, keyvalue) = match (opts.keyname.as_ref(), opts.keyvalue.as_ref()) {
+            (Some(n), Some(v)) => (n, v),
+            _ => die!(PROGNAME, "usage: wnwhich [options] [map keyname keyvalue]"),
+        };
+        match client.lookup(map, keyname, keyvalue) {
+            Ok(answer) => {
+                println!("server: {}", answer.server);
+                println!("{}", json!({ "result": answer.result }));
+            },
+            Err(ref e) if e.code == 404 => {
+                eprintln!("{}: {}={}: not found in map {}", PROGNAME, keyname, keyvalue, map);
+                std::process::exit(1);
+            },
+            Err(e) => die!(PROGNAME, "{}: {}", map, e),
+        }
+        return;
+    }
+
+    let status = client.servers().unwrap_or_else(|e| die!(PROGNAME, "{}", e));
+    if opts.all {
+        println!("{}", serde_json::to_string_pretty(&status).unwrap());
+        return;
+    }
+    let mut down = false;
+    for domain in status["domains"].as_array().map(|d| d.as_slice()).unwrap_or(&[]) {
+        match domain["active"].as_str() {
+            Some(server) => println!("{}: {}", domain["domain"].as_str().unwrap_or(""), server),
+            None => {
+                println!("{}: no servers available", domain["domain"].as_str().unwrap_or(""));
+                down = true;
+            },
+        }
+    }
+    std::process::exit(if down { 1 } else { 0 });
+}
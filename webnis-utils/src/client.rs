@@ -0,0 +1,331 @@
+//
+// Talk to webnis, either via the webnis-bind socket,
+// or directly to the webnis servers.
+//
+use std::fmt;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::header;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::Value;
+use tokio::runtime;
+use tokio::time;
+
+use crate::config::{self, Domain};
+
+type HttpClient = hyper_util::client::legacy::Client<hyper_tls::HttpsConnector<HttpConnector>, Empty<Bytes>>;
+
+// same encode sets as webnis-bind.
+const QUERY_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'<').add(b'>');
+const DEFAULT_ENCODE_SET: &AsciiSet = &QUERY_ENCODE_SET.add(b'`').add(b'?').add(b'{').add(b'}');
+const PATH_ENCODE_SET: &AsciiSet = &DEFAULT_ENCODE_SET.add(b'/').add(b'%').add(b'&').add(b'=').add(b'+');
+
+// time a webnis server gets to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// time webnis-bind gets to answer. it does its own retries.
+const BIND_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// An error: a status code and a message, like in the webnis-bind protocol.
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub code:       i64,
+    pub message:    String,
+}
+
+impl Error {
+    pub fn new(code: i64, message: impl Into<String>) -> Error {
+        Error { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.message)
+    }
+}
+
+/// The answer to a lookup or list request.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    /// the server that answered.
+    pub server:     String,
+    /// the "result" member of the JSON reply.
+    pub result:     Value,
+}
+
+// talking to the servers directly.
+struct Direct {
+    domains:    Vec<Domain>,
+    rt:         runtime::Runtime,
+    http:       HttpClient,
+}
+
+enum Transport {
+    Bind(String),
+    Direct(Box<Direct>),
+}
+
+/// A client, connected to webnis-bind or to the servers directly.
+pub struct Client {
+    domain:     Option<String>,
+    transport:  Transport,
+}
+
+impl Client {
+    /// Talk to the webnis-bind daemon on `socket`.
+    pub fn bind(socket: &str, domain: Option<String>) -> Client {
+        Client {
+            domain,
+            transport: Transport::Bind(socket.to_string()),
+        }
+    }
+
+    /// Talk to the servers from the webnis-bind configuration file directly.
+    pub fn direct(config_file: &str, domain: Option<String>) -> Result<Client, Error> {
+        let domains = config::read(config_file).map_err(|e| Error::new(500, e.to_string()))?;
+        if let Some(ref name) = domain {
+            if !domains.iter().any(|d| &d.name == name) {
+                return Err(Error::new(400, format!("{}: domain {} not found", config_file, name)));
+            }
+        }
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::new(500, e.to_string()))?;
+        let http = {
+            let _guard = rt.enter();
+            hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(hyper_tls::HttpsConnector::new())
+        };
+        Ok(Client {
+            domain,
+            transport: Transport::Direct(Box::new(Direct { domains, rt, http })),
+        })
+    }
+
+    /// Look up a key in a map.
+    pub fn lookup(&self, map: &str, keyname: &str, keyvalue: &str) -> Result<Answer, Error> {
+        match self.transport {
+            Transport::Bind(ref socket) => {
+                for arg in &[map, keyname, keyvalue] {
+                    if arg.is_empty() || arg.contains(char::is_whitespace) {
+                        return Err(Error::new(400, "empty argument or whitespace in argument, use --direct"));
+                    }
+                }
+                let reply = self.bind_request(socket, "map", &[map, keyname, keyvalue])?;
+                bind_answer(&reply)
+            },
+            Transport::Direct(..) => {
+                self.direct_request(|domain| {
+                    format!(
+                        "/{}/map/{}?{}={}",
+                        utf8_percent_encode(domain, PATH_ENCODE_SET),
+                        utf8_percent_encode(map, PATH_ENCODE_SET),
+                        utf8_percent_encode(keyname, PATH_ENCODE_SET),
+                        utf8_percent_encode(keyvalue, PATH_ENCODE_SET)
+                    )
+                })
+            },
+        }
+    }
+
+    /// Get all entries of a map. The map must have `enumerate = true`
+    /// in the server configuration.
+    pub fn list(&self, map: &str) -> Result<Answer, Error> {
+        match self.transport {
+            Transport::Bind(ref socket) => {
+                if map.is_empty() || map.contains(char::is_whitespace) {
+                    return Err(Error::new(400, "invalid map name"));
+                }
+                let reply = self.bind_request(socket, "list", &[map])?;
+                bind_answer(&reply)
+            },
+            Transport::Direct(..) => {
+                self.direct_request(|domain| {
+                    format!(
+                        "/{}/map/{}/list",
+                        utf8_percent_encode(domain, PATH_ENCODE_SET),
+                        utf8_percent_encode(map, PATH_ENCODE_SET)
+                    )
+                })
+            },
+        }
+    }
+
+    /// The state of the servers of every domain, in the same
+    /// format as the webnis-bind SERVERS command.
+    pub fn servers(&self) -> Result<Value, Error> {
+        let (domains, rt, http) = match self.transport {
+            Transport::Bind(ref socket) => {
+                let reply = self.bind_request(socket, "servers", &[])?;
+                return serde_json::from_str(&reply).map_err(|_| Error::new(400, "JSON error"));
+            },
+            Transport::Direct(ref d) => (&d.domains, &d.rt, &d.http),
+        };
+        let mut status = Vec::new();
+        for domain in self.domains(domains) {
+            let path = format!("/{}/info", utf8_percent_encode(&domain.name, PATH_ENCODE_SET));
+            let mut active = None;
+            let mut servers = Vec::new();
+            for server in &domain.servers {
+                let now = Instant::now();
+                let res = rt.block_on(get(http, domain, server, &path));
+                let latency = now.elapsed().as_secs_f64() * 1000.0;
+                let mut s = json!({ "server": server });
+                match res {
+                    Ok(_) => {
+                        s["state"] = json!("up");
+                        s["latency_ms"] = json!((latency * 10.0).round() / 10.0);
+                        active = active.or_else(|| Some(server.clone()));
+                    },
+                    Err(e) => {
+                        s["state"] = json!("down");
+                        s["error"] = json!(e.to_string());
+                    },
+                }
+                servers.push(s);
+            }
+            status.push(json!({ "domain": domain.name, "active": active, "servers": servers }));
+        }
+        Ok(json!({ "domains": status }))
+    }
+
+    // The domains to use, in lookup order.
+    fn domains<'a>(&self, domains: &'a [Domain]) -> Vec<&'a Domain> {
+        domains
+            .iter()
+            .filter(|d| self.domain.as_ref().map(|n| n == &d.name).unwrap_or(true))
+            .collect()
+    }
+
+    // Send one command to webnis-bind, and return the data after "200 ".
+    fn bind_request(&self, socket: &str, cmd: &str, args: &[&str]) -> Result<String, Error> {
+        let mut line = match self.domain {
+            Some(ref d) => format!("{}@{}", cmd, d),
+            None => cmd.to_string(),
+        };
+        for arg in args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        line.push('\n');
+
+        let io_err = |e: std::io::Error| Error::new(503, format!("{}: {}", socket, e));
+        let mut stream = UnixStream::connect(socket).map_err(io_err)?;
+        stream.set_read_timeout(Some(BIND_TIMEOUT)).map_err(io_err)?;
+        stream.write_all(line.as_bytes()).map_err(io_err)?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).map_err(io_err)?;
+
+        let reply = reply.trim_end();
+        let mut parts = reply.splitn(2, ' ');
+        let code = parts.next().and_then(|c| c.parse::<i64>().ok());
+        let rest = parts.next().unwrap_or("").to_string();
+        match code {
+            Some(200) => Ok(rest),
+            Some(code) => Err(Error::new(code, rest)),
+            None => Err(Error::new(500, format!("{}: unexpected reply", socket))),
+        }
+    }
+
+    // Send a GET request to the servers of each domain in turn. The next
+    // server is tried on errors, the next domain when the key was not found.
+    fn direct_request(&self, path: impl Fn(&str) -> String) -> Result<Answer, Error> {
+        let (domains, rt, http) = match self.transport {
+            Transport::Direct(ref d) => (&d.domains, &d.rt, &d.http),
+            Transport::Bind(_) => unreachable!(),
+        };
+        let mut error = Error::new(404, "not found");
+        for domain in self.domains(domains) {
+            let path = path(&domain.name);
+            for server in &domain.servers {
+                match rt.block_on(get(http, domain, server, &path)) {
+                    Ok(result) => return Ok(Answer { server: server.clone(), result }),
+                    Err(e) => {
+                        error = e;
+                        // these are answers, not failures of the server.
+                        if error.code == 401 || error.code == 403 || error.code == 404 {
+                            break;
+                        }
+                    },
+                }
+            }
+            if error.code != 404 {
+                break;
+            }
+        }
+        Err(error)
+    }
+}
+
+// Parse the {"server":..,"result":..} reply of webnis-bind.
+fn bind_answer(reply: &str) -> Result<Answer, Error> {
+    let mut data = serde_json::from_str::<Value>(reply).map_err(|_| Error::new(400, "JSON error"))?;
+    let server = data["server"].as_str().unwrap_or("").to_string();
+    Ok(Answer { server, result: data["result"].take() })
+}
+
+// Same as build_uri in webnis-bind.
+fn build_uri(host: &str, path: &str) -> String {
+    if host.starts_with("http://") || host.starts_with("https://") {
+        let host = host.trim_end_matches('/');
+        format!("{}{}", host, path)
+    } else if host == "localhost" || host.starts_with("localhost:") {
+        format!("http://{}/.well-known/webnis{}", host, path)
+    } else {
+        format!("https://{}/.well-known/webnis{}", host, path)
+    }
+}
+
+// GET a path from one server, and return the "result" member of the reply.
+async fn get(http: &HttpClient, domain: &Domain, server: &str, path: &str) -> Result<Value, Error> {
+    let uri = build_uri(server, path);
+    let req = hyper::Request::get(uri.as_str())
+        .header(header::AUTHORIZATION, domain.authorization())
+        .body(Empty::new())
+        .map_err(|e| Error::new(400, format!("{}: {}", uri, e)))?;
+    let fetch = async {
+        let resp = http.request(req).await.map_err(|e| Error::new(503, format!("{}: {}", server, e)))?;
+        let status = resp.status();
+        let body = resp.into_body().collect().await.map_err(|e| Error::new(503, format!("{}: {}", server, e)))?;
+        Ok::<_, Error>((status, body.to_bytes()))
+    };
+    let (status, body) = match time::timeout(REQUEST_TIMEOUT, fetch).await {
+        Ok(res) => res?,
+        Err(_) => return Err(Error::new(408, format!("{}: request timeout", server))),
+    };
+    let mut data = match serde_json::from_slice::<Value>(&body) {
+        Ok(data) => data,
+        Err(_) => return Err(Error::new(status.as_u16() as i64, "HTTP error")),
+    };
+    if let Some(error) = data.get("error") {
+        let code = error.get("code").and_then(|c| c.as_i64()).unwrap_or(status.as_u16() as i64);
+        let message = error.get("message").and_then(|m| m.as_str()).unwrap_or("error");
+        return Err(Error::new(code, message));
+    }
+    match data.get_mut("result") {
+        Some(result) => Ok(result.take()),
+        None => Err(Error::new(400, "JSON error")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_build_uri() {
+        assert_eq!(build_uri("localhost:3245", "/d/info"), "http://localhost:3245/.well-known/webnis/d/info");
+        assert_eq!(build_uri("webnis.example.com", "/d/info"), "https://webnis.example.com/.well-known/webnis/d/info");
+        assert_eq!(build_uri("http://10.0.0.1:3245/", "/d/info"), "http://10.0.0.1:3245/d/info");
+        let a = bind_answer(r#"{"server":"s1","result":{"uid":0}}"#).unwrap();
+        assert_eq!(a.server, "s1");
+        assert_eq!(a.result["uid"], 0);
+    }
+}
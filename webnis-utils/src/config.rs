@@ -0,0 +1,121 @@
+//
+// The parts of the webnis-bind configuration that are needed
+// to talk to the webnis servers directly.
+//
+use std::fs;
+use std::io;
+
+use webnis_common::secret::secret;
+
+#[derive(Deserialize, Debug, Clone)]
+struct Config {
+    // either the name of the one domain, or a list of [[domain]] sections.
+    #[serde(default)]
+    domain:                 NameOrDomains,
+    // the settings below are defaults for all domains.
+    http_authschema:        Option<String>,
+    http_authtoken:         Option<String>,
+    http_authencoding:      Option<String>,
+    server:                 Option<String>,
+    #[serde(default)]
+    servers:                Vec<String>,
+    servers_srv:            Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum NameOrDomains {
+    Name(String),
+    Domains(Vec<Domain>),
+}
+
+impl Default for NameOrDomains {
+    fn default() -> NameOrDomains {
+        NameOrDomains::Name("".to_string())
+    }
+}
+
+/// A webnis domain, with its servers and credentials.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Domain {
+    #[serde(default)]
+    pub name:               String,
+    pub http_authschema:    Option<String>,
+    pub http_authtoken:     Option<String>,
+    pub http_authencoding:  Option<String>,
+    pub server:             Option<String>,
+    #[serde(default)]
+    pub servers:            Vec<String>,
+    pub servers_srv:        Option<String>,
+}
+
+impl Domain {
+    /// Value of the Authorization: header.
+    pub fn authorization(&self) -> String {
+        let schema = self.http_authschema.as_deref().unwrap_or("");
+        let token = self.http_authtoken.as_deref().unwrap_or("");
+        match self.http_authencoding.as_deref() {
+            Some("base64") => format!("{} {}", schema, base64::encode(token)),
+            _ => format!("{} {}", schema, token),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the webnis-bind configuration file, and return the domains
+/// in lookup order, with the defaults from the top level filled in.
+pub fn read(name: &str) -> io::Result<Vec<Domain>> {
+    let buffer = fs::read_to_string(name).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+    let mut config: Config = toml::from_str(&buffer).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+
+    if let Some(s) = config.server.take() {
+        config.servers.push(s);
+    }
+
+    // old style config, one domain, everything at the top level.
+    let (mut domains, single) = match std::mem::take(&mut config.domain) {
+        NameOrDomains::Name(name) => (vec![Domain { name, ..Domain::default() }], true),
+        NameOrDomains::Domains(d) => (d, false),
+    };
+
+    for d in domains.iter_mut() {
+        if d.name.as_str() == "" {
+            if !single {
+                return Err(invalid(format!("{}: [[domain]]: name not set", name)));
+            }
+            d.name = "default".to_string();
+        }
+        if d.http_authschema.is_none() {
+            d.http_authschema = config.http_authschema.clone();
+        }
+        if d.http_authtoken.is_none() {
+            d.http_authtoken = config.http_authtoken.clone();
+        }
+        if d.http_authencoding.is_none() {
+            d.http_authencoding = config.http_authencoding.clone();
+        }
+        if let Some(token) = d.http_authtoken.take() {
+            let token = secret(&token).map_err(|e| invalid(format!("{}: domain {}: http_authtoken: {}", name, d.name, e)))?;
+            d.http_authtoken = Some(token);
+        }
+        if let Some(s) = d.server.take() {
+            d.servers.push(s);
+        }
+        if d.servers.is_empty() && d.servers_srv.is_none() {
+            d.servers = config.servers.clone();
+            d.servers_srv = config.servers_srv.clone();
+        }
+        // SRV lookups are done by webnis-bind only.
+        if d.servers.is_empty() {
+            let msg = match d.servers_srv {
+                Some(_) => "servers_srv is not supported, use webnis-bind",
+                None => "no servers defined",
+            };
+            return Err(invalid(format!("{}: domain {}: {}", name, d.name, msg)));
+        }
+    }
+    Ok(domains)
+}
@@ -0,0 +1,78 @@
+//
+// webnis-utils: command line tools to query webnis.
+//
+// The tools talk to the webnis-bind daemon over its unix socket, or
+// directly to the webnis servers using the webnis-bind configuration.
+//
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+use std::path::Path;
+
+use structopt::StructOpt;
+
+pub mod client;
+pub mod config;
+
+pub use crate::client::{Answer, Client, Error};
+
+/// Print an error message and exit with status 2.
+#[macro_export]
+macro_rules! die {
+    ($prog:expr, $($tt:tt)*) => ({
+        eprintln!("{}: {}", $prog, format!($($tt)*));
+        std::process::exit(2);
+    });
+}
+
+/// Options that are the same for all tools.
+#[derive(Debug, StructOpt)]
+pub struct ClientOpts {
+    /// webnis-bind socket
+    #[structopt(short = "S", long = "socket", default_value = "/var/run/webnis-bind.sock")]
+    pub socket: String,
+
+    /// talk to the webnis servers directly, not via webnis-bind
+    #[structopt(short = "D", long = "direct")]
+    pub direct: bool,
+
+    /// webnis-bind configuration file, used with --direct
+    #[structopt(short = "c", long = "config", default_value = "/etc/webnis-bind.toml")]
+    pub config: String,
+
+    /// domain (default: all domains, in lookup order)
+    #[structopt(short = "d", long = "domain")]
+    pub domain: Option<String>,
+}
+
+impl ClientOpts {
+    /// Create a client. If --direct was not used and the webnis-bind
+    /// socket does not exist, talk to the servers directly.
+    pub fn client(&self) -> Result<Client, Error> {
+        if self.direct || !Path::new(&self.socket).exists() {
+            Client::direct(&self.config, self.domain.clone())
+        } else {
+            Ok(Client::bind(&self.socket, self.domain.clone()))
+        }
+    }
+}
+
+/// A JSON value as text: strings without quotes, arrays as
+/// comma separated lists, everything else as JSON.
+pub fn value_str(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::String(s) => s.to_string(),
+        serde_json::Value::Array(a) => a.iter().map(value_str).collect::<Vec<_>>().join(","),
+        v => v.to_string(),
+    }
+}
+
+/// The entries of a list result.
+pub fn entries(answer: &Answer) -> &[serde_json::Value] {
+    match answer.result {
+        serde_json::Value::Array(ref a) => a,
+        ref v => std::slice::from_ref(v),
+    }
+}